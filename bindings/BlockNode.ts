@@ -0,0 +1,10 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { BlockKind } from "./BlockKind";
+import type { DirectiveNode } from "./DirectiveNode";
+import type { Node } from "./Node";
+
+/**
+ * A `-- +migration`, `-- +group`, `-- +macro` or `-- +function` block, from
+ * its opening directive to its closing one.
+ */
+export type BlockNode = { kind: BlockKind, name: string, open: DirectiveNode, items: Array<Node>, close: DirectiveNode | null, span: { start: number, end: number, }, };
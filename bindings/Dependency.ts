@@ -0,0 +1,7 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { DependencyKind } from "./DependencyKind";
+
+/**
+ * What a node depends on, with where in its file the dependency was declared.
+ */
+export type Dependency = { kind: DependencyKind, line: number, span: { start: number, end: number, }, };
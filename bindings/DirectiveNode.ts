@@ -0,0 +1,19 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * A directive line, with the `-- |` lines continuing it.
+ */
+export type DirectiveNode = { 
+/**
+ * As in `directives::DIRECTIVES`, `description` for `--+` and `|` for a
+ * continuation without a directive before it. Unknown names are kept.
+ */
+name: string, name_span: { start: number, end: number, }, 
+/**
+ * What follows the name, continuation lines appended.
+ */
+argument: string | null, 
+/**
+ * From the start of the argument to the end of the last continuation.
+ */
+argument_span: { start: number, end: number, } | null, line: number, text: string, span: { start: number, end: number, }, };
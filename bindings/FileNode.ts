@@ -0,0 +1,11 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Node } from "./Node";
+
+/**
+ * Syntax tree of an FSQL file. Every byte of the file belongs to exactly one
+ * leaf, a directive or a chunk of SQL, so printing the tree gives the file
+ * back as it was written. Building it never fails: blocks that are not
+ * closed simply have no `close`, `MigrationFile::parse_file` reports what
+ * is wrong when lowering the tree to models.
+ */
+export type FileNode = { items: Array<Node>, span: { start: number, end: number, }, };
@@ -1,5 +1,6 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Dependency } from "./Dependency";
 import type { FuncArgument } from "./FuncArgument";
 import type { FunctionTags } from "./FunctionTags";
 
-export type Function = { name: string, arguments: Array<FuncArgument>, return_type: string | null, tags: Array<FunctionTags>, description: string, body: string, parsed_body: string, language: string | null, complete: boolean, };
+export type Function = { name: string, full_path: string | null, arguments: Array<FuncArgument>, return_type: string | null, tags: Array<FunctionTags>, description: string, body: string, body_ranges: Array<{ start: number, end: number, }>, parsed_body: string, language: string | null, language_span: { start: number, end: number, } | null, complete: boolean, dependencies: Array<Dependency>, line: number, span: { start: number, end: number, }, };
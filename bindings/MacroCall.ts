@@ -0,0 +1,7 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * A `-- +call: name(args...)` site inside a migration, or a
+ * `{{name(args...)}}` placeholder inside a macro body.
+ */
+export type MacroCall = { path: string, source: string, arguments: Array<string>, raw_arguments: Array<string>, line: number, span: { start: number, end: number, }, rollback: boolean, sql_line: number, };
@@ -1,4 +1,6 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Dependency } from "./Dependency";
 import type { MacroArgument } from "./MacroArgument";
+import type { MacroCall } from "./MacroCall";
 
-export type MacroFunc = { name: string, arguments: Array<MacroArgument>, description: string, body: string, parsed_body: string, used_arguments: Array<string>, };
+export type MacroFunc = { name: string, full_path: string | null, arguments: Array<MacroArgument>, description: string, body: string, body_ranges: Array<{ start: number, end: number, }>, parsed_body: string, used_arguments: Array<string>, macro_calls: Array<MacroCall>, dependencies: Array<Dependency>, line: number, span: { start: number, end: number, }, };
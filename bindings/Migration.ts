@@ -1,5 +1,6 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
-import type { MigrationDependency } from "./MigrationDependency";
+import type { Dependency } from "./Dependency";
+import type { MacroCall } from "./MacroCall";
 import type { MigrationTags } from "./MigrationTags";
 
-export type Migration = { name: string, version: string, description: string, sql: string, dependencies: Array<MigrationDependency>, tags: Array<MigrationTags>, };
+export type Migration = { name: string, full_path: string | null, version: string | null, description: string, sql: string, sql_rollback: string, sql_ranges: Array<{ start: number, end: number, }>, sql_rollback_ranges: Array<{ start: number, end: number, }>, dependencies: Array<Dependency>, macro_calls: Array<MacroCall>, tags: Array<MigrationTags>, nuclear: boolean, retries: number | null, line: number, span: { start: number, end: number, }, };
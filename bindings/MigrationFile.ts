@@ -4,4 +4,4 @@ import type { MacroFunc } from "./MacroFunc";
 import type { Migration } from "./Migration";
 import type { MigrationGroup } from "./MigrationGroup";
 
-export type MigrationFile = { file_path: string, file_name: string, file_content: string, migrations: Array<Migration>, migration_groups: Array<MigrationGroup>, macros: Array<MacroFunc>, functions: Array<Function>, sqlx_version: string | null, };
+export type MigrationFile = { file_path: string, file_name: string, file_content: string, migrations: Array<Migration>, migration_groups: Array<MigrationGroup>, macros: Array<MacroFunc>, functions: Array<Function>, fsql_version: string | null, };
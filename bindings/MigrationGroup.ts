@@ -1,6 +1,6 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Dependency } from "./Dependency";
 import type { Migration } from "./Migration";
-import type { MigrationDependency } from "./MigrationDependency";
 import type { MigrationTags } from "./MigrationTags";
 
-export type MigrationGroup = { name: string, version: string, description: string, migrations: Array<Migration>, groups: Array<MigrationGroup>, dependencies: Array<MigrationDependency>, current_group_index: number, tags: Array<MigrationTags>, };
+export type MigrationGroup = { name: string, full_path: string | null, version: string | null, description: string, migrations: Array<Migration>, groups: Array<MigrationGroup>, dependencies: Array<Dependency>, current_migration_index: number, current_group_index: number, tags: Array<MigrationTags>, nuclear: boolean, transaction: boolean, retries: number | null, line: number, span: { start: number, end: number, }, };
@@ -0,0 +1,13 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Function } from "./Function";
+import type { MacroFunc } from "./MacroFunc";
+import type { Migration } from "./Migration";
+import type { MigrationFile } from "./MigrationFile";
+import type { MigrationGroup } from "./MigrationGroup";
+import type { NodeId } from "./NodeId";
+
+/**
+ * Every parsed file of a migrations directory, with all migrations, groups,
+ * macros and functions flattened and indexed by their full path.
+ */
+export type MigrationProject = { root: string, files: Array<MigrationFile>, migrations: Array<Migration>, groups: Array<MigrationGroup>, macros: Array<MacroFunc>, functions: Array<Function>, index: { [key in string]?: NodeId }, resolved_dependencies: { [key in NodeId]?: Array<NodeId> }, };
@@ -0,0 +1,6 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { BlockNode } from "./BlockNode";
+import type { DirectiveNode } from "./DirectiveNode";
+import type { SqlChunk } from "./SqlChunk";
+
+export type Node = { "Block": BlockNode } | { "Directive": DirectiveNode } | { "Sql": SqlChunk };
@@ -0,0 +1,7 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * Consecutive lines that are not directives: SQL, plain comments and blank
+ * lines.
+ */
+export type SqlChunk = { line: number, text: string, span: { start: number, end: number, }, };
@@ -0,0 +1,135 @@
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+use sqlx::{Connection, PgConnection, PgPool};
+
+use crate::execution_errors::{ExecutionError, ExecutionErrorKind};
+
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Session level advisory lock keeping two migrators off the same tracking
+/// table. It lives on its own connection, detached from the pool: dropping
+/// the lock closes the connection, which releases it on the server.
+pub struct MigratorLock {
+    connection: PgConnection,
+    key: i64,
+}
+
+impl MigratorLock {
+    /// Advisory lock key of a tracking table. Migrators using different
+    /// tables do not wait for each other.
+    pub fn key(table: &str) -> i64 {
+        let digest = Sha256::digest(format!("fsql:{table}").as_bytes());
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&digest[..8]);
+        i64::from_be_bytes(bytes)
+    }
+
+    /// Takes the lock of `table`, waiting at most `timeout` for the migrator
+    /// holding it.
+    pub async fn acquire(
+        pool: &PgPool,
+        table: &str,
+        timeout: Duration,
+    ) -> Result<Self, ExecutionError> {
+        let key = Self::key(table);
+        let mut connection = pool.acquire().await.map_err(Self::failed)?.detach();
+        let deadline = Instant::now() + timeout;
+        let mut waiting = false;
+
+        loop {
+            let acquired: bool = sqlx::query_scalar("SELECT pg_try_advisory_lock($1)")
+                .bind(key)
+                .fetch_one(&mut connection)
+                .await
+                .map_err(Self::failed)?;
+            if acquired {
+                return Ok(Self { connection, key });
+            }
+
+            // Missing when the lock was released in between, the next poll takes it
+            let holder = Self::holder(&mut connection, key)
+                .await?
+                .map(|pid| format!(" (pid {pid})"));
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(ExecutionError::new(
+                    ExecutionErrorKind::MigratorBusy(table.to_string()),
+                    format!(
+                        "Another migrator holds the lock on '{table}'{}, gave up after {}s",
+                        holder.unwrap_or_default(),
+                        timeout.as_secs_f64()
+                    ),
+                ));
+            }
+            if let Some(holder) = holder.filter(|_| !waiting) {
+                eprintln!(
+                    "Another migrator holds the lock on '{table}'{holder}, waiting up to {}s",
+                    timeout.as_secs_f64()
+                );
+                waiting = true;
+            }
+            tokio::time::sleep(POLL_INTERVAL.min(deadline - now)).await;
+        }
+    }
+
+    /// Backend holding the lock of `key` in the current database, from
+    /// `pg_locks`. A bigint key is split in `classid` and `objid`.
+    async fn holder(
+        connection: &mut PgConnection,
+        key: i64,
+    ) -> Result<Option<i32>, ExecutionError> {
+        sqlx::query_scalar(
+            r#"
+            SELECT pid FROM pg_locks
+            WHERE locktype = 'advisory'
+                AND granted
+                AND database = (SELECT oid FROM pg_database WHERE datname = current_database())
+                AND classid = (($1 >> 32) & 4294967295)::oid
+                AND objid = ($1 & 4294967295)::oid
+                AND objsubid = 1
+            LIMIT 1
+            "#,
+        )
+        .bind(key)
+        .fetch_optional(connection)
+        .await
+        .map_err(Self::failed)
+    }
+
+    /// Releases the lock and closes its connection.
+    pub async fn release(mut self) -> Result<(), ExecutionError> {
+        sqlx::query("SELECT pg_advisory_unlock($1)")
+            .bind(self.key)
+            .execute(&mut self.connection)
+            .await
+            .map_err(Self::failed)?;
+        self.connection.close().await.map_err(Self::failed)
+    }
+
+    fn failed(error: sqlx::Error) -> ExecutionError {
+        ExecutionError::new(
+            ExecutionErrorKind::MigratorLock,
+            format!("Migrator lock failed: {error}"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_stable_and_differ_per_table() {
+        assert_eq!(
+            MigratorLock::key("migrations"),
+            MigratorLock::key("migrations")
+        );
+        assert_ne!(
+            MigratorLock::key("migrations"),
+            MigratorLock::key("other.migrations")
+        );
+    }
+}
@@ -0,0 +1,740 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
+
+use clap::{Parser, Subcommand};
+use colored::Colorize;
+use sqlx::types::chrono;
+
+use crate::{
+    advisory_lock,
+    execution_errors::{ExecutionError, ExecutionErrorKind},
+    execution_plan::ExecutionPlan,
+    executor::{self, ExecutionReport, Executor, RetryPolicy, RollbackTarget},
+    migration_parser::MigrationParser,
+    models::project::{MigrationProject, NodeKind},
+    parse_errors::{ParseError, ParseWarning},
+    tracking::{self, MigrationStatus, TrackingTable},
+};
+
+const DEFAULT_MIGRATIONS_DIR: &str = "migrations";
+const REGEX_MIGRATION_NAME: &str = r"^\w+$";
+
+/// A migration or the database could not be processed.
+const EXIT_EXECUTION_FAILED: u8 = 1;
+/// The FSQL files are invalid, clap itself uses 2 for usage errors.
+const EXIT_INVALID_PROJECT: u8 = 3;
+/// Applied migrations were edited after they ran.
+const EXIT_DRIFT: u8 = 4;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "fsql",
+    version,
+    about = "Runs FSQL migrations against PostgreSQL"
+)]
+pub struct Cli {
+    /// Root directory of the FSQL migrations
+    #[arg(long, global = true, default_value = DEFAULT_MIGRATIONS_DIR)]
+    pub dir: PathBuf,
+
+    /// Database to migrate, defaults to DATABASE_URL from the environment or .env
+    #[arg(long, global = true)]
+    pub database_url: Option<String>,
+
+    /// Name of the tracking table
+    #[arg(long, global = true, default_value = tracking::DEFAULT_TABLE_NAME)]
+    pub table: String,
+
+    /// Extra function language to accept, can be repeated
+    #[arg(long = "language", global = true)]
+    pub languages: Vec<String>,
+
+    /// Fail on unknown `-- +` directives instead of warning about them
+    #[arg(long, global = true)]
+    pub strict: bool,
+
+    /// Delay before the first retry of a transiently failed migration
+    #[arg(long, global = true, default_value_t = 200)]
+    pub retry_delay_ms: u64,
+
+    /// Factor the retry delay grows by after every attempt
+    #[arg(long, global = true, default_value_t = 2.0)]
+    pub retry_multiplier: f64,
+
+    /// Upper bound of the retry delay
+    #[arg(long, global = true, default_value_t = 10_000)]
+    pub retry_max_delay_ms: u64,
+
+    /// How many concurrent migrations may run in parallel
+    #[arg(long, global = true, default_value_t = executor::DEFAULT_MAX_PARALLEL)]
+    pub max_parallel: usize,
+
+    /// How long to wait for another migrator working on the same tracking table
+    #[arg(long, global = true, default_value_t = advisory_lock::DEFAULT_LOCK_TIMEOUT.as_secs())]
+    pub lock_timeout_secs: u64,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Apply pending migrations
+    Up {
+        /// Only apply this migration and what it depends on
+        #[arg(long)]
+        target: Option<String>,
+        /// Print what would be applied without touching the database
+        #[arg(long)]
+        dry_run: bool,
+        /// Confirm running nuclear migrations ahead of the plan
+        #[arg(long)]
+        allow_nuclear: bool,
+    },
+    /// Roll back applied migrations, the last one by default
+    Down {
+        /// Roll back a single migration
+        #[arg(long, conflicts_with_all = ["group", "steps"])]
+        target: Option<String>,
+        /// Roll back every migration of a group
+        #[arg(long, conflicts_with = "steps")]
+        group: Option<String>,
+        /// Roll back the last N applied migrations
+        #[arg(long)]
+        steps: Option<usize>,
+        /// Print what would be rolled back without touching the database
+        #[arg(long)]
+        dry_run: bool,
+        /// Roll back even if applied migrations depend on the selection
+        #[arg(long)]
+        force: bool,
+    },
+    /// Show the state of every migration
+    Status,
+    /// Print the execution order of the project
+    Plan {
+        /// Print the SQL script `up` would run against the database instead
+        #[arg(long)]
+        sql: bool,
+        /// Only plan this migration and what it depends on
+        #[arg(long, requires = "sql")]
+        target: Option<String>,
+    },
+    /// Parse the project and check its dependencies
+    Validate,
+    /// Print the SQL of a migration, function or macro
+    Render {
+        /// Full path or name of what to render
+        target: String,
+        /// Render the rollback section of a migration instead
+        #[arg(long)]
+        rollback: bool,
+    },
+    /// Add an empty migration
+    New {
+        /// Name of the migration
+        name: String,
+        /// File to append it to, a new timestamped file by default
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+    /// Show the tracking table, most recent change first
+    History,
+    /// Accept the new content of edited migrations
+    Repair {
+        /// Only repair this migration
+        #[arg(long)]
+        target: Option<String>,
+    },
+    /// Protect applied migrations from rollbacks and edits
+    Lock {
+        /// Lock a single migration
+        #[arg(required_unless_present = "group", conflicts_with = "group")]
+        target: Option<String>,
+        /// Lock every migration of a group
+        #[arg(long)]
+        group: Option<String>,
+    },
+    /// Lift the protection of locked migrations
+    Unlock {
+        /// Unlock a single migration
+        #[arg(required_unless_present = "group", conflicts_with = "group")]
+        target: Option<String>,
+        /// Unlock every migration of a group
+        #[arg(long)]
+        group: Option<String>,
+    },
+}
+
+/// Error reported by a command, with the exit code it maps to.
+#[derive(Debug)]
+pub struct Failure {
+    pub code: u8,
+    pub message: String,
+}
+
+impl Failure {
+    fn new(code: u8, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<ParseError> for Failure {
+    fn from(error: ParseError) -> Self {
+        Self::new(EXIT_INVALID_PROJECT, error.to_string())
+    }
+}
+
+impl From<ExecutionError> for Failure {
+    fn from(error: ExecutionError) -> Self {
+        let code = match error.kind {
+            ExecutionErrorKind::HashMismatch(_) | ExecutionErrorKind::LockedModified(_) => {
+                EXIT_DRIFT
+            }
+            _ => EXIT_EXECUTION_FAILED,
+        };
+        Self::new(code, error.message)
+    }
+}
+
+pub async fn run(cli: Cli) -> ExitCode {
+    match run_command(&cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) => {
+            eprintln!("{} {}", "error:".red().bold(), failure.message);
+            ExitCode::from(failure.code)
+        }
+    }
+}
+
+async fn run_command(cli: &Cli) -> Result<(), Failure> {
+    match &cli.command {
+        Command::Up {
+            target,
+            dry_run,
+            allow_nuclear,
+        } => up(cli, target.as_deref(), *dry_run, *allow_nuclear).await,
+        Command::Down {
+            target,
+            group,
+            steps,
+            dry_run,
+            force,
+        } => {
+            let target = match (target, group) {
+                (Some(target), _) => RollbackTarget::Migration(target.clone()),
+                (_, Some(group)) => RollbackTarget::Group(group.clone()),
+                _ => RollbackTarget::Last(steps.unwrap_or(1)),
+            };
+            down(cli, &target, *dry_run, *force).await
+        }
+        Command::Status => status(cli).await,
+        Command::Plan { sql: false, .. } => plan(cli),
+        Command::Plan { sql: true, target } => plan_sql(cli, target.as_deref()).await,
+        Command::Validate => validate(cli),
+        Command::Render { target, rollback } => render(cli, target, *rollback),
+        Command::New { name, file } => new_migration(cli, name, file.as_deref()),
+        Command::History => history(cli).await,
+        Command::Repair { target } => repair(cli, target.as_deref()).await,
+        Command::Lock { target, group } => lock(cli, target, group, true).await,
+        Command::Unlock { target, group } => lock(cli, target, group, false).await,
+    }
+}
+
+fn load(cli: &Cli) -> Result<(MigrationProject, ExecutionPlan), Failure> {
+    let mut parser = MigrationParser::new(&cli.dir);
+    for language in &cli.languages {
+        parser.add_language(language);
+    }
+    parser.set_strict(cli.strict);
+    let (project, warnings) = parser.parse_project()?;
+    print_warnings(&warnings);
+    let plan = ExecutionPlan::build(&project)?;
+    Ok((project, plan))
+}
+
+fn print_warnings(warnings: &[ParseWarning]) {
+    for warning in warnings {
+        eprintln!("{} {warning}", "warning:".yellow().bold());
+    }
+}
+
+async fn connect(cli: &Cli) -> Result<Executor, Failure> {
+    let database_url = match &cli.database_url {
+        Some(url) => url.clone(),
+        None => std::env::var("DATABASE_URL").map_err(|_| {
+            Failure::new(
+                EXIT_EXECUTION_FAILED,
+                "DATABASE_URL is not set, use --database-url or a .env file",
+            )
+        })?,
+    };
+
+    // One connection per parallel migration, plus one for the tracking table
+    // and one for the migrator lock
+    let max_connections = u32::try_from(cli.max_parallel.max(1) + 2).unwrap_or(u32::MAX);
+    let mut executor = Executor::connect_with(&database_url, max_connections).await?;
+    executor.set_table(TrackingTable::new(&cli.table)?);
+    executor.set_retry_policy(RetryPolicy {
+        initial_delay: Duration::from_millis(cli.retry_delay_ms),
+        multiplier: cli.retry_multiplier,
+        max_delay: Duration::from_millis(cli.retry_max_delay_ms),
+    });
+    executor.set_max_parallel(cli.max_parallel);
+    executor.set_lock_timeout(Duration::from_secs(cli.lock_timeout_secs));
+    Ok(executor)
+}
+
+fn print_report(report: &ExecutionReport) {
+    for path in &report.functions {
+        println!("{} {path}", "created function".blue());
+    }
+    for path in &report.applied {
+        println!("{} {path}", "applied".green());
+    }
+    for path in &report.rolled_back {
+        println!("{} {path}", "rolled back".yellow());
+    }
+    for path in &report.repaired {
+        println!("{} {path}", "repaired".cyan());
+    }
+    for path in &report.locked {
+        println!("{} {path}", "locked".magenta());
+    }
+    for path in &report.unlocked {
+        println!("{} {path}", "unlocked".magenta());
+    }
+    if report.applied.is_empty()
+        && report.functions.is_empty()
+        && report.rolled_back.is_empty()
+        && report.repaired.is_empty()
+        && report.locked.is_empty()
+        && report.unlocked.is_empty()
+    {
+        println!("Nothing to do");
+    }
+}
+
+async fn up(
+    cli: &Cli,
+    target: Option<&str>,
+    dry_run: bool,
+    allow_nuclear: bool,
+) -> Result<(), Failure> {
+    let mut executor = connect(cli).await?;
+    executor.set_allow_nuclear(allow_nuclear);
+    let lock = executor.lock_migrator().await?;
+    let (project, plan) = load(cli)?;
+    let target = target
+        .map(|query| Executor::find_one(&project, query, NodeKind::Migration))
+        .transpose()?;
+
+    if dry_run {
+        let pending = executor.pending(&project, &plan, target).await?;
+        let changed = Executor::function_drift(&project, &executor.tracked().await?)?;
+        for id in &pending {
+            match id.kind {
+                NodeKind::Function => {
+                    let path = project.path_of(*id);
+                    match changed.iter().find(|drift| drift.full_path == path) {
+                        Some(drift) => {
+                            let mut changes = String::new();
+                            Executor::describe_changes(&mut changes, drift);
+                            print!("{} {path}\n{changes}", "would replace".blue());
+                        }
+                        None => println!("{} {path}", "would create".blue()),
+                    }
+                }
+                _ if project.is_nuclear(*id) => println!(
+                    "{} {} {}",
+                    "would apply".green(),
+                    project.path_of(*id),
+                    "(nuclear)".red()
+                ),
+                _ => println!("{} {}", "would apply".green(), project.path_of(*id)),
+            }
+        }
+        if pending.is_empty() {
+            println!("Nothing to do");
+        }
+        lock.release().await?;
+        return Ok(());
+    }
+
+    print_report(&executor.up(&lock, &project, &plan, target).await?);
+    lock.release().await?;
+    Ok(())
+}
+
+async fn down(
+    cli: &Cli,
+    target: &RollbackTarget,
+    dry_run: bool,
+    force: bool,
+) -> Result<(), Failure> {
+    let executor = connect(cli).await?;
+    let lock = executor.lock_migrator().await?;
+    let (project, plan) = load(cli)?;
+
+    if dry_run {
+        let selected = executor
+            .rollback_plan(&project, &plan, target, force)
+            .await?;
+        for id in &selected {
+            println!("{} {}", "would roll back".yellow(), project.path_of(*id));
+        }
+        if selected.is_empty() {
+            println!("Nothing to do");
+        }
+        lock.release().await?;
+        return Ok(());
+    }
+
+    print_report(&executor.down(&lock, &project, &plan, target, force).await?);
+    lock.release().await?;
+    Ok(())
+}
+
+async fn status(cli: &Cli) -> Result<(), Failure> {
+    let (project, plan) = load(cli)?;
+    let executor = connect(cli).await?;
+    executor.bootstrap().await?;
+    let tracked = executor.tracked().await?;
+    let drifts = Executor::drift(&project, &tracked)?;
+
+    for migration in plan.migrations(&project) {
+        let path = migration.path();
+        let state = match tracked.get(path) {
+            _ if drifts
+                .iter()
+                .any(|drift| drift.full_path == path && drift.locked) =>
+            {
+                "modified!".red().bold()
+            }
+            _ if drifts.iter().any(|drift| drift.full_path == path) => "modified".red(),
+            Some(row) => match row.status()? {
+                MigrationStatus::Executed => "executed".green(),
+                MigrationStatus::RolledBack => "rolled_back".yellow(),
+                // Started without finishing, some of its statements may have run
+                MigrationStatus::Pending
+                    if project
+                        .get(path)
+                        .is_some_and(|id| project.is_concurrent(id)) =>
+                {
+                    "partial".yellow()
+                }
+                MigrationStatus::Pending => "pending".normal(),
+            },
+            None => "pending".normal(),
+        };
+        println!("{state:<12} {path}");
+    }
+
+    // Changed functions are not an error, `up` creates them again
+    let changed = Executor::function_drift(&project, &tracked)?;
+    for function in project.functions() {
+        let path = function.path();
+        let state = match tracked.get(path) {
+            _ if changed.iter().any(|drift| drift.full_path == path) => "changed".yellow(),
+            Some(row) if row.status()? == MigrationStatus::Executed => "created".green(),
+            _ => "pending".normal(),
+        };
+        println!("{state:<12} {path}");
+    }
+
+    for row in tracked.values() {
+        if project.get(&row.full_path).is_none() {
+            println!("{:<12} {}", "missing".red(), row.full_path);
+        }
+    }
+
+    if !drifts.is_empty() {
+        return Err(Failure::new(
+            EXIT_DRIFT,
+            format!(
+                "{} applied migration(s) were modified, see `fsql repair`",
+                drifts.len()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn plan(cli: &Cli) -> Result<(), Failure> {
+    let (project, plan) = load(cli)?;
+    for (position, id) in plan.order().iter().enumerate() {
+        println!(
+            "{:>4}. {:<9} {}",
+            position + 1,
+            id.kind.as_str().to_lowercase(),
+            project.path_of(*id)
+        );
+    }
+    Ok(())
+}
+
+async fn plan_sql(cli: &Cli, target: Option<&str>) -> Result<(), Failure> {
+    let executor = connect(cli).await?;
+    let lock = executor.lock_migrator().await?;
+    let (project, plan) = load(cli)?;
+    let target = target
+        .map(|query| Executor::find_one(&project, query, NodeKind::Migration))
+        .transpose()?;
+    print!(
+        "{}",
+        executor.render_plan(&lock, &project, &plan, target).await?
+    );
+    lock.release().await?;
+    Ok(())
+}
+
+/// Reports every error and warning of the project at once, for CI.
+fn validate(cli: &Cli) -> Result<(), Failure> {
+    let mut parser = MigrationParser::new(&cli.dir);
+    for language in &cli.languages {
+        parser.add_language(language);
+    }
+    parser.set_strict(cli.strict);
+    let (project, mut report) = parser.parse_project_recovering();
+    if let Some(project) = &project
+        && let Err(e) = ExecutionPlan::build(project)
+    {
+        report.errors.push(e);
+    }
+
+    print_warnings(&report.warnings);
+    for error in &report.errors {
+        eprintln!("{} {error}", "error:".red().bold());
+    }
+    let project = match project {
+        Some(project) if !report.has_errors() => project,
+        _ => {
+            return Err(Failure::new(
+                EXIT_INVALID_PROJECT,
+                format!(
+                    "{} error(s) in '{}'",
+                    report.errors.len(),
+                    cli.dir.display()
+                ),
+            ));
+        }
+    };
+
+    println!(
+        "{} {} files, {} migrations, {} groups, {} macros, {} functions",
+        "ok".green(),
+        project.files().len(),
+        project.migrations().len(),
+        project.groups().len(),
+        project.macros().len(),
+        project.functions().len()
+    );
+    Ok(())
+}
+
+fn render(cli: &Cli, target: &str, rollback: bool) -> Result<(), Failure> {
+    let (project, _) = load(cli)?;
+
+    for kind in [NodeKind::Migration, NodeKind::Function, NodeKind::Macro] {
+        let id = match project.find(target, kind).as_slice() {
+            [] => continue,
+            [id] => *id,
+            _ => Executor::find_one(&project, target, kind)?,
+        };
+
+        let sql = match (kind, rollback) {
+            (NodeKind::Migration, false) => project.migrations()[id.index].sql(),
+            (NodeKind::Migration, true) => project.migrations()[id.index].sql_rollback(),
+            (_, true) => {
+                return Err(Failure::new(
+                    EXIT_INVALID_PROJECT,
+                    "--rollback only applies to migrations",
+                ));
+            }
+            (NodeKind::Function, false) => project.functions()[id.index].parsed_body(),
+            _ => project.macros()[id.index].parsed_body(),
+        };
+        println!("-- {}", project.path_of(id));
+        println!("{}", sql.trim_end_matches(['\r', '\n']));
+        return Ok(());
+    }
+
+    Err(Failure::new(
+        EXIT_INVALID_PROJECT,
+        format!("Nothing named '{target}' in the project"),
+    ))
+}
+
+fn new_migration(cli: &Cli, name: &str, file: Option<&Path>) -> Result<(), Failure> {
+    let regex_migration_name =
+        regex::Regex::new(REGEX_MIGRATION_NAME).expect("Invalid regex for migration name");
+    if !regex_migration_name.is_match(name) {
+        return Err(Failure::new(
+            EXIT_INVALID_PROJECT,
+            format!("'{name}' is not a valid migration name, use letters, digits and _"),
+        ));
+    }
+
+    let path = match file {
+        Some(file) => cli.dir.join(file),
+        None => cli.dir.join(format!(
+            "{}_{name}.sql",
+            chrono::Utc::now().format("%Y%m%d%H%M%S")
+        )),
+    };
+    let io_failure = |e: std::io::Error| {
+        Failure::new(
+            EXIT_EXECUTION_FAILED,
+            format!("Failed to write '{}': {e}", path.display()),
+        )
+    };
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(io_failure)?;
+    }
+    let separator = if path.exists() { "\n" } else { "" };
+    let mut output = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(io_failure)?;
+    write!(
+        output,
+        "{separator}-- +migration: {name}\n--+ Describe {name} here\n\n-- +rollback\n\n-- +endmigration\n"
+    )
+    .map_err(io_failure)?;
+
+    println!(
+        "{} {}::Migration({name})",
+        "created".green(),
+        path.display()
+    );
+    Ok(())
+}
+
+async fn history(cli: &Cli) -> Result<(), Failure> {
+    let executor = connect(cli).await?;
+    executor.bootstrap().await?;
+    let mut rows: Vec<_> = executor.tracked().await?.into_values().collect();
+    rows.sort_by_key(|row| std::cmp::Reverse((row.updated_at, row.id)));
+
+    for row in rows {
+        let rolled_back_at = row
+            .rolled_back_at
+            .map(|at| format!(" rolled back {}", at.format("%Y-%m-%d %H:%M:%S")))
+            .unwrap_or_default();
+        println!(
+            "{}  {:<12} {}{}{}{}",
+            row.updated_at.format("%Y-%m-%d %H:%M:%S"),
+            row.status,
+            row.full_path,
+            rolled_back_at,
+            if row.locked { " [locked]" } else { "" },
+            if row.nuclear { " [nuclear]" } else { "" }
+        );
+    }
+    Ok(())
+}
+
+async fn repair(cli: &Cli, target: Option<&str>) -> Result<(), Failure> {
+    let executor = connect(cli).await?;
+    let lock = executor.lock_migrator().await?;
+    let (project, _) = load(cli)?;
+    print_report(&executor.repair(&lock, &project, target).await?);
+    lock.release().await?;
+    Ok(())
+}
+
+async fn lock(
+    cli: &Cli,
+    target: &Option<String>,
+    group: &Option<String>,
+    locked: bool,
+) -> Result<(), Failure> {
+    let executor = connect(cli).await?;
+    let lock = executor.lock_migrator().await?;
+    let (project, _) = load(cli)?;
+    let report = match (target, group) {
+        (_, Some(group)) => {
+            executor
+                .set_locked(&lock, &project, group, true, locked)
+                .await?
+        }
+        (Some(target), None) => {
+            executor
+                .set_locked(&lock, &project, target, false, locked)
+                .await?
+        }
+        (None, None) => unreachable!("clap requires a target or a group"),
+    };
+    print_report(&report);
+    lock.release().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::root;
+    use clap::CommandFactory;
+
+    #[test]
+    fn arguments_are_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn down_takes_one_kind_of_selection() {
+        assert!(Cli::try_parse_from(["fsql", "down", "--target", "a", "--steps", "2"]).is_err());
+        assert!(Cli::try_parse_from(["fsql", "down", "--group", "g", "--steps", "2"]).is_err());
+        assert!(Cli::try_parse_from(["fsql", "lock"]).is_err());
+        assert!(Cli::try_parse_from(["fsql", "plan", "--target", "a"]).is_err());
+
+        let cli = Cli::try_parse_from(["fsql", "down", "--steps", "2", "--dir", "db"]).unwrap();
+        assert_eq!(cli.dir, PathBuf::from("db"));
+        assert!(matches!(cli.command, Command::Down { steps: Some(2), .. }));
+    }
+
+    #[test]
+    fn drift_has_its_own_exit_code() {
+        let failure = |kind| Failure::from(ExecutionError::new(kind, "failed"));
+        assert_eq!(
+            failure(ExecutionErrorKind::HashMismatch(vec![])).code,
+            EXIT_DRIFT
+        );
+        assert_eq!(
+            failure(ExecutionErrorKind::LockedModified(vec![])).code,
+            EXIT_DRIFT
+        );
+        assert_eq!(
+            failure(ExecutionErrorKind::Bootstrap).code,
+            EXIT_EXECUTION_FAILED
+        );
+    }
+
+    #[test]
+    fn new_appends_an_empty_migration() {
+        let root = root("new", &[]);
+        let cli =
+            Cli::try_parse_from(["fsql", "--dir", root.to_str().unwrap(), "validate"]).unwrap();
+
+        assert!(new_migration(&cli, "bad name", None).is_err());
+        new_migration(&cli, "first", Some(Path::new("a.sql"))).unwrap();
+        new_migration(&cli, "second", Some(Path::new("a.sql"))).unwrap();
+
+        let content = std::fs::read_to_string(root.join("a.sql")).unwrap();
+        assert_eq!(content.matches("-- +endmigration").count(), 2);
+        assert!(content.contains("\n\n-- +migration: second\n"));
+
+        let (project, _) = MigrationParser::new(&root).parse_project().unwrap();
+        assert_eq!(project.migrations().len(), 2);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
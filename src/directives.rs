@@ -0,0 +1,208 @@
+use std::ops::Range;
+
+/// Names of every `-- +name` directive the parser understands. A line that
+/// looks like a directive but is not listed here is most likely a typo.
+pub const DIRECTIVES: [&str; 22] = [
+    "fsql:version",
+    "migration",
+    "endmigration",
+    "group",
+    "endgroup",
+    "macro",
+    "endmacro",
+    "function",
+    "endfunction",
+    "version",
+    "nuclear",
+    "retries",
+    "transaction",
+    "tags",
+    "description",
+    "depends",
+    "rollback",
+    "parameters",
+    "returns",
+    "language",
+    "call",
+    "call-func",
+];
+
+/// Old names of directives, still read with a warning, and the name that
+/// replaced each of them.
+pub const DEPRECATED: [(&str, &str); 1] = [("sqlx:version", "fsql:version")];
+
+/// The name replacing `name`, when it is deprecated.
+pub fn replacement(name: &str) -> Option<&'static str> {
+    DEPRECATED
+        .iter()
+        .find(|(deprecated, _)| *deprecated == name)
+        .map(|(_, replacement)| *replacement)
+}
+
+/// `name`, or the name replacing it when it is deprecated.
+pub fn canonical(name: &str) -> &str {
+    replacement(name).unwrap_or(name)
+}
+
+/// A directive read from a `--` comment. Ranges are relative to the comment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Directive {
+    /// As in `DIRECTIVES`, `description` for `--+` and `|` for a `-- |`
+    /// continuation. Unknown names are kept as written.
+    pub name: String,
+    pub name_range: Range<usize>,
+    /// What follows `name:`, trimmed.
+    pub argument: Option<String>,
+    pub argument_range: Option<Range<usize>>,
+}
+
+impl Directive {
+    pub fn is_known(&self) -> bool {
+        self.name == "|" || DIRECTIVES.contains(&canonical(&self.name))
+    }
+}
+
+/// Reads the directive in `comment`, the text of a line comment token, or
+/// `None` when it is a plain comment. The name is the text after `-- +` up
+/// to the first `:` followed by a space, or the end of the word.
+pub fn parse(comment: &str) -> Option<Directive> {
+    let (name, name_range, argument_start) = if comment.starts_with("--+ ") {
+        ("description", 0..3, Some(3))
+    } else if comment.starts_with("-- |") {
+        ("|", 3..4, Some(4))
+    } else {
+        let rest = comment.strip_prefix("-- +")?;
+        let end = rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-' || c == ':'))
+            .unwrap_or(rest.len());
+        let name = rest[..end].trim_end_matches(':');
+        if name.is_empty() {
+            return None;
+        }
+        let name_end = 4 + name.len();
+        let argument_start = comment[name_end..].starts_with(':').then_some(name_end + 1);
+        (name, 4..name_end, argument_start)
+    };
+
+    let argument_range = argument_start.map(|from| trimmed_range(comment, from));
+    Some(Directive {
+        name: name.to_string(),
+        name_range,
+        argument: argument_range
+            .clone()
+            .map(|range| comment[range].to_string()),
+        argument_range,
+    })
+}
+
+/// Range of `text[from..]` without its surrounding whitespace.
+fn trimmed_range(text: &str, from: usize) -> Range<usize> {
+    let rest = &text[from..];
+    let start = from + rest.len() - rest.trim_start().len();
+    start..start + rest.trim().len()
+}
+
+/// Known directive closest to `name`, if it is near enough to be a typo.
+pub fn suggest(name: &str) -> Option<&'static str> {
+    let name = name.to_lowercase();
+    let max_distance = (name.chars().count() / 3).clamp(1, 3);
+    DIRECTIVES
+        .iter()
+        .map(|known| (edit_distance(&name, known), *known))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, known)| known)
+}
+
+/// Levenshtein distance between `a` and `b`, counted in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+
+    for (i, ca) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_name_and_argument_with_ranges() {
+        let comment = "-- +migration:  create_users ";
+        let directive = parse(comment).unwrap();
+        assert_eq!(directive.name, "migration");
+        assert_eq!(&comment[directive.name_range], "migration");
+        assert_eq!(directive.argument.as_deref(), Some("create_users"));
+        assert_eq!(&comment[directive.argument_range.unwrap()], "create_users");
+    }
+
+    #[test]
+    fn keeps_colons_inside_names() {
+        let directive = parse("-- +fsql:version: 0.1").unwrap();
+        assert_eq!(directive.name, "fsql:version");
+        assert_eq!(directive.argument.as_deref(), Some("0.1"));
+    }
+
+    #[test]
+    fn flags_have_no_argument() {
+        let directive = parse("-- +nuclear\r").unwrap();
+        assert_eq!(directive.name, "nuclear");
+        assert_eq!(directive.argument, None);
+    }
+
+    #[test]
+    fn reads_short_descriptions_and_continuations() {
+        let description = parse("--+ Creates the users").unwrap();
+        assert_eq!(description.name, "description");
+        assert_eq!(description.argument.as_deref(), Some("Creates the users"));
+
+        let continuation = parse("-- | b, c").unwrap();
+        assert_eq!(continuation.name, "|");
+        assert_eq!(continuation.argument.as_deref(), Some("b, c"));
+    }
+
+    #[test]
+    fn plain_comments_are_not_directives() {
+        assert_eq!(parse("-- creates the users"), None);
+        assert_eq!(parse("-- + spaced"), None);
+        assert_eq!(parse("--+description"), None);
+    }
+
+    #[test]
+    fn unknown_names_are_kept() {
+        let directive = parse("-- +migrtion: a").unwrap();
+        assert_eq!(directive.name, "migrtion");
+        assert!(!directive.is_known());
+    }
+
+    #[test]
+    fn suggests_the_closest_directive() {
+        assert_eq!(suggest("migraton"), Some("migration"));
+        assert_eq!(suggest("endgropu"), Some("endgroup"));
+        assert_eq!(suggest("Depend"), Some("depends"));
+        assert_eq!(suggest("call_func"), Some("call-func"));
+    }
+
+    #[test]
+    fn does_not_suggest_far_away_directives() {
+        assert_eq!(suggest("frobnicate"), None);
+        assert_eq!(suggest("x"), None);
+    }
+
+    #[test]
+    fn deprecated_names_stand_for_their_replacement() {
+        assert_eq!(replacement("sqlx:version"), Some("fsql:version"));
+        assert_eq!(canonical("sqlx:version"), "fsql:version");
+        assert_eq!(canonical("migration"), "migration");
+        assert!(parse("-- +sqlx:version: 0.1").unwrap().is_known());
+    }
+}
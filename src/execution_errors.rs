@@ -0,0 +1,47 @@
+#[derive(Debug, Clone)]
+pub struct ExecutionError {
+    pub kind: ExecutionErrorKind,
+    pub message: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum ExecutionErrorKind {
+    Connection,
+    InvalidTableName(String),
+    Bootstrap,
+    TrackingTable,
+    MigratorLock,
+    MigratorBusy(String),
+    UnknownStatus(String),
+    MigrationFailed(String),
+    RollbackFailed(String),
+    FunctionFailed(String),
+    GroupFailed(String, String),
+    TransactionOrder(String, String),
+    ConcurrentInTransaction(String),
+    NuclearNotAllowed(Vec<String>),
+    UnknownTarget(String),
+    NotInProject(String),
+    NotApplied(String),
+    MissingRollback(String),
+    DependentStillApplied(String, String),
+    Locked(String),
+    HashMismatch(Vec<String>),
+    LockedModified(Vec<String>),
+}
+
+impl ExecutionError {
+    pub fn new(kind: ExecutionErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    pub fn tracking_table(error: sqlx::Error) -> Self {
+        Self::new(
+            ExecutionErrorKind::TrackingTable,
+            format!("Failed to access the migrations table: {error}"),
+        )
+    }
+}
@@ -0,0 +1,322 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, BinaryHeap, HashMap},
+};
+
+use crate::{
+    models::{
+        migration::Migration,
+        project::{MigrationProject, NodeId, NodeKind, group_scope, split_file_path},
+    },
+    parse_errors::{ParseError, ParseErrorKind},
+};
+
+/// Position used to break ties between nodes that are ready at the same time:
+/// file order first, then the line the node was defined at.
+type SortKey = (usize, usize, NodeKind, usize);
+
+/// A dependency DAG over every node of a project, plus the order it runs in.
+#[derive(Debug, Clone, Default)]
+pub struct ExecutionPlan {
+    order: Vec<NodeId>,
+    edges: HashMap<NodeId, BTreeSet<NodeId>>,
+}
+
+/// Scope a node is defined in, `file::a::Migration(m)` becomes `file::a`.
+fn scope_of(path: &str) -> &str {
+    path.rsplit_once("::").map_or("", |(scope, _)| scope)
+}
+
+impl ExecutionPlan {
+    fn all_nodes(project: &MigrationProject) -> Vec<NodeId> {
+        let mut nodes = Vec::new();
+        nodes.extend((0..project.migrations().len()).map(|i| NodeId::new(NodeKind::Migration, i)));
+        nodes.extend((0..project.groups().len()).map(|i| NodeId::new(NodeKind::Group, i)));
+        nodes.extend((0..project.macros().len()).map(|i| NodeId::new(NodeKind::Macro, i)));
+        nodes.extend((0..project.functions().len()).map(|i| NodeId::new(NodeKind::Function, i)));
+        nodes
+    }
+
+    fn sort_key(project: &MigrationProject, files: &HashMap<&str, usize>, id: NodeId) -> SortKey {
+        let file = split_file_path(project.path_of(id)).0;
+        (
+            files.get(file).copied().unwrap_or(usize::MAX),
+            project.line_of(id),
+            id.kind,
+            id.index,
+        )
+    }
+
+    /// Builds the DAG from the resolved dependencies of `project`.
+    ///
+    /// A group finishes after all of its members, and the dependencies of a
+    /// group apply to every migration and subgroup nested in it.
+    pub fn build(project: &MigrationProject) -> Result<Self, ParseError> {
+        let nodes = Self::all_nodes(project);
+        let mut edges: HashMap<NodeId, BTreeSet<NodeId>> = nodes
+            .iter()
+            .map(|id| (*id, project.dependencies_of(*id).iter().copied().collect()))
+            .collect();
+
+        let members: Vec<NodeId> = nodes
+            .iter()
+            .copied()
+            .filter(|id| matches!(id.kind, NodeKind::Migration | NodeKind::Group))
+            .collect();
+
+        for (index, group) in project.groups().iter().enumerate() {
+            let group_id = NodeId::new(NodeKind::Group, index);
+            let scope = group_scope(group.path());
+            let nested_prefix = format!("{scope}::");
+            let group_dependencies: Vec<NodeId> = project.dependencies_of(group_id).to_vec();
+
+            for member in &members {
+                let member_path = project.path_of(*member);
+                if !member_path.starts_with(&nested_prefix) {
+                    continue;
+                }
+                if scope_of(member_path) == scope {
+                    edges.entry(group_id).or_default().insert(*member);
+                }
+                edges
+                    .entry(*member)
+                    .or_default()
+                    .extend(group_dependencies.iter().copied());
+            }
+        }
+
+        let files: HashMap<&str, usize> = project
+            .files()
+            .iter()
+            .enumerate()
+            .map(|(i, file)| (file.file_path.as_str(), i))
+            .collect();
+
+        let mut dependents: HashMap<NodeId, Vec<NodeId>> = HashMap::new();
+        let mut missing: HashMap<NodeId, usize> = HashMap::new();
+        for (id, dependencies) in &edges {
+            missing.insert(*id, dependencies.len());
+            for dependency in dependencies {
+                dependents.entry(*dependency).or_default().push(*id);
+            }
+        }
+
+        let mut ready: BinaryHeap<Reverse<(SortKey, NodeId)>> = nodes
+            .iter()
+            .filter(|id| missing.get(id).copied().unwrap_or(0) == 0)
+            .map(|id| Reverse((Self::sort_key(project, &files, *id), *id)))
+            .collect();
+
+        let mut order = Vec::with_capacity(nodes.len());
+        while let Some(Reverse((_, id))) = ready.pop() {
+            order.push(id);
+            for dependent in dependents.get(&id).into_iter().flatten() {
+                if let Some(count) = missing.get_mut(dependent) {
+                    *count -= 1;
+                    if *count == 0 {
+                        ready.push(Reverse((
+                            Self::sort_key(project, &files, *dependent),
+                            *dependent,
+                        )));
+                    }
+                }
+            }
+        }
+
+        let plan = Self { order, edges };
+        if plan.order.len() < nodes.len() {
+            return Err(plan.cycle_error(project, &missing));
+        }
+        Ok(plan)
+    }
+
+    /// Walks the nodes Kahn's algorithm could not schedule until one repeats.
+    fn cycle_error(
+        &self,
+        project: &MigrationProject,
+        missing: &HashMap<NodeId, usize>,
+    ) -> ParseError {
+        let blocked = |id: &NodeId| missing.get(id).copied().unwrap_or(0) > 0;
+        let start = missing
+            .keys()
+            .filter(|id| blocked(id))
+            .min()
+            .copied()
+            .expect("a cycle needs at least one blocked node");
+
+        let mut chain = vec![start];
+        let mut current = start;
+        let cycle_start = loop {
+            current = self.edges[&current]
+                .iter()
+                .copied()
+                .find(|id| blocked(id))
+                .expect("a blocked node always depends on another blocked node");
+            if let Some(position) = chain.iter().position(|id| *id == current) {
+                break position;
+            }
+            chain.push(current);
+        };
+
+        let mut cycle: Vec<String> = chain[cycle_start..]
+            .iter()
+            .map(|id| project.path_of(*id).to_string())
+            .collect();
+        cycle.push(cycle[0].clone());
+
+        let message = format!("Dependency cycle detected: {}", cycle.join(" -> "));
+        project.error_at_node(
+            chain[cycle_start],
+            ParseErrorKind::DependencyCycle(cycle),
+            message,
+        )
+    }
+
+    /// Every node in execution order.
+    pub fn order(&self) -> &[NodeId] {
+        &self.order
+    }
+
+    /// Direct dependencies of `id`, including the ones implied by groups.
+    pub fn dependencies_of(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        self.edges.get(&id).into_iter().flatten().copied()
+    }
+
+    /// Every node `id` depends on, directly or through other nodes.
+    pub fn transitive_dependencies(&self, id: NodeId) -> BTreeSet<NodeId> {
+        let mut seen = BTreeSet::new();
+        let mut stack: Vec<NodeId> = self.dependencies_of(id).collect();
+        while let Some(current) = stack.pop() {
+            if seen.insert(current) {
+                stack.extend(self.dependencies_of(current));
+            }
+        }
+        seen
+    }
+
+    /// Position of `id` in the execution order.
+    pub fn position_of(&self, id: NodeId) -> Option<usize> {
+        self.order.iter().position(|node| *node == id)
+    }
+
+    /// Migrations only, in the order they have to be applied.
+    pub fn migrations<'a>(
+        &'a self,
+        project: &'a MigrationProject,
+    ) -> impl Iterator<Item = &'a Migration> + 'a {
+        self.order
+            .iter()
+            .filter(|id| id.kind == NodeKind::Migration)
+            .map(|id| &project.migrations()[id.index])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::project;
+
+    fn ordered(project: &MigrationProject) -> Vec<&str> {
+        let plan = ExecutionPlan::build(project).unwrap();
+        plan.order().iter().map(|id| project.path_of(*id)).collect()
+    }
+
+    #[test]
+    fn independent_nodes_keep_file_and_line_order() {
+        let project = project(&[
+            ("b.sql", "-- +migration: b\nSELECT 1;\n-- +endmigration\n"),
+            (
+                "a.sql",
+                "-- +migration: a2\nSELECT 1;\n-- +endmigration\n\
+                 -- +migration: a1\nSELECT 1;\n-- +endmigration\n",
+            ),
+        ]);
+        assert_eq!(
+            ordered(&project),
+            [
+                "b.sql::Migration(b)",
+                "a.sql::Migration(a2)",
+                "a.sql::Migration(a1)"
+            ]
+        );
+    }
+
+    #[test]
+    fn dependencies_run_first() {
+        let project = project(&[(
+            "a.sql",
+            "-- +migration: late\n-- +depends: Migration(early)\nSELECT 1;\n-- +endmigration\n\
+             -- +function: f\n-- +language: sql\n-- +returns: int\nSELECT 1;\n-- +endfunction\n\
+             -- +migration: early\n-- +call-func: f\nSELECT f();\n-- +endmigration\n",
+        )]);
+        assert_eq!(
+            ordered(&project),
+            [
+                "a.sql::Function(f)",
+                "a.sql::Migration(early)",
+                "a.sql::Migration(late)"
+            ]
+        );
+    }
+
+    #[test]
+    fn groups_finish_after_their_members_and_pass_on_their_dependencies() {
+        let project = project(&[(
+            "a.sql",
+            "-- +group: g\n-- +depends: Migration(base)\n\
+             -- +migration: inside\nSELECT 1;\n-- +endmigration\n\
+             -- +endgroup\n\
+             -- +migration: after\n-- +depends: Group(g)\nSELECT 1;\n-- +endmigration\n\
+             -- +migration: base\nSELECT 1;\n-- +endmigration\n",
+        )]);
+        assert_eq!(
+            ordered(&project),
+            [
+                "a.sql::Migration(base)",
+                "a.sql::g::Migration(inside)",
+                "a.sql::Group(g)",
+                "a.sql::Migration(after)"
+            ]
+        );
+
+        let plan = ExecutionPlan::build(&project).unwrap();
+        let after = project.get("a.sql::Migration(after)").unwrap();
+        let base = project.get("a.sql::Migration(base)").unwrap();
+        assert!(plan.transitive_dependencies(after).contains(&base));
+    }
+
+    #[test]
+    fn cycles_are_reported_with_their_path() {
+        let content = "-- +migration: free\nSELECT 1;\n-- +endmigration\n\
+                       -- +migration: x\n-- +depends: Migration(y)\nSELECT 1;\n-- +endmigration\n\
+                       -- +migration: y\n-- +depends: Migration(x)\nSELECT 1;\n-- +endmigration\n";
+        let project = project(&[("a.sql", content)]);
+        let error = ExecutionPlan::build(&project).unwrap_err();
+        let ParseErrorKind::DependencyCycle(cycle) = &error.kind else {
+            panic!("expected a cycle, got {:?}", error.kind);
+        };
+        assert_eq!(
+            cycle,
+            &[
+                "a.sql::Migration(x)",
+                "a.sql::Migration(y)",
+                "a.sql::Migration(x)"
+            ]
+        );
+        assert_eq!(error.line, 4);
+    }
+
+    #[test]
+    fn a_group_depending_on_its_own_member_is_a_cycle() {
+        let project = project(&[(
+            "a.sql",
+            "-- +group: g\n-- +depends: g::Migration(m)\n\
+             -- +migration: m\nSELECT 1;\n-- +endmigration\n\
+             -- +endgroup\n",
+        )]);
+        assert!(matches!(
+            ExecutionPlan::build(&project).unwrap_err().kind,
+            ParseErrorKind::DependencyCycle(_)
+        ));
+    }
+}
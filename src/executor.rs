@@ -0,0 +1,1898 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use futures_util::{StreamExt, stream};
+use sqlx::{PgConnection, PgPool, postgres::PgPoolOptions, types::chrono};
+
+use crate::{
+    advisory_lock::{self, MigratorLock},
+    diff::{DiffLine, diff_lines},
+    execution_errors::{ExecutionError, ExecutionErrorKind},
+    execution_plan::ExecutionPlan,
+    models::{
+        migration::split_statements,
+        project::{MigrationProject, NodeId, NodeKind},
+    },
+    plan_renderer::PlanRenderer,
+    tracking::{MigrationStatus, TrackedMigration, TrackingEntry, TrackingTable},
+};
+
+const DEFAULT_MAX_CONNECTIONS: u32 = 5;
+pub const DEFAULT_MAX_PARALLEL: usize = 4;
+
+/// SQLSTATEs worth retrying: serialization failure, deadlock and lock timeout.
+/// Class `08` (connection exceptions) is matched separately.
+const TRANSIENT_SQLSTATES: [&str; 3] = ["40001", "40P01", "55P03"];
+
+#[derive(Debug, Clone, Default)]
+pub struct ExecutionReport {
+    pub applied: Vec<String>,
+    pub functions: Vec<String>,
+    pub skipped: Vec<String>,
+    pub rolled_back: Vec<String>,
+    pub repaired: Vec<String>,
+    pub locked: Vec<String>,
+    pub unlocked: Vec<String>,
+}
+
+/// An applied or locked migration whose content no longer matches the
+/// stored hash.
+#[derive(Debug, Clone)]
+pub struct HashDrift {
+    pub full_path: String,
+    pub stored: String,
+    pub current: String,
+    /// Normalized SQL as applied, when the row has it.
+    pub stored_content: Option<String>,
+    pub current_content: String,
+    pub executed_at: chrono::DateTime<chrono::Utc>,
+    pub locked: bool,
+}
+
+/// Backoff between the retries of a migration that failed transiently. The
+/// n-th retry waits `initial_delay * multiplier^(n-1)`, at most `max_delay`.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub initial_delay: Duration,
+    pub multiplier: f64,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(200),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = self
+            .multiplier
+            .max(1.0)
+            .powi(retry.saturating_sub(1) as i32);
+        self.initial_delay
+            .mul_f64(factor.min(u32::MAX as f64))
+            .min(self.max_delay)
+    }
+}
+
+/// Postgres truncates longer identifiers.
+const MAX_IDENTIFIER_LENGTH: usize = 63;
+
+/// Name of the savepoint around a migration inside the transaction of its
+/// group, so a failure points back at the migration it belongs to. It is
+/// quoted, and cut on a character boundary to fit an identifier.
+pub fn savepoint_name(project: &MigrationProject, id: NodeId) -> String {
+    let mut name = format!(
+        "fsql_{}_{}",
+        id.index,
+        project.migrations()[id.index].name().to_lowercase()
+    );
+    let mut length = name.len().min(MAX_IDENTIFIER_LENGTH);
+    while !name.is_char_boundary(length) {
+        length -= 1;
+    }
+    name.truncate(length);
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Table and index name of a `CREATE INDEX CONCURRENTLY` statement that names
+/// its index, with the name as Postgres stores it.
+fn concurrent_index(statement: &str) -> Option<(String, String)> {
+    let identifier = r#"(?:"(?:[^"]|"")+"|[A-Za-z_][A-Za-z0-9_$]*)"#;
+    let regex = regex::Regex::new(&format!(
+        concat!(
+            r"(?is)^(?:\s|--[^\n]*\n)*CREATE\s+(?:UNIQUE\s+)?INDEX\s+CONCURRENTLY\s+",
+            r"(?:IF\s+NOT\s+EXISTS\s+)?({0})\s+ON\s+(?:ONLY\s+)?({0}(?:\.{0})?)",
+        ),
+        identifier
+    ))
+    .expect("Invalid regex for concurrent indexes");
+    let captures = regex.captures(statement)?;
+
+    let index = &captures[1];
+    let index = match index
+        .strip_prefix('"')
+        .and_then(|name| name.strip_suffix('"'))
+    {
+        Some(quoted) => quoted.replace("\"\"", "\""),
+        None => index.to_lowercase(),
+    };
+    Some((captures[2].to_string(), index))
+}
+
+/// Whether running the same statements again may succeed.
+pub fn is_transient(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Database(error) => error.code().is_some_and(|code| {
+            TRANSIENT_SQLSTATES.contains(&code.as_ref()) || code.starts_with("08")
+        }),
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut => true,
+        _ => false,
+    }
+}
+
+/// What `down` rolls back.
+#[derive(Debug, Clone)]
+pub enum RollbackTarget {
+    /// The last `n` executed migrations.
+    Last(usize),
+    /// A single migration, by full path or name.
+    Migration(String),
+    /// Every migration nested in a group, by full path or name.
+    Group(String),
+}
+
+/// One unit of work of `up`.
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// A migration in its own transaction, or without one if it is concurrent.
+    Migration(NodeId),
+    /// A nuclear migration, run ahead of the plan.
+    Nuclear(NodeId),
+    /// The `CREATE OR REPLACE FUNCTION` of a function.
+    Function(NodeId),
+    /// A transactional group and what its transaction runs, in order.
+    Group(NodeId, Vec<NodeId>),
+    /// Independent concurrent migrations, run in parallel.
+    Concurrent(Vec<NodeId>),
+}
+
+impl Step {
+    /// Migrations and functions run by the step.
+    pub fn nodes(&self) -> &[NodeId] {
+        match self {
+            Step::Migration(id) | Step::Nuclear(id) | Step::Function(id) => {
+                std::slice::from_ref(id)
+            }
+            Step::Group(_, steps) => steps,
+            Step::Concurrent(wave) => wave,
+        }
+    }
+}
+
+/// Applies the migrations of a project to a PostgreSQL database, keeping the
+/// tracking table up to date.
+pub struct Executor {
+    pool: PgPool,
+    table: TrackingTable,
+    retry_policy: RetryPolicy,
+    max_parallel: usize,
+    allow_nuclear: bool,
+    lock_timeout: Duration,
+}
+
+impl Executor {
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool,
+            table: TrackingTable::default(),
+            retry_policy: RetryPolicy::default(),
+            max_parallel: DEFAULT_MAX_PARALLEL,
+            allow_nuclear: false,
+            lock_timeout: advisory_lock::DEFAULT_LOCK_TIMEOUT,
+        }
+    }
+
+    pub async fn connect(database_url: &str) -> Result<Self, ExecutionError> {
+        Self::connect_with(database_url, DEFAULT_MAX_CONNECTIONS).await
+    }
+
+    pub async fn connect_with(
+        database_url: &str,
+        max_connections: u32,
+    ) -> Result<Self, ExecutionError> {
+        let pool = PgPoolOptions::new()
+            .max_connections(max_connections)
+            .connect(database_url)
+            .await
+            .map_err(|e| {
+                ExecutionError::new(
+                    ExecutionErrorKind::Connection,
+                    format!("Failed to connect to the database: {e}"),
+                )
+            })?;
+        Ok(Self::new(pool))
+    }
+
+    pub fn set_table(&mut self, table: TrackingTable) {
+        self.table = table;
+    }
+
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// How many `concurrent` migrations may run at the same time, each on its
+    /// own pool connection.
+    pub fn set_max_parallel(&mut self, max_parallel: usize) {
+        self.max_parallel = max_parallel.max(1);
+    }
+
+    /// Nuclear migrations are refused unless this was confirmed.
+    pub fn set_allow_nuclear(&mut self, allow_nuclear: bool) {
+        self.allow_nuclear = allow_nuclear;
+    }
+
+    /// How long to wait for another migrator to release the lock of the
+    /// tracking table.
+    pub fn set_lock_timeout(&mut self, lock_timeout: Duration) {
+        self.lock_timeout = lock_timeout;
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry_policy
+    }
+
+    pub fn max_parallel(&self) -> usize {
+        self.max_parallel
+    }
+
+    pub fn pool(&self) -> &PgPool {
+        &self.pool
+    }
+
+    pub fn table(&self) -> &TrackingTable {
+        &self.table
+    }
+
+    /// Creates the tracking table if it does not exist yet.
+    pub async fn bootstrap(&self) -> Result<(), ExecutionError> {
+        self.table.bootstrap(&self.pool).await
+    }
+
+    /// Takes the advisory lock of the tracking table, held until it is
+    /// released or dropped. `up`, `down`, `repair`, `set_locked` and
+    /// `render_plan` need it, taken before the project is planned, so the
+    /// plan is never made from a tracking table another migrator is changing.
+    pub async fn lock_migrator(&self) -> Result<MigratorLock, ExecutionError> {
+        MigratorLock::acquire(&self.pool, self.table.name(), self.lock_timeout).await
+    }
+
+    pub async fn tracked(&self) -> Result<HashMap<String, TrackedMigration>, ExecutionError> {
+        self.table.fetch_all(&self.pool).await
+    }
+
+    fn is_executed(
+        tracked: &HashMap<String, TrackedMigration>,
+        path: &str,
+    ) -> Result<bool, ExecutionError> {
+        match tracked.get(path) {
+            Some(row) => Ok(row.status()? == MigrationStatus::Executed),
+            None => Ok(false),
+        }
+    }
+
+    /// Whether the function behind `id` was created as it is defined now.
+    fn is_created(
+        project: &MigrationProject,
+        tracked: &HashMap<String, TrackedMigration>,
+        id: NodeId,
+    ) -> Result<bool, ExecutionError> {
+        let function = &project.functions()[id.index];
+        match tracked.get(function.path()) {
+            Some(row) => {
+                Ok(row.status()? == MigrationStatus::Executed && row.hash == function.hash())
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Whether `id` has to run to reach `target`, every node does without one.
+    fn is_in_scope(plan: &ExecutionPlan, target: Option<NodeId>, id: NodeId) -> bool {
+        match target {
+            Some(target) => id == target || plan.transitive_dependencies(target).contains(&id),
+            None => true,
+        }
+    }
+
+    /// Migrations of the plan that are not in `executed` state, along with the
+    /// functions that were never created or changed since, in plan order.
+    /// With a `target`, only the target and what it depends on are considered.
+    pub async fn pending(
+        &self,
+        project: &MigrationProject,
+        plan: &ExecutionPlan,
+        target: Option<NodeId>,
+    ) -> Result<Vec<NodeId>, ExecutionError> {
+        self.bootstrap().await?;
+        let tracked = self.tracked().await?;
+        Self::check_drift(project, &tracked)?;
+        Self::pending_in(project, plan, target, &tracked)
+    }
+
+    fn pending_in(
+        project: &MigrationProject,
+        plan: &ExecutionPlan,
+        target: Option<NodeId>,
+        tracked: &HashMap<String, TrackedMigration>,
+    ) -> Result<Vec<NodeId>, ExecutionError> {
+        let mut migrations = HashSet::new();
+        let mut functions = HashSet::new();
+        for id in plan.order() {
+            if !Self::is_in_scope(plan, target, *id) {
+                continue;
+            }
+            match id.kind {
+                NodeKind::Migration if !Self::is_executed(tracked, project.path_of(*id))? => {
+                    migrations.insert(*id);
+                }
+                NodeKind::Function if !Self::is_created(project, tracked, *id)? => {
+                    functions.insert(*id);
+                }
+                _ => {}
+            }
+        }
+
+        // Nuclear migrations jump ahead of everything else, with the functions
+        // they call, regardless of what else they depend on
+        let nuclear: HashSet<NodeId> = migrations
+            .iter()
+            .filter(|id| project.is_nuclear(**id))
+            .flat_map(|id| {
+                plan.transitive_dependencies(*id)
+                    .into_iter()
+                    .filter(|dependency| functions.contains(dependency))
+                    .chain([*id])
+            })
+            .collect();
+
+        // Functions are (re)created right before the first migration needing them
+        let (mut pending, rest): (Vec<NodeId>, Vec<NodeId>) = plan
+            .order()
+            .iter()
+            .filter(|id| migrations.contains(id) || functions.contains(id))
+            .partition(|id| nuclear.contains(id));
+        pending.extend(rest);
+        Ok(pending)
+    }
+
+    /// Fails when nuclear migrations are pending without `allow_nuclear`.
+    fn check_nuclear(
+        &self,
+        project: &MigrationProject,
+        pending: &[NodeId],
+    ) -> Result<(), ExecutionError> {
+        let nuclear: Vec<String> = pending
+            .iter()
+            .filter(|id| id.kind == NodeKind::Migration && project.is_nuclear(**id))
+            .map(|id| project.path_of(*id).to_string())
+            .collect();
+        if nuclear.is_empty() || self.allow_nuclear {
+            return Ok(());
+        }
+
+        Err(ExecutionError::new(
+            ExecutionErrorKind::NuclearNotAllowed(nuclear.clone()),
+            format!(
+                "Nuclear migrations run ahead of the plan and ignore their dependencies, pass --allow-nuclear to apply them:\n  {}",
+                nuclear.join("\n  ")
+            ),
+        ))
+    }
+
+    /// Runs the `CREATE OR REPLACE FUNCTION` of a function and records it in
+    /// the same transaction.
+    async fn create_function(
+        &self,
+        project: &MigrationProject,
+        id: NodeId,
+    ) -> Result<(), ExecutionError> {
+        let function = &project.functions()[id.index];
+        let failed = |e: sqlx::Error| {
+            ExecutionError::new(
+                ExecutionErrorKind::FunctionFailed(function.path().to_string()),
+                format!("Function '{}' could not be created: {e}", function.path()),
+            )
+        };
+
+        let mut transaction = self.pool.begin().await.map_err(failed)?;
+        sqlx::raw_sql(function.parsed_body())
+            .execute(&mut *transaction)
+            .await
+            .map_err(failed)?;
+        let entry = Self::tracking_entry(project, id, MigrationStatus::Executed, 1);
+        self.table.record(&mut transaction, &entry).await?;
+        transaction.commit().await.map_err(failed)?;
+        Ok(())
+    }
+
+    /// The row of a migration or function.
+    fn tracking_entry<'a>(
+        project: &'a MigrationProject,
+        id: NodeId,
+        status: MigrationStatus,
+        attempts: u32,
+    ) -> TrackingEntry<'a> {
+        let dependencies = project
+            .dependencies_of(id)
+            .iter()
+            .map(|dependency| project.path_of(*dependency).to_string())
+            .collect();
+
+        if id.kind == NodeKind::Function {
+            let function = &project.functions()[id.index];
+            let mut tags: Vec<String> = function
+                .tags()
+                .iter()
+                .map(|tag| tag.as_str().to_string())
+                .collect();
+            tags.sort();
+
+            return TrackingEntry {
+                kind: NodeKind::Function,
+                full_path: function.path(),
+                name: function.name(),
+                hash: function.hash(),
+                rollback: false,
+                status,
+                description: function.description(),
+                tags,
+                dependencies,
+                attempts: attempts as i32,
+                nuclear: false,
+                bypassed: Vec::new(),
+                content: function.normalized_content(),
+            };
+        }
+
+        let migration = &project.migrations()[id.index];
+        let mut tags: Vec<String> = migration
+            .tags()
+            .iter()
+            .map(|tag| tag.as_str().to_string())
+            .collect();
+        tags.sort();
+
+        TrackingEntry {
+            kind: NodeKind::Migration,
+            full_path: migration.path(),
+            name: migration.name(),
+            hash: project.migration_hash(id),
+            rollback: migration.has_rollback(),
+            status,
+            description: migration.description(),
+            tags,
+            dependencies,
+            attempts: attempts as i32,
+            nuclear: project.is_nuclear(id),
+            bypassed: Vec::new(),
+            content: migration.normalized_content(),
+        }
+    }
+
+    /// Runs one migration and records it in the same transaction. Transient
+    /// failures are retried as often as the migration or its group allow,
+    /// every failed attempt is stored in the tracking table. `bypassed` are
+    /// the pending dependencies a nuclear migration runs ahead of.
+    async fn apply_migration(
+        &self,
+        project: &MigrationProject,
+        id: NodeId,
+        bypassed: &[String],
+    ) -> Result<(), ExecutionError> {
+        let migration = &project.migrations()[id.index];
+        let retries = project.retries_of(id);
+        let concurrent = project.is_concurrent(id);
+        // Statements of a concurrent migration that stay applied after a failure
+        let mut applied = 0;
+        let mut attempt = 1;
+
+        loop {
+            let entry = TrackingEntry {
+                bypassed: bypassed.to_vec(),
+                ..Self::tracking_entry(project, id, MigrationStatus::Executed, attempt)
+            };
+            let result = if concurrent {
+                self.try_apply_concurrent(migration.sql(), &entry, &mut applied)
+                    .await?
+            } else {
+                self.try_apply(migration.sql(), &entry).await?
+            };
+            let error = match result {
+                Ok(()) => return Ok(()),
+                Err(error) => error,
+            };
+            self.table
+                .record_attempt(&self.pool, &entry, &error)
+                .await?;
+
+            if attempt <= retries && is_transient(&error) {
+                tokio::time::sleep(self.retry_policy.delay(attempt)).await;
+                attempt += 1;
+                continue;
+            }
+
+            let attempts = if attempt > 1 {
+                format!(" after {attempt} attempts")
+            } else {
+                String::new()
+            };
+            return Err(ExecutionError::new(
+                ExecutionErrorKind::MigrationFailed(migration.path().to_string()),
+                format!("Migration '{}' failed{attempts}: {error}", migration.path()),
+            ));
+        }
+    }
+
+    /// A single attempt at a migration. The outer error means the tracking
+    /// table could not be written, the inner one that the migration failed.
+    async fn try_apply(
+        &self,
+        sql: &str,
+        entry: &TrackingEntry<'_>,
+    ) -> Result<Result<(), sqlx::Error>, ExecutionError> {
+        let mut transaction = match self.pool.begin().await {
+            Ok(transaction) => transaction,
+            Err(error) => return Ok(Err(error)),
+        };
+        if !sql.trim().is_empty()
+            && let Err(error) = sqlx::raw_sql(sql).execute(&mut *transaction).await
+        {
+            return Ok(Err(error));
+        }
+        self.table.record(&mut transaction, entry).await?;
+        Ok(transaction.commit().await)
+    }
+
+    /// A single attempt at a `concurrent` migration. Its statements are sent
+    /// one by one outside of a transaction, so a failure leaves the ones
+    /// before it applied. The row is `pending` until every statement ran,
+    /// which `status` reports as partly applied. The attempt skips the first
+    /// `applied` statements, run by earlier attempts, and counts the ones it
+    /// runs.
+    async fn try_apply_concurrent(
+        &self,
+        sql: &str,
+        entry: &TrackingEntry<'_>,
+        applied: &mut usize,
+    ) -> Result<Result<(), sqlx::Error>, ExecutionError> {
+        let mut connection = match self.pool.acquire().await {
+            Ok(connection) => connection,
+            Err(error) => return Ok(Err(error)),
+        };
+        let started = TrackingEntry {
+            status: MigrationStatus::Pending,
+            ..entry.clone()
+        };
+        self.table.record(&mut connection, &started).await?;
+
+        for statement in split_statements(sql).into_iter().skip(*applied) {
+            let result = async {
+                if let Some((table, index)) = concurrent_index(statement) {
+                    Self::drop_invalid_index(&mut connection, &table, &index).await?;
+                }
+                sqlx::raw_sql(statement).execute(&mut *connection).await
+            }
+            .await;
+            if let Err(error) = result {
+                return Ok(Err(error));
+            }
+            *applied += 1;
+        }
+        self.table.record(&mut connection, entry).await?;
+        Ok(Ok(()))
+    }
+
+    /// Drops the index `index` of `table` if a failed `CREATE INDEX
+    /// CONCURRENTLY` left it behind as invalid, so creating it again does not
+    /// fail because it exists.
+    async fn drop_invalid_index(
+        connection: &mut PgConnection,
+        table: &str,
+        index: &str,
+    ) -> Result<(), sqlx::Error> {
+        let invalid: Option<String> = sqlx::query_scalar(
+            "SELECT i.indexrelid::regclass::text FROM pg_index i \
+             JOIN pg_class c ON c.oid = i.indexrelid \
+             WHERE i.indrelid = to_regclass($1) AND c.relname = $2 AND NOT i.indisvalid",
+        )
+        .bind(table)
+        .bind(index)
+        .fetch_optional(&mut *connection)
+        .await?;
+        if let Some(invalid) = invalid {
+            sqlx::raw_sql(&format!("DROP INDEX CONCURRENTLY IF EXISTS {invalid}"))
+                .execute(connection)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Pending `concurrent` migrations outside of transactional groups whose
+    /// dependencies have all run, so they can run side by side.
+    fn concurrent_wave(
+        project: &MigrationProject,
+        plan: &ExecutionPlan,
+        pending: &[NodeId],
+        done: &HashSet<NodeId>,
+    ) -> Vec<NodeId> {
+        pending
+            .iter()
+            .filter(|id| {
+                id.kind == NodeKind::Migration
+                    && !done.contains(id)
+                    && project.is_concurrent(**id)
+                    && project.transaction_group_of(**id).is_none()
+            })
+            .filter(|id| {
+                let dependencies = plan.transitive_dependencies(**id);
+                pending
+                    .iter()
+                    .filter(|other| dependencies.contains(other))
+                    .all(|other| done.contains(other))
+            })
+            .copied()
+            .collect()
+    }
+
+    /// Applies independent `concurrent` migrations in parallel, at most
+    /// `max_parallel` at a time. Every migration gets to finish before the
+    /// first failure is returned.
+    async fn apply_concurrently(
+        &self,
+        project: &MigrationProject,
+        wave: &[NodeId],
+    ) -> Result<(), ExecutionError> {
+        let results: Vec<Result<(), ExecutionError>> = stream::iter(wave)
+            .map(|id| self.apply_migration(project, *id, &[]))
+            .buffer_unordered(self.max_parallel)
+            .collect()
+            .await;
+        results.into_iter().collect()
+    }
+
+    /// Executed or locked migrations of `project` whose hash changed since
+    /// they were recorded.
+    pub fn drift(
+        project: &MigrationProject,
+        tracked: &HashMap<String, TrackedMigration>,
+    ) -> Result<Vec<HashDrift>, ExecutionError> {
+        let mut drifts = Vec::new();
+        for (index, migration) in project.migrations().iter().enumerate() {
+            let Some(row) = tracked.get(migration.path()) else {
+                continue;
+            };
+            if row.status()? != MigrationStatus::Executed && !row.locked {
+                continue;
+            }
+
+            let current = project.migration_hash(NodeId::new(NodeKind::Migration, index));
+            if current != row.hash {
+                drifts.push(HashDrift {
+                    full_path: row.full_path.clone(),
+                    stored: row.hash.clone(),
+                    current,
+                    stored_content: row.content.clone(),
+                    current_content: migration.normalized_content(),
+                    executed_at: row.created_at,
+                    locked: row.locked,
+                });
+            }
+        }
+        Ok(drifts)
+    }
+
+    /// Created functions whose definition changed since, `up` creates them
+    /// again.
+    pub fn function_drift(
+        project: &MigrationProject,
+        tracked: &HashMap<String, TrackedMigration>,
+    ) -> Result<Vec<HashDrift>, ExecutionError> {
+        let mut drifts = Vec::new();
+        for function in project.functions() {
+            let Some(row) = tracked.get(function.path()) else {
+                continue;
+            };
+            if row.status()? != MigrationStatus::Executed {
+                continue;
+            }
+
+            let current = function.hash();
+            if current != row.hash {
+                drifts.push(HashDrift {
+                    full_path: row.full_path.clone(),
+                    stored: row.hash.clone(),
+                    current,
+                    stored_content: row.content.clone(),
+                    current_content: function.normalized_content(),
+                    executed_at: row.created_at,
+                    locked: row.locked,
+                });
+            }
+        }
+        Ok(drifts)
+    }
+
+    /// Fails with a report of every edited migration, if there is any.
+    /// Edited locked migrations are reported on their own, they cannot be
+    /// repaired.
+    fn check_drift(
+        project: &MigrationProject,
+        tracked: &HashMap<String, TrackedMigration>,
+    ) -> Result<(), ExecutionError> {
+        let (locked, drifts): (Vec<HashDrift>, Vec<HashDrift>) = Self::drift(project, tracked)?
+            .into_iter()
+            .partition(|drift| drift.locked);
+        if !locked.is_empty() {
+            return Err(Self::locked_modified(locked));
+        }
+        if drifts.is_empty() {
+            return Ok(());
+        }
+
+        let mut message =
+            String::from("Applied migrations were modified after they were executed:\n");
+        Self::describe_drifts(&mut message, &drifts);
+        message.push_str("Revert the edits, or run `repair` to accept the new content.");
+
+        Err(ExecutionError::new(
+            ExecutionErrorKind::HashMismatch(
+                drifts.into_iter().map(|drift| drift.full_path).collect(),
+            ),
+            message,
+        ))
+    }
+
+    fn locked_modified(drifts: Vec<HashDrift>) -> ExecutionError {
+        let mut message = String::from("LOCKED migrations were modified, they must not change:\n");
+        Self::describe_drifts(&mut message, &drifts);
+        message.push_str(
+            "Revert the edits. Locked migrations cannot be repaired, `unlock` them first.",
+        );
+
+        ExecutionError::new(
+            ExecutionErrorKind::LockedModified(
+                drifts.into_iter().map(|drift| drift.full_path).collect(),
+            ),
+            message,
+        )
+    }
+
+    /// Lists the drifts with their hashes, followed by the lines that changed
+    /// when the applied SQL was stored.
+    fn describe_drifts(message: &mut String, drifts: &[HashDrift]) {
+        for drift in drifts {
+            message.push_str(&format!(
+                "  ~ {}\n    - {}  (executed {})\n    + {}\n",
+                drift.full_path,
+                drift.stored,
+                drift.executed_at.format("%Y-%m-%d %H:%M:%S UTC"),
+                drift.current
+            ));
+            Self::describe_changes(message, drift);
+        }
+    }
+
+    /// The lines that changed since `drift` was recorded, when its SQL was
+    /// stored.
+    pub fn describe_changes(message: &mut String, drift: &HashDrift) {
+        match &drift.stored_content {
+            Some(stored) => {
+                for line in diff_lines(stored, &drift.current_content) {
+                    match line {
+                        DiffLine::Same(_) => {}
+                        DiffLine::Removed(line) => message.push_str(&format!("      - {line}\n")),
+                        DiffLine::Added(line) => message.push_str(&format!("      + {line}\n")),
+                    }
+                }
+            }
+            None => message.push_str(
+                "      (applied before its SQL was stored, only the hashes can be compared)\n",
+            ),
+        }
+    }
+
+    /// Accepts the current content of edited migrations by storing their new
+    /// hash. With a `target`, only that migration is repaired. Nothing is
+    /// repaired while a selected migration is locked.
+    pub async fn repair(
+        &self,
+        _lock: &MigratorLock,
+        project: &MigrationProject,
+        target: Option<&str>,
+    ) -> Result<ExecutionReport, ExecutionError> {
+        self.bootstrap().await?;
+        let tracked = self.tracked().await?;
+
+        let only = match target {
+            Some(query) => {
+                Some(project.path_of(Self::find_one(project, query, NodeKind::Migration)?))
+            }
+            None => None,
+        };
+
+        let (locked, drifts): (Vec<HashDrift>, Vec<HashDrift>) = Self::drift(project, &tracked)?
+            .into_iter()
+            .filter(|drift| only.is_none_or(|path| path == drift.full_path))
+            .partition(|drift| drift.locked);
+        if !locked.is_empty() {
+            return Err(Self::locked_modified(locked));
+        }
+
+        let mut report = ExecutionReport::default();
+        for drift in drifts {
+            self.table
+                .update_hash(
+                    &self.pool,
+                    &drift.full_path,
+                    &drift.current,
+                    &drift.current_content,
+                )
+                .await?;
+            report.repaired.push(drift.full_path);
+        }
+        Ok(report)
+    }
+
+    /// Locks or unlocks a migration, or every migration nested in a group when
+    /// `group` is set. Only migrations with a row in the tracking table can be
+    /// locked.
+    pub async fn set_locked(
+        &self,
+        _lock: &MigratorLock,
+        project: &MigrationProject,
+        query: &str,
+        group: bool,
+        locked: bool,
+    ) -> Result<ExecutionReport, ExecutionError> {
+        self.bootstrap().await?;
+        let tracked = self.tracked().await?;
+
+        let selected = if group {
+            project.migrations_in_group(Self::find_one(project, query, NodeKind::Group)?)
+        } else {
+            vec![Self::find_one(project, query, NodeKind::Migration)?]
+        };
+        let mut paths = Vec::new();
+        for id in selected {
+            let path = project.path_of(id);
+            match tracked.get(path) {
+                Some(row) if row.locked == locked => {}
+                Some(_) => paths.push(path.to_string()),
+                None if group => {}
+                None => {
+                    return Err(ExecutionError::new(
+                        ExecutionErrorKind::NotApplied(path.to_string()),
+                        format!("'{path}' has never been applied and cannot be locked"),
+                    ));
+                }
+            }
+        }
+
+        let changed = self.table.set_locked(&self.pool, &paths, locked).await?;
+        let mut report = ExecutionReport::default();
+        if locked {
+            report.locked = changed;
+        } else {
+            report.unlocked = changed;
+        }
+        Ok(report)
+    }
+
+    /// Applies every pending migration of `plan`, skipping the ones the
+    /// tracking table already marks as executed. With a `target`, only the
+    /// target and what it depends on are applied.
+    pub async fn up(
+        &self,
+        _lock: &MigratorLock,
+        project: &MigrationProject,
+        plan: &ExecutionPlan,
+        target: Option<NodeId>,
+    ) -> Result<ExecutionReport, ExecutionError> {
+        self.bootstrap().await?;
+        let tracked = self.tracked().await?;
+        Self::check_drift(project, &tracked)?;
+
+        let pending = Self::pending_in(project, plan, target, &tracked)?;
+        self.check_nuclear(project, &pending)?;
+        let mut report = ExecutionReport::default();
+        for id in plan.order() {
+            if id.kind == NodeKind::Migration
+                && Self::is_in_scope(plan, target, *id)
+                && !pending.contains(id)
+            {
+                report.skipped.push(project.path_of(*id).to_string());
+            }
+        }
+
+        for step in Self::schedule(project, plan, &pending)? {
+            match &step {
+                Step::Migration(id) => self.apply_migration(project, *id, &[]).await?,
+                Step::Nuclear(id) => {
+                    // What it depends on and did not run yet, for the audit trail
+                    let bypassed: Vec<String> = plan
+                        .order()
+                        .iter()
+                        .filter(|dependency| {
+                            dependency.kind == NodeKind::Migration
+                                && pending.contains(dependency)
+                                && plan.transitive_dependencies(*id).contains(dependency)
+                        })
+                        .map(|dependency| project.path_of(*dependency).to_string())
+                        .filter(|path| !report.applied.contains(path))
+                        .collect();
+                    self.apply_migration(project, *id, &bypassed).await?
+                }
+                Step::Function(id) => self.create_function(project, *id).await?,
+                Step::Group(group, steps) => self.apply_group(project, *group, steps).await?,
+                Step::Concurrent(wave) => self.apply_concurrently(project, wave).await?,
+            }
+
+            for id in step.nodes() {
+                let path = project.path_of(*id).to_string();
+                match id.kind {
+                    NodeKind::Function => report.functions.push(path),
+                    _ => report.applied.push(path),
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// The SQL script `up` would run for `target`, see [`PlanRenderer`]. Like
+    /// `up`, it is planned under the lock, so another migrator cannot change
+    /// the tracking table meanwhile.
+    pub async fn render_plan(
+        &self,
+        _lock: &MigratorLock,
+        project: &MigrationProject,
+        plan: &ExecutionPlan,
+        target: Option<NodeId>,
+    ) -> Result<String, ExecutionError> {
+        let pending = self.pending(project, plan, target).await?;
+        let steps = Self::schedule(project, plan, &pending)?;
+        Ok(PlanRenderer::new(project, self).render(&steps))
+    }
+
+    /// Splits `pending` into the steps `up` runs, in order.
+    pub fn schedule(
+        project: &MigrationProject,
+        plan: &ExecutionPlan,
+        pending: &[NodeId],
+    ) -> Result<Vec<Step>, ExecutionError> {
+        let mut steps = Vec::new();
+        let mut done = HashSet::new();
+        for id in pending {
+            if done.contains(id) {
+                continue;
+            }
+
+            let nuclear = id.kind == NodeKind::Migration && project.is_nuclear(*id);
+            let step = match project.transaction_group_of(*id) {
+                // Nuclear migrations run on their own, even inside a transactional group
+                _ if nuclear => Step::Nuclear(*id),
+                Some(group) => Step::Group(
+                    group,
+                    Self::group_steps(project, plan, group, pending, &done)?,
+                ),
+                None if id.kind == NodeKind::Function => Step::Function(*id),
+                None if project.is_concurrent(*id) => {
+                    Step::Concurrent(Self::concurrent_wave(project, plan, pending, &done))
+                }
+                None => Step::Migration(*id),
+            };
+
+            done.extend(step.nodes().iter().copied());
+            steps.push(step);
+        }
+        Ok(steps)
+    }
+
+    /// What the transaction of `group` runs, in order: its pending migrations
+    /// and the functions they call that were not created yet. Fails if one
+    /// of them waits for a migration outside of the group.
+    fn group_steps(
+        project: &MigrationProject,
+        plan: &ExecutionPlan,
+        group: NodeId,
+        pending: &[NodeId],
+        done: &HashSet<NodeId>,
+    ) -> Result<Vec<NodeId>, ExecutionError> {
+        let in_group = |id: &NodeId| {
+            id.kind == NodeKind::Migration
+                && !done.contains(id)
+                && project.transaction_group_of(*id) == Some(group)
+        };
+
+        let mut steps: Vec<NodeId> = Vec::new();
+        for member in pending.iter().filter(|id| in_group(id)) {
+            if project.is_concurrent(*member) {
+                return Err(ExecutionError::new(
+                    ExecutionErrorKind::ConcurrentInTransaction(
+                        project.path_of(*member).to_string(),
+                    ),
+                    format!(
+                        "'{}' is concurrent and cannot run inside the transaction of group '{}'",
+                        project.path_of(*member),
+                        project.path_of(group)
+                    ),
+                ));
+            }
+            let dependencies = plan.transitive_dependencies(*member);
+            for dependency in pending.iter().filter(|id| dependencies.contains(id)) {
+                if done.contains(dependency) || steps.contains(dependency) || in_group(dependency) {
+                    continue;
+                }
+                if dependency.kind != NodeKind::Function {
+                    return Err(ExecutionError::new(
+                        ExecutionErrorKind::TransactionOrder(
+                            project.path_of(group).to_string(),
+                            project.path_of(*dependency).to_string(),
+                        ),
+                        format!(
+                            "Group '{}' cannot run in one transaction: '{}' depends on '{}', which has to run in between",
+                            project.path_of(group),
+                            project.path_of(*member),
+                            project.path_of(*dependency)
+                        ),
+                    ));
+                }
+                steps.push(*dependency);
+            }
+            steps.push(*member);
+        }
+        Ok(steps)
+    }
+
+    /// Runs the steps of a transactional group in one transaction, with a
+    /// savepoint around every migration. A failure rolls the whole group back.
+    async fn apply_group(
+        &self,
+        project: &MigrationProject,
+        group: NodeId,
+        steps: &[NodeId],
+    ) -> Result<(), ExecutionError> {
+        let retries = steps
+            .iter()
+            .filter(|id| id.kind == NodeKind::Migration)
+            .map(|id| project.retries_of(*id))
+            .max()
+            .unwrap_or_default();
+        let mut attempt = 1;
+
+        loop {
+            let (failed, error) = match self.try_apply_group(project, steps, attempt).await? {
+                Ok(()) => return Ok(()),
+                Err(failure) => failure,
+            };
+            if failed.kind == NodeKind::Migration {
+                let entry =
+                    Self::tracking_entry(project, failed, MigrationStatus::Executed, attempt);
+                self.table
+                    .record_attempt(&self.pool, &entry, &error)
+                    .await?;
+            }
+
+            if attempt <= retries && is_transient(&error) {
+                tokio::time::sleep(self.retry_policy.delay(attempt)).await;
+                attempt += 1;
+                continue;
+            }
+
+            let group_path = project.path_of(group);
+            let failed_path = project.path_of(failed);
+            return Err(ExecutionError::new(
+                ExecutionErrorKind::GroupFailed(group_path.to_string(), failed_path.to_string()),
+                format!("Group '{group_path}' was rolled back, '{failed_path}' failed: {error}"),
+            ));
+        }
+    }
+
+    /// A single attempt at a transactional group. The inner error carries the
+    /// step that failed.
+    async fn try_apply_group(
+        &self,
+        project: &MigrationProject,
+        steps: &[NodeId],
+        attempt: u32,
+    ) -> Result<Result<(), (NodeId, sqlx::Error)>, ExecutionError> {
+        let Some(first) = steps.first() else {
+            return Ok(Ok(()));
+        };
+        let mut transaction = match self.pool.begin().await {
+            Ok(transaction) => transaction,
+            Err(error) => return Ok(Err((*first, error))),
+        };
+
+        for step in steps {
+            if step.kind == NodeKind::Function {
+                let sql = project.functions()[step.index].parsed_body();
+                if let Err(error) = sqlx::raw_sql(sql).execute(&mut *transaction).await {
+                    return Ok(Err((*step, error)));
+                }
+                let entry = Self::tracking_entry(project, *step, MigrationStatus::Executed, 1);
+                self.table.record(&mut transaction, &entry).await?;
+                continue;
+            }
+
+            let sql = project.migrations()[step.index].sql();
+            let savepoint = savepoint_name(project, *step);
+            let result = async {
+                sqlx::raw_sql(&format!("SAVEPOINT {savepoint}"))
+                    .execute(&mut *transaction)
+                    .await?;
+                if !sql.trim().is_empty() {
+                    sqlx::raw_sql(sql).execute(&mut *transaction).await?;
+                }
+                Ok(())
+            }
+            .await;
+            if let Err(error) = result {
+                // Leave the transaction usable, even though it is rolled back anyway
+                let _ = sqlx::raw_sql(&format!("ROLLBACK TO SAVEPOINT {savepoint}"))
+                    .execute(&mut *transaction)
+                    .await;
+                return Ok(Err((*step, error)));
+            }
+
+            let entry = Self::tracking_entry(project, *step, MigrationStatus::Executed, attempt);
+            self.table.record(&mut transaction, &entry).await?;
+            if let Err(error) = sqlx::raw_sql(&format!("RELEASE SAVEPOINT {savepoint}"))
+                .execute(&mut *transaction)
+                .await
+            {
+                return Ok(Err((*step, error)));
+            }
+        }
+
+        let last = steps.last().copied().unwrap_or(*first);
+        Ok(transaction.commit().await.map_err(|error| (last, error)))
+    }
+
+    pub fn find_one(
+        project: &MigrationProject,
+        query: &str,
+        kind: NodeKind,
+    ) -> Result<NodeId, ExecutionError> {
+        match project.find(query, kind).as_slice() {
+            [id] => Ok(*id),
+            [] => Err(ExecutionError::new(
+                ExecutionErrorKind::UnknownTarget(query.to_string()),
+                format!("No {} matches '{query}'", kind.as_str().to_lowercase()),
+            )),
+            many => Err(ExecutionError::new(
+                ExecutionErrorKind::UnknownTarget(query.to_string()),
+                format!(
+                    "'{query}' is ambiguous, candidates: {}",
+                    many.iter()
+                        .map(|id| project.path_of(*id))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            )),
+        }
+    }
+
+    /// Executed migrations selected by `target`.
+    fn rollback_selection(
+        project: &MigrationProject,
+        tracked: &HashMap<String, TrackedMigration>,
+        target: &RollbackTarget,
+    ) -> Result<Vec<NodeId>, ExecutionError> {
+        match target {
+            RollbackTarget::Last(count) => {
+                let mut executed: Vec<&TrackedMigration> = Vec::new();
+                for row in tracked.values() {
+                    if !row.is_function() && row.status()? == MigrationStatus::Executed {
+                        executed.push(row);
+                    }
+                }
+                executed.sort_by_key(|row| Reverse((row.created_at, row.id)));
+
+                executed
+                    .into_iter()
+                    .take(*count)
+                    .map(|row| {
+                        project.get(&row.full_path).ok_or_else(|| {
+                            ExecutionError::new(
+                                ExecutionErrorKind::NotInProject(row.full_path.clone()),
+                                format!(
+                                    "'{}' is applied but no longer exists in the project",
+                                    row.full_path
+                                ),
+                            )
+                        })
+                    })
+                    .collect()
+            }
+            RollbackTarget::Migration(query) => {
+                let id = Self::find_one(project, query, NodeKind::Migration)?;
+                let path = project.path_of(id);
+                if !Self::is_executed(tracked, path)? {
+                    return Err(ExecutionError::new(
+                        ExecutionErrorKind::NotApplied(path.to_string()),
+                        format!("'{path}' is not applied"),
+                    ));
+                }
+                Ok(vec![id])
+            }
+            RollbackTarget::Group(query) => {
+                let group = Self::find_one(project, query, NodeKind::Group)?;
+                let mut selected = Vec::new();
+                for id in project.migrations_in_group(group) {
+                    if Self::is_executed(tracked, project.path_of(id))? {
+                        selected.push(id);
+                    }
+                }
+                Ok(selected)
+            }
+        }
+    }
+
+    /// Refuses a rollback of a locked migration, or of one that has nothing
+    /// to run. Unless `force` is set, also refuses to leave an applied
+    /// migration without one of its dependencies.
+    fn check_rollback(
+        project: &MigrationProject,
+        plan: &ExecutionPlan,
+        tracked: &HashMap<String, TrackedMigration>,
+        selected: &[NodeId],
+        force: bool,
+    ) -> Result<(), ExecutionError> {
+        for id in selected {
+            let migration = &project.migrations()[id.index];
+            if tracked.get(migration.path()).is_some_and(|row| row.locked) {
+                return Err(ExecutionError::new(
+                    ExecutionErrorKind::Locked(migration.path().to_string()),
+                    format!(
+                        "'{}' is locked and cannot be rolled back, `unlock` it first",
+                        migration.path()
+                    ),
+                ));
+            }
+            if !migration.has_rollback() {
+                return Err(ExecutionError::new(
+                    ExecutionErrorKind::MissingRollback(migration.path().to_string()),
+                    format!("'{}' has no -- +rollback section", migration.path()),
+                ));
+            }
+        }
+        if force {
+            return Ok(());
+        }
+
+        let selected_set: HashSet<NodeId> = selected.iter().copied().collect();
+        for id in plan.order() {
+            if id.kind != NodeKind::Migration || selected_set.contains(id) {
+                continue;
+            }
+            let path = project.path_of(*id);
+            if !Self::is_executed(tracked, path)? {
+                continue;
+            }
+            if let Some(dependency) = plan
+                .transitive_dependencies(*id)
+                .into_iter()
+                .find(|dependency| selected_set.contains(dependency))
+            {
+                let dependency = project.path_of(dependency);
+                return Err(ExecutionError::new(
+                    ExecutionErrorKind::DependentStillApplied(
+                        dependency.to_string(),
+                        path.to_string(),
+                    ),
+                    format!(
+                        "Cannot roll back '{dependency}', '{path}' depends on it and is still applied"
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the rollback section of one migration and marks it in the same
+    /// transaction, which is dropped if the row turns out to be locked.
+    async fn rollback_migration(
+        &self,
+        project: &MigrationProject,
+        id: NodeId,
+    ) -> Result<(), ExecutionError> {
+        let migration = &project.migrations()[id.index];
+        let failed = |e: sqlx::Error| {
+            ExecutionError::new(
+                ExecutionErrorKind::RollbackFailed(migration.path().to_string()),
+                format!("Rollback of '{}' failed: {e}", migration.path()),
+            )
+        };
+
+        // Like when it was applied, statements such as `DROP INDEX CONCURRENTLY`
+        // cannot run in a transaction, a failure leaves the ones before it applied
+        if project.is_concurrent(id) {
+            let mut connection = self.pool.acquire().await.map_err(failed)?;
+            for statement in split_statements(migration.sql_rollback()) {
+                sqlx::raw_sql(statement)
+                    .execute(&mut *connection)
+                    .await
+                    .map_err(failed)?;
+            }
+            return self
+                .table
+                .mark_rolled_back(&mut connection, migration.path())
+                .await;
+        }
+
+        let mut transaction = self.pool.begin().await.map_err(failed)?;
+        sqlx::raw_sql(migration.sql_rollback())
+            .execute(&mut *transaction)
+            .await
+            .map_err(failed)?;
+        self.table
+            .mark_rolled_back(&mut transaction, migration.path())
+            .await?;
+        transaction.commit().await.map_err(failed)?;
+        Ok(())
+    }
+
+    /// Migrations `down` would roll back for `target`, in the order it would
+    /// run them. `force` skips the check for applied dependents, locked
+    /// migrations are refused regardless.
+    pub async fn rollback_plan(
+        &self,
+        project: &MigrationProject,
+        plan: &ExecutionPlan,
+        target: &RollbackTarget,
+        force: bool,
+    ) -> Result<Vec<NodeId>, ExecutionError> {
+        self.bootstrap().await?;
+        let tracked = self.tracked().await?;
+        Self::check_drift(project, &tracked)?;
+
+        let mut selected = Self::rollback_selection(project, &tracked, target)?;
+        Self::check_rollback(project, plan, &tracked, &selected, force)?;
+        selected.sort_by_key(|id| Reverse(plan.position_of(*id)));
+        Ok(selected)
+    }
+
+    /// Rolls back the migrations selected by `target`, dependents first.
+    pub async fn down(
+        &self,
+        _lock: &MigratorLock,
+        project: &MigrationProject,
+        plan: &ExecutionPlan,
+        target: &RollbackTarget,
+        force: bool,
+    ) -> Result<ExecutionReport, ExecutionError> {
+        let selected = self.rollback_plan(project, plan, target, force).await?;
+
+        let mut report = ExecutionReport::default();
+        for id in selected {
+            self.rollback_migration(project, id).await?;
+            report.rolled_back.push(project.path_of(id).to_string());
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{executor, planned};
+
+    const FILE: &str = "-- +function: f\n-- +language: sql\n-- +returns: int\nSELECT 1;\n-- +endfunction\n\
+                        -- +function: unused\n-- +language: sql\n-- +returns: int\nSELECT 2;\n-- +endfunction\n\
+                        -- +migration: m\n-- +call-func: f\nSELECT f();\n-- +endmigration\n";
+
+    /// Rows as `up` records them for `ids`.
+    fn tracked(project: &MigrationProject, ids: &[NodeId]) -> HashMap<String, TrackedMigration> {
+        ids.iter()
+            .map(|id| {
+                let entry = Executor::tracking_entry(project, *id, MigrationStatus::Executed, 1);
+                let row = TrackedMigration {
+                    id: id.index as i32,
+                    full_path: entry.full_path.to_string(),
+                    name: entry.name.to_string(),
+                    hash: entry.hash,
+                    rollback: entry.rollback,
+                    locked: false,
+                    status: entry.status.as_str().to_string(),
+                    description: None,
+                    created_at: chrono::Utc::now(),
+                    updated_at: chrono::Utc::now(),
+                    rolled_back_at: None,
+                    tags: sqlx::types::JsonValue::Null,
+                    dependencies: sqlx::types::JsonValue::Null,
+                    attempts: entry.attempts,
+                    last_error: None,
+                    nuclear: entry.nuclear,
+                    content: Some(entry.content),
+                    kind: entry.kind.as_str().to_lowercase(),
+                    attempt_log: sqlx::types::JsonValue::Null,
+                };
+                (row.full_path.clone(), row)
+            })
+            .collect()
+    }
+
+    fn pending(
+        project: &MigrationProject,
+        plan: &ExecutionPlan,
+        tracked: &HashMap<String, TrackedMigration>,
+    ) -> Vec<String> {
+        Executor::pending_in(project, plan, None, tracked)
+            .unwrap()
+            .into_iter()
+            .map(|id| project.path_of(id).to_string())
+            .collect()
+    }
+
+    #[test]
+    fn every_function_not_created_yet_is_pending() {
+        let (project, plan) = planned(FILE);
+        assert_eq!(
+            pending(&project, &plan, &HashMap::new()),
+            [
+                "a.sql::Function(f)",
+                "a.sql::Function(unused)",
+                "a.sql::Migration(m)"
+            ]
+        );
+    }
+
+    #[test]
+    fn changed_functions_are_created_again_without_a_pending_caller() {
+        let (before, _) = planned(FILE);
+        let ids = [
+            before.get("a.sql::Function(f)").unwrap(),
+            before.get("a.sql::Function(unused)").unwrap(),
+            before.get("a.sql::Migration(m)").unwrap(),
+        ];
+        let tracked = tracked(&before, &ids);
+
+        let (project, plan) = planned(FILE);
+        assert!(pending(&project, &plan, &tracked).is_empty());
+
+        let (project, plan) = planned(&FILE.replace("SELECT 1;", "SELECT 10;"));
+        assert_eq!(pending(&project, &plan, &tracked), ["a.sql::Function(f)"]);
+
+        let drifts = Executor::function_drift(&project, &tracked).unwrap();
+        assert_eq!(drifts.len(), 1);
+        assert_eq!(drifts[0].full_path, "a.sql::Function(f)");
+        let mut changes = String::new();
+        Executor::describe_changes(&mut changes, &drifts[0]);
+        assert!(changes.contains("- SELECT 1;") && changes.contains("+ SELECT 10;"));
+    }
+
+    #[test]
+    fn function_rows_are_not_migration_drift() {
+        let (before, _) = planned(FILE);
+        let tracked = tracked(&before, &[before.get("a.sql::Function(f)").unwrap()]);
+        let (project, _) = planned(&FILE.replace("SELECT 1;", "SELECT 10;"));
+        assert!(Executor::drift(&project, &tracked).unwrap().is_empty());
+        assert!(Executor::check_drift(&project, &tracked).is_ok());
+        assert!(tracked["a.sql::Function(f)"].is_function());
+    }
+
+    #[test]
+    fn savepoints_are_named_after_their_migration() {
+        let name = "a".repeat(80);
+        let (project, _) = planned(&format!(
+            "-- +migration: Short\nSELECT 1;\n-- +endmigration\n\
+             -- +migration: {name}\nSELECT 1;\n-- +endmigration\n"
+        ));
+        let short = project.get("a.sql::Migration(Short)").unwrap();
+        assert_eq!(savepoint_name(&project, short), "\"fsql_0_short\"");
+        let long = project.get(&format!("a.sql::Migration({name})")).unwrap();
+        assert_eq!(
+            savepoint_name(&project, long),
+            format!("\"fsql_1_{}\"", "a".repeat(56))
+        );
+    }
+
+    #[tokio::test]
+    async fn groups_apply_with_savepoints_of_any_name() {
+        let Some(executor) = executor("savepoints").await else {
+            return;
+        };
+        let name = "é".repeat(35);
+        let (project, plan) = planned(&format!(
+            "-- +group: g\n-- +transaction\n\
+             -- +migration: {name}\nCREATE TABLE t ();\n-- +endmigration\n\
+             -- +migration: with \"quotes\"\nDROP TABLE t;\n-- +endmigration\n\
+             -- +endgroup\n"
+        ));
+        let lock = executor.lock_migrator().await.unwrap();
+        let report = executor.up(&lock, &project, &plan, None).await.unwrap();
+        assert_eq!(report.applied.len(), 2);
+    }
+
+    #[test]
+    fn savepoint_names_are_cut_between_characters() {
+        // 70 bytes, the 63rd falls in the middle of an 'é'
+        let name = "é".repeat(35);
+        let (project, _) = planned(&format!(
+            "-- +migration: {name}\nSELECT 1;\n-- +endmigration\n"
+        ));
+        let id = project.get(&format!("a.sql::Migration({name})")).unwrap();
+        let savepoint = savepoint_name(&project, id);
+        assert_eq!(savepoint, format!("\"fsql_0_{}\"", "é".repeat(28)));
+        assert!(savepoint.trim_matches('"').len() <= MAX_IDENTIFIER_LENGTH);
+    }
+
+    const CHAIN: &str = "-- +migration: a\nCREATE TABLE a ();\n-- +rollback\nDROP TABLE a;\n-- +endmigration\n\
+                         -- +migration: b\n-- +depends: Migration(a)\nCREATE TABLE b ();\n-- +rollback\nDROP TABLE b;\n-- +endmigration\n\
+                         -- +migration: c\nCREATE TABLE c ();\n-- +endmigration\n";
+
+    fn ids(project: &MigrationProject, paths: &[&str]) -> Vec<NodeId> {
+        paths
+            .iter()
+            .map(|path| project.get(path).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn rolling_back_the_last_migrations_takes_the_newest_first() {
+        let (project, _) = planned(CHAIN);
+        let mut tracked = tracked(
+            &project,
+            &ids(&project, &["a.sql::Migration(a)", "a.sql::Migration(b)"]),
+        );
+        let now = chrono::Utc::now();
+        tracked.get_mut("a.sql::Migration(a)").unwrap().created_at = now;
+        tracked.get_mut("a.sql::Migration(b)").unwrap().created_at =
+            now - Duration::from_secs(3600);
+
+        let selected =
+            Executor::rollback_selection(&project, &tracked, &RollbackTarget::Last(1)).unwrap();
+        assert_eq!(selected, ids(&project, &["a.sql::Migration(a)"]));
+    }
+
+    #[test]
+    fn rollbacks_need_a_rollback_section_and_no_applied_dependents() {
+        let (project, plan) = planned(CHAIN);
+        let (a, b, c) = (
+            project.get("a.sql::Migration(a)").unwrap(),
+            project.get("a.sql::Migration(b)").unwrap(),
+            project.get("a.sql::Migration(c)").unwrap(),
+        );
+        let tracked = tracked(&project, &[a, b, c]);
+
+        let error = Executor::check_rollback(&project, &plan, &tracked, &[c], false).unwrap_err();
+        assert!(matches!(error.kind, ExecutionErrorKind::MissingRollback(_)));
+
+        let error = Executor::check_rollback(&project, &plan, &tracked, &[a], false).unwrap_err();
+        assert!(matches!(
+            error.kind,
+            ExecutionErrorKind::DependentStillApplied(ref dependency, ref dependent)
+                if dependency == "a.sql::Migration(a)" && dependent == "a.sql::Migration(b)"
+        ));
+        assert!(Executor::check_rollback(&project, &plan, &tracked, &[a], true).is_ok());
+        assert!(Executor::check_rollback(&project, &plan, &tracked, &[a, b], false).is_ok());
+    }
+
+    #[test]
+    fn retry_delays_grow_up_to_the_maximum() {
+        let policy = RetryPolicy {
+            initial_delay: Duration::from_millis(100),
+            multiplier: 3.0,
+            max_delay: Duration::from_secs(1),
+        };
+        let delays: Vec<u128> = (1..=4)
+            .map(|retry| policy.delay(retry).as_millis())
+            .collect();
+        assert_eq!(delays, [100, 300, 900, 1000]);
+
+        let shrinking = RetryPolicy {
+            multiplier: 0.5,
+            ..policy
+        };
+        assert_eq!(shrinking.delay(3), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn only_connection_and_pool_errors_are_transient_outside_the_database() {
+        assert!(is_transient(&sqlx::Error::PoolTimedOut));
+        assert!(is_transient(&sqlx::Error::Io(std::io::Error::from(
+            std::io::ErrorKind::ConnectionReset
+        ))));
+        assert!(!is_transient(&sqlx::Error::RowNotFound));
+    }
+
+    #[tokio::test]
+    async fn every_failed_attempt_is_logged_with_its_sqlstate() {
+        let Some(executor) = executor("attempt_log").await else {
+            return;
+        };
+        let (project, plan) = planned(
+            "-- +migration: busy\n-- +retries: 2\n\
+             DO $$ BEGIN RAISE EXCEPTION 'busy' USING ERRCODE = '40001'; END $$;\n\
+             -- +endmigration\n",
+        );
+        let lock = executor.lock_migrator().await.unwrap();
+        let error = executor.up(&lock, &project, &plan, None).await.unwrap_err();
+        assert!(matches!(error.kind, ExecutionErrorKind::MigrationFailed(_)));
+
+        let row = &executor.tracked().await.unwrap()["a.sql::Migration(busy)"];
+        assert_eq!(row.attempts, 3);
+        let log = row.attempt_log.as_array().unwrap();
+        let attempts: Vec<i64> = log.iter().map(|e| e["attempt"].as_i64().unwrap()).collect();
+        assert_eq!(attempts, [1, 2, 3]);
+        assert!(log.iter().all(|entry| entry["sqlstate"] == "40001"
+            && entry["error"].as_str().unwrap().contains("busy")
+            && entry["at"].is_string()));
+    }
+
+    #[test]
+    fn named_concurrent_indexes_are_recognized() {
+        assert_eq!(
+            concurrent_index(
+                "-- +tags\ncreate unique index concurrently if not exists Idx ON s.t (v)"
+            ),
+            Some(("s.t".to_string(), "idx".to_string()))
+        );
+        assert_eq!(
+            concurrent_index("CREATE INDEX CONCURRENTLY \"My \"\"Idx\"\"\" ON ONLY \"T\" (v)"),
+            Some(("\"T\"".to_string(), "My \"Idx\"".to_string()))
+        );
+        assert_eq!(concurrent_index("CREATE INDEX CONCURRENTLY ON t (v)"), None);
+        assert_eq!(concurrent_index("CREATE INDEX idx ON t (v)"), None);
+    }
+
+    const DUPLICATES: &str = "-- +migration: table\n\
+                              CREATE TABLE t (v int);\nINSERT INTO t VALUES (1), (1);\n\
+                              -- +endmigration\n\
+                              -- +migration: index\n-- +tags: concurrent\n-- +depends: Migration(table)\n\
+                              CREATE UNIQUE INDEX CONCURRENTLY t_v ON t (v);\n\
+                              -- +rollback\nDROP INDEX CONCURRENTLY t_v;\n-- +endmigration\n";
+
+    #[tokio::test]
+    async fn invalid_indexes_of_failed_concurrent_migrations_are_created_again() {
+        let Some(executor) = executor("invalid_index").await else {
+            return;
+        };
+        let (project, plan) = planned(DUPLICATES);
+        let lock = executor.lock_migrator().await.unwrap();
+        assert!(executor.up(&lock, &project, &plan, None).await.is_err());
+        let row = &executor.tracked().await.unwrap()["a.sql::Migration(index)"];
+        assert_eq!(row.status().unwrap(), MigrationStatus::Pending);
+
+        sqlx::raw_sql("DELETE FROM t WHERE ctid <> (SELECT min(ctid) FROM t)")
+            .execute(executor.pool())
+            .await
+            .unwrap();
+        let report = executor.up(&lock, &project, &plan, None).await.unwrap();
+        assert_eq!(report.applied, ["a.sql::Migration(index)"]);
+        let valid: bool = sqlx::query_scalar(
+            "SELECT indisvalid FROM pg_index WHERE indexrelid = 't_v'::regclass",
+        )
+        .fetch_one(executor.pool())
+        .await
+        .unwrap();
+        assert!(valid);
+    }
+
+    #[tokio::test]
+    async fn concurrent_migrations_roll_back_outside_of_a_transaction() {
+        let Some(executor) = executor("concurrent_rollback").await else {
+            return;
+        };
+        let (project, plan) = planned(&DUPLICATES.replace("(1), (1)", "(1), (2)"));
+        let lock = executor.lock_migrator().await.unwrap();
+        executor.up(&lock, &project, &plan, None).await.unwrap();
+
+        let target = RollbackTarget::Migration("index".to_string());
+        let report = executor
+            .down(&lock, &project, &plan, &target, false)
+            .await
+            .unwrap();
+        assert_eq!(report.rolled_back, ["a.sql::Migration(index)"]);
+        let index: Option<String> = sqlx::query_scalar("SELECT to_regclass('t_v')::text")
+            .fetch_one(executor.pool())
+            .await
+            .unwrap();
+        assert_eq!(index, None);
+        let row = &executor.tracked().await.unwrap()["a.sql::Migration(index)"];
+        assert_eq!(row.status().unwrap(), MigrationStatus::RolledBack);
+    }
+
+    #[test]
+    fn migrations_inherit_the_retries_of_their_innermost_group() {
+        let (project, _) = planned(
+            "-- +group: outer\n-- +retries: 1\n\
+             -- +group: inner\n-- +retries: 2\n\
+             -- +migration: inherits\nSELECT 1;\n-- +endmigration\n\
+             -- +migration: own\n-- +retries: 5\nSELECT 1;\n-- +endmigration\n\
+             -- +endgroup\n-- +endgroup\n\
+             -- +migration: none\nSELECT 1;\n-- +endmigration\n",
+        );
+        let retries = |path| project.retries_of(project.get(path).unwrap());
+        assert_eq!(retries("a.sql::outer::inner::Migration(inherits)"), 2);
+        assert_eq!(retries("a.sql::outer::inner::Migration(own)"), 5);
+        assert_eq!(retries("a.sql::Migration(none)"), 0);
+    }
+
+    /// The steps `up` takes on a fresh database, one line per step.
+    fn schedule(content: &str) -> Result<Vec<String>, ExecutionError> {
+        let (project, plan) = planned(content);
+        let pending = Executor::pending_in(&project, &plan, None, &HashMap::new())?;
+        let steps = Executor::schedule(&project, &plan, &pending)?;
+        Ok(steps
+            .iter()
+            .map(|step| {
+                let nodes: Vec<&str> = step.nodes().iter().map(|id| project.path_of(*id)).collect();
+                let kind = match step {
+                    Step::Migration(_) => "migration",
+                    Step::Nuclear(_) => "nuclear",
+                    Step::Function(_) => "function",
+                    Step::Group(..) => "group",
+                    Step::Concurrent(_) => "concurrent",
+                };
+                format!("{kind} {}", nodes.join(", "))
+            })
+            .collect())
+    }
+
+    #[test]
+    fn transactional_groups_run_their_members_and_new_functions_in_one_step() {
+        let steps = schedule(
+            "-- +group: g\n-- +transaction\n\
+             -- +migration: a\n-- +call-func: f\nSELECT f();\n-- +endmigration\n\
+             -- +migration: b\nSELECT 1;\n-- +endmigration\n\
+             -- +function: f\n-- +language: sql\n-- +returns: int\nSELECT 1;\n-- +endfunction\n\
+             -- +endgroup\n",
+        )
+        .unwrap();
+        assert_eq!(
+            steps,
+            ["group a.sql::g::Migration(b), a.sql::g::Function(f), a.sql::g::Migration(a)"]
+        );
+    }
+
+    #[test]
+    fn transactional_groups_refuse_what_cannot_share_their_transaction() {
+        let error = schedule(
+            "-- +group: g\n-- +tags: transactional\n\
+             -- +migration: a\n-- +tags: concurrent\nSELECT 1;\n-- +endmigration\n\
+             -- +endgroup\n",
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error.kind,
+            ExecutionErrorKind::ConcurrentInTransaction(_)
+        ));
+
+        let error = schedule(
+            "-- +group: g\n-- +transaction\n\
+             -- +migration: a\nSELECT 1;\n-- +endmigration\n\
+             -- +migration: c\n-- +depends: ::a.sql::Migration(x)\nSELECT 1;\n-- +endmigration\n\
+             -- +endgroup\n\
+             -- +migration: x\n-- +depends: g::Migration(a)\nSELECT 1;\n-- +endmigration\n",
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error.kind,
+            ExecutionErrorKind::TransactionOrder(ref group, ref between)
+                if group == "a.sql::Group(g)" && between == "a.sql::Migration(x)"
+        ));
+    }
+
+    #[test]
+    fn independent_concurrent_migrations_share_a_wave() {
+        let steps = schedule(
+            "-- +migration: a\n-- +tags: concurrent\nSELECT 1;\n-- +endmigration\n\
+             -- +migration: b\n-- +tags: concurrent\nSELECT 1;\n-- +endmigration\n\
+             -- +migration: c\n-- +tags: concurrent\n-- +depends: Migration(a)\nSELECT 1;\n-- +endmigration\n\
+             -- +migration: d\nSELECT 1;\n-- +endmigration\n",
+        )
+        .unwrap();
+        assert_eq!(
+            steps,
+            [
+                "concurrent a.sql::Migration(a), a.sql::Migration(b)",
+                "concurrent a.sql::Migration(c)",
+                "migration a.sql::Migration(d)"
+            ]
+        );
+    }
+
+    #[test]
+    fn nuclear_migrations_run_first_with_the_functions_they_call() {
+        let steps = schedule(
+            "-- +migration: a\nSELECT 1;\n-- +endmigration\n\
+             -- +function: f\n-- +language: sql\n-- +returns: int\nSELECT 1;\n-- +endfunction\n\
+             -- +group: g\n-- +transaction\n\
+             -- +migration: b\nSELECT 1;\n-- +endmigration\n\
+             -- +migration: fix\n-- +nuclear\n-- +depends: ::a.sql::Migration(a)\n-- +call-func: ::a.sql::f\n\
+             SELECT f();\n-- +endmigration\n\
+             -- +endgroup\n",
+        )
+        .unwrap();
+        assert_eq!(
+            steps,
+            [
+                "function a.sql::Function(f)",
+                "nuclear a.sql::g::Migration(fix)",
+                "migration a.sql::Migration(a)",
+                "group a.sql::g::Migration(b)"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn nuclear_runs_are_audited_with_what_they_bypassed() {
+        let Some(mut executor) = executor("nuclear_audit").await else {
+            return;
+        };
+        executor.set_allow_nuclear(true);
+        let (project, plan) = planned(
+            "-- +migration: a\nSELECT 1;\n-- +endmigration\n\
+             -- +migration: fix\n-- +nuclear\n-- +depends: Migration(a)\nSELECT 1;\n-- +endmigration\n",
+        );
+        let lock = executor.lock_migrator().await.unwrap();
+        executor.up(&lock, &project, &plan, None).await.unwrap();
+
+        let sql = format!(
+            "SELECT event, full_path, hash, bypassed::text, db_user = current_user FROM {}",
+            executor.table().audit_name()
+        );
+        let rows: Vec<(String, String, String, String, bool)> = sqlx::query_as(&sql)
+            .fetch_all(executor.pool())
+            .await
+            .unwrap();
+        let fix = project.get("a.sql::Migration(fix)").unwrap();
+        assert_eq!(
+            rows,
+            [(
+                "nuclear".to_string(),
+                "a.sql::Migration(fix)".to_string(),
+                project.migration_hash(fix),
+                r#"["a.sql::Migration(a)"]"#.to_string(),
+                true
+            )]
+        );
+    }
+
+    #[test]
+    fn locked_migrations_cannot_change_or_be_rolled_back() {
+        let (before, plan) = planned(CHAIN);
+        let b = before.get("a.sql::Migration(b)").unwrap();
+        let mut tracked = tracked(&before, &[b]);
+        let row = tracked.get_mut("a.sql::Migration(b)").unwrap();
+        row.locked = true;
+
+        let error = Executor::check_rollback(&before, &plan, &tracked, &[b], true).unwrap_err();
+        assert!(matches!(error.kind, ExecutionErrorKind::Locked(_)));
+
+        // Rolled back locked rows are still held to their content
+        let row = tracked.get_mut("a.sql::Migration(b)").unwrap();
+        row.status = MigrationStatus::RolledBack.as_str().to_string();
+        let (edited, _) = planned(&CHAIN.replace("CREATE TABLE b ()", "CREATE TABLE b (id INT)"));
+        let error = Executor::check_drift(&edited, &tracked).unwrap_err();
+        assert!(matches!(
+            error.kind,
+            ExecutionErrorKind::LockedModified(ref paths) if paths == &["a.sql::Migration(b)"]
+        ));
+        assert!(
+            error
+                .message
+                .contains("      - CREATE TABLE b ();\n      + CREATE TABLE b (id INT);")
+        );
+    }
+}
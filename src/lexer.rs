@@ -0,0 +1,377 @@
+use std::ops::Range;
+
+/// What a token of SQL is, as far as FSQL cares. Everything that is not a
+/// literal, a comment or a `;` is `Code`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Code,
+    /// `'...'`, or `E'...'` with backslash escapes
+    String,
+    /// `$$...$$` or `$tag$...$tag$`
+    DollarString,
+    /// `"..."`
+    QuotedIdentifier,
+    /// `-- ...`, up to the end of the line
+    LineComment,
+    /// `/* ... */`, which may nest
+    BlockComment,
+    Semicolon,
+}
+
+impl TokenKind {
+    /// Whether the token can span lines, hiding whatever looks like a
+    /// directive inside of it.
+    pub fn is_opaque(&self) -> bool {
+        matches!(
+            self,
+            TokenKind::String
+                | TokenKind::DollarString
+                | TokenKind::QuotedIdentifier
+                | TokenKind::BlockComment
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub range: Range<usize>,
+}
+
+/// Where the lexer is when a text ends, so the next one can pick up inside
+/// a literal left open.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+enum State {
+    #[default]
+    Code,
+    String {
+        escapes: bool,
+    },
+    QuotedIdentifier,
+    /// Holds the tag, `$` included on both sides
+    DollarString(String),
+    /// Holds the nesting depth
+    BlockComment(usize),
+}
+
+impl State {
+    fn kind(&self) -> Option<TokenKind> {
+        match self {
+            State::Code => None,
+            State::String { .. } => Some(TokenKind::String),
+            State::QuotedIdentifier => Some(TokenKind::QuotedIdentifier),
+            State::DollarString(_) => Some(TokenKind::DollarString),
+            State::BlockComment(_) => Some(TokenKind::BlockComment),
+        }
+    }
+}
+
+/// Splits SQL in tokens following the lexical rules of Postgres, one piece
+/// of text at a time. A literal or comment that is not closed by the end of
+/// a piece continues in the next one.
+#[derive(Debug, Clone, Default)]
+pub struct Lexer {
+    state: State,
+}
+
+impl Lexer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the next piece of text starts inside a literal or block
+    /// comment.
+    pub fn in_literal(&self) -> bool {
+        self.state != State::Code
+    }
+
+    /// Tokens of `text`, with ranges relative to it.
+    pub fn scan(&mut self, text: &str) -> Vec<Token> {
+        let bytes = text.as_bytes();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+
+        if let Some(kind) = self.state.kind() {
+            i = self.literal_end(text, 0);
+            tokens.push(Token { kind, range: 0..i });
+        }
+
+        let mut code_start = i;
+        while i < bytes.len() {
+            let token = match bytes[i] {
+                b'\'' => {
+                    // E'...' takes backslash escapes, unless the E ends a word
+                    let escapes = i > 0
+                        && matches!(bytes[i - 1], b'E' | b'e')
+                        && !(i > 1 && is_identifier_byte(bytes[i - 2]));
+                    self.state = State::String { escapes };
+                    Some((TokenKind::String, self.literal_end(text, i + 1)))
+                }
+                b'"' => {
+                    self.state = State::QuotedIdentifier;
+                    Some((TokenKind::QuotedIdentifier, self.literal_end(text, i + 1)))
+                }
+                b'-' if bytes.get(i + 1) == Some(&b'-') => {
+                    let end = bytes[i..]
+                        .iter()
+                        .position(|byte| *byte == b'\n')
+                        .map_or(bytes.len(), |position| i + position);
+                    Some((TokenKind::LineComment, end))
+                }
+                b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                    self.state = State::BlockComment(1);
+                    Some((TokenKind::BlockComment, self.literal_end(text, i + 2)))
+                }
+                // `$` inside a word is part of an identifier, not a quote
+                b'$' if !(i > 0 && is_identifier_byte(bytes[i - 1])) => {
+                    dollar_tag(text, i).map(|tag| {
+                        let content = i + tag.len();
+                        self.state = State::DollarString(tag.to_string());
+                        (TokenKind::DollarString, self.literal_end(text, content))
+                    })
+                }
+                b';' => Some((TokenKind::Semicolon, i + 1)),
+                _ => None,
+            };
+
+            match token {
+                Some((kind, end)) => {
+                    if code_start < i {
+                        tokens.push(Token {
+                            kind: TokenKind::Code,
+                            range: code_start..i,
+                        });
+                    }
+                    tokens.push(Token {
+                        kind,
+                        range: i..end,
+                    });
+                    i = end;
+                    code_start = end;
+                }
+                None => i += 1,
+            }
+        }
+        if code_start < bytes.len() {
+            tokens.push(Token {
+                kind: TokenKind::Code,
+                range: code_start..bytes.len(),
+            });
+        }
+        tokens
+    }
+
+    /// End of the literal the lexer is in, reading `text` from `from`. The
+    /// state goes back to code when the literal closes, otherwise the whole
+    /// rest of the text belongs to it.
+    fn literal_end(&mut self, text: &str, from: usize) -> usize {
+        let bytes = text.as_bytes();
+        let end = match &mut self.state {
+            State::Code => return from,
+            State::String { escapes } => quoted_end(bytes, from, b'\'', *escapes),
+            State::QuotedIdentifier => quoted_end(bytes, from, b'"', false),
+            State::DollarString(tag) => text[from..]
+                .find(tag.as_str())
+                .map(|close| from + close + tag.len()),
+            State::BlockComment(depth) => block_comment_end(bytes, from, depth),
+        };
+        match end {
+            Some(end) => {
+                self.state = State::Code;
+                end
+            }
+            None => bytes.len(),
+        }
+    }
+}
+
+/// Splits `sql` in tokens. A literal or comment that is never closed runs to
+/// the end of the input.
+pub fn tokenize(sql: &str) -> Vec<Token> {
+    Lexer::new().scan(sql)
+}
+
+fn is_identifier_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'$' || !byte.is_ascii()
+}
+
+/// End of a literal quoted by `quote`, whose content starts at `from`. The
+/// quote is escaped by doubling it, or with a backslash when `escapes` is set.
+fn quoted_end(bytes: &[u8], from: usize, quote: u8, escapes: bool) -> Option<usize> {
+    let mut i = from;
+    while i < bytes.len() {
+        if escapes && bytes[i] == b'\\' {
+            i += 2;
+        } else if bytes[i] == quote {
+            if bytes.get(i + 1) != Some(&quote) {
+                return Some(i + 1);
+            }
+            i += 2;
+        } else {
+            i += 1;
+        }
+    }
+    None
+}
+
+/// End of a block comment `depth` levels deep at `from`, updating `depth`.
+fn block_comment_end(bytes: &[u8], from: usize, depth: &mut usize) -> Option<usize> {
+    let mut i = from;
+    while i < bytes.len() {
+        if bytes[i] == b'/' && bytes.get(i + 1) == Some(&b'*') {
+            *depth += 1;
+            i += 2;
+        } else if bytes[i] == b'*' && bytes.get(i + 1) == Some(&b'/') {
+            *depth -= 1;
+            i += 2;
+            if *depth == 0 {
+                return Some(i);
+            }
+        } else {
+            i += 1;
+        }
+    }
+    None
+}
+
+/// Tag of the dollar quote opening at `start`, like `$$` or `$body$`, or
+/// `None` when the `$` does not open one, like in the `$1` parameter.
+fn dollar_tag(sql: &str, start: usize) -> Option<&str> {
+    let bytes = sql.as_bytes();
+    let tag_end = bytes[start + 1..]
+        .iter()
+        .position(|byte| !(byte.is_ascii_alphanumeric() || *byte == b'_' || !byte.is_ascii()))
+        .map(|position| start + 1 + position)?;
+    if bytes[tag_end] != b'$' || bytes.get(start + 1).is_some_and(u8::is_ascii_digit) {
+        return None;
+    }
+    Some(&sql[start..=tag_end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(sql: &str) -> Vec<(TokenKind, &str)> {
+        tokenize(sql)
+            .into_iter()
+            .map(|token| (token.kind, &sql[token.range]))
+            .collect()
+    }
+
+    #[test]
+    fn splits_code_literals_and_semicolons() {
+        assert_eq!(
+            kinds("select 'a;b', \"c;d\"; -- e;f"),
+            vec![
+                (TokenKind::Code, "select "),
+                (TokenKind::String, "'a;b'"),
+                (TokenKind::Code, ", "),
+                (TokenKind::QuotedIdentifier, "\"c;d\""),
+                (TokenKind::Semicolon, ";"),
+                (TokenKind::Code, " "),
+                (TokenKind::LineComment, "-- e;f"),
+            ]
+        );
+    }
+
+    #[test]
+    fn doubled_quotes_do_not_close_a_string() {
+        assert_eq!(
+            kinds("'it''s'x"),
+            vec![(TokenKind::String, "'it''s'"), (TokenKind::Code, "x")]
+        );
+    }
+
+    #[test]
+    fn backslashes_escape_only_in_e_strings() {
+        assert_eq!(kinds(r"E'a\'b'")[1], (TokenKind::String, r"'a\'b'"));
+        assert_eq!(kinds(r"'a\'b'")[0], (TokenKind::String, r"'a\'"));
+        // The E of `type` is not an E-string prefix
+        assert_eq!(kinds(r"type'a\'b'")[1], (TokenKind::String, r"'a\'"));
+    }
+
+    #[test]
+    fn dollar_quotes_need_a_matching_tag() {
+        assert_eq!(
+            kinds("$a$ $$ ; $a$;"),
+            vec![
+                (TokenKind::DollarString, "$a$ $$ ; $a$"),
+                (TokenKind::Semicolon, ";"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parameters_and_identifiers_are_not_dollar_quotes() {
+        assert!(
+            kinds("select $1, a$b$ from t")
+                .iter()
+                .all(|(kind, _)| *kind == TokenKind::Code)
+        );
+    }
+
+    #[test]
+    fn block_comments_nest() {
+        assert_eq!(
+            kinds("/* a /* b */ c */;"),
+            vec![
+                (TokenKind::BlockComment, "/* a /* b */ c */"),
+                (TokenKind::Semicolon, ";"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unclosed_literals_run_to_the_end() {
+        assert_eq!(
+            kinds("x 'abc"),
+            vec![(TokenKind::Code, "x "), (TokenKind::String, "'abc")]
+        );
+        assert_eq!(
+            kinds("/* a /* b */"),
+            vec![(TokenKind::BlockComment, "/* a /* b */")]
+        );
+    }
+
+    #[test]
+    fn literals_continue_across_scans() {
+        let mut lexer = Lexer::new();
+        let first = lexer.scan("select $body$\n");
+        assert_eq!(
+            first.last().map(|token| token.kind),
+            Some(TokenKind::DollarString)
+        );
+        assert!(lexer.in_literal());
+
+        let second = lexer.scan("-- +endmigration\n");
+        assert_eq!(
+            second,
+            vec![Token {
+                kind: TokenKind::DollarString,
+                range: 0..17
+            }]
+        );
+        assert!(lexer.in_literal());
+
+        let third = lexer.scan("$body$; /* a\n");
+        assert_eq!(
+            third[0],
+            Token {
+                kind: TokenKind::DollarString,
+                range: 0..6
+            }
+        );
+        assert!(lexer.in_literal());
+
+        let fourth = lexer.scan("/* b */ */ 'x'\n");
+        assert_eq!(
+            fourth[0],
+            Token {
+                kind: TokenKind::BlockComment,
+                range: 0..10
+            }
+        );
+        assert!(!lexer.in_literal());
+    }
+}
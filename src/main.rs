@@ -1,55 +1,32 @@
+pub mod advisory_lock;
+pub mod cli;
+pub mod diff;
+pub mod directives;
+pub mod execution_errors;
+pub mod execution_plan;
+pub mod executor;
+pub mod lexer;
 pub mod migration_parser;
 pub mod models;
 pub mod parse_errors;
+pub mod plan_renderer;
+pub mod tracking;
 
-#[tokio::main]
-async fn main() {
-    if let Err(e) = dotenvy::dotenv() {
-        eprintln!("Failed to load .env file: {e}");
-        return;
-    }
-
-    let mut file = models::file::MigrationFile::new(
-        "path/to/file.sql",
-        "file.sql",
-        r#"
-            -- +fsql:version: 0.5
+#[cfg(test)]
+mod test_fixtures;
 
-            -- +migration: smth
-            -- +version: 1.0.0
-            --+ This is a migration description
-            -- +depends: ::path/to/something.sql::Migration(my_migration)
-            -- +tags: concurrent
-            -- +nuclear
-                CREATE TABLE IF NOT EXISTS my_table (
-                    id SERIAL PRIMARY KEY,
-                    name VARCHAR(255) NOT NULL,
-                    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-                    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP
-                );
-            -- +rollback
-                DROP TABLE IF EXISTS my_table;
-            -- +endmigration
-            
-            -- +macro: my_macro
-            -- +parameters: table_name, type_of_id, ...columns
-            -- +depends: ::path/to/something.sql::Migration(my_migration)
-                CREATE TABLE IF NOT EXISTS {{table_name}} (
-                    id {{type_of_id}} PRIMARY KEY,
-                    {{...columns}},
-                    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-                    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP
-                );
-            -- +endmacro
+use std::process::ExitCode;
 
-            -- +migration: another_migration
-            -- +depends: ::path/to/something.sql::Migration(my_migration)
-                -- +call: ::/path/to/::my_macro('another_table', 'UUID', 'name VARCHAR(255) NOT NULL', 'age INT')
-            -- +endmigration
-        "#,
-    );
+use clap::Parser;
 
-    file.parse_file().unwrap();
+#[tokio::main]
+async fn main() -> ExitCode {
+    if let Err(e) = dotenvy::dotenv()
+        && !e.not_found()
+    {
+        eprintln!("Failed to load .env file: {e}");
+        return ExitCode::FAILURE;
+    }
 
-    println!("Parsed migrations:\n{:#?}", file);
+    cli::run(cli::Cli::parse()).await
 }
@@ -1,3 +1,299 @@
-pub struct MigrationParser {}
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
 
-impl MigrationParser {}
+use crate::{
+    models::{file::MigrationFile, function::KNOWN_LANGUAGES, project::MigrationProject},
+    parse_errors::{ParseError, ParseErrorKind, ParseReport, ParseWarning},
+};
+
+const FSQL_EXTENSION: &str = "sql";
+
+/// Loads every FSQL file below a migrations root into a single project.
+pub struct MigrationParser {
+    root: PathBuf,
+    languages: Vec<String>,
+    strict: bool,
+}
+
+impl MigrationParser {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            languages: Vec::new(),
+            strict: false,
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Allows functions in `language` on top of `KNOWN_LANGUAGES`.
+    pub fn add_language(&mut self, language: impl Into<String>) {
+        self.languages.push(language.into());
+    }
+
+    /// Makes unknown `-- +` directives an error instead of a warning.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    fn check_languages(&self, project: &MigrationProject) -> Result<(), ParseError> {
+        for function in project.functions() {
+            if let Err(kind) = function.check_language(&self.languages) {
+                let mut known: Vec<&str> = KNOWN_LANGUAGES.to_vec();
+                known.extend(self.languages.iter().map(String::as_str));
+                let span = function.language_span().unwrap_or(function.span()).clone();
+                let message = format!(
+                    "unknown language '{}' for function '{}', expected one of: {}",
+                    function.language(),
+                    function.name(),
+                    known.join(", ")
+                );
+                return Err(project.error_in(function.path(), kind, span, message));
+            }
+        }
+        Ok(())
+    }
+
+    fn file_read_error(path: &Path, error: std::io::Error) -> ParseError {
+        ParseError {
+            kind: ParseErrorKind::FileRead(path.display().to_string()),
+            line: 0,
+            column: 0,
+            span: None,
+            message: format!("Failed to read '{}': {}", path.display(), error),
+        }
+    }
+
+    /// Walks `dir` depth first in path order. Symlinked directories are
+    /// followed, but each directory is only walked once so loops end.
+    fn collect_sql_files(
+        dir: &Path,
+        visited: &mut HashSet<PathBuf>,
+        files: &mut Vec<PathBuf>,
+    ) -> Result<(), ParseError> {
+        let canonical = dir
+            .canonicalize()
+            .map_err(|e| Self::file_read_error(dir, e))?;
+        if !visited.insert(canonical) {
+            return Ok(());
+        }
+
+        let entries = std::fs::read_dir(dir).map_err(|e| Self::file_read_error(dir, e))?;
+
+        let mut paths = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|e| Self::file_read_error(dir, e))?;
+            paths.push(entry.path());
+        }
+        // read_dir order is platform dependent, sorting keeps the project deterministic
+        paths.sort();
+
+        for path in paths {
+            if path.is_dir() {
+                Self::collect_sql_files(&path, visited, files)?;
+            } else if path
+                .extension()
+                .is_some_and(|extension| extension == FSQL_EXTENSION)
+            {
+                files.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    /// Path of `path` relative to the root, always using `/` as separator.
+    fn relative_path(&self, path: &Path) -> String {
+        path.strip_prefix(&self.root)
+            .unwrap_or(path)
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Reads every `.sql` file below the root without parsing it.
+    pub fn load_files(&self) -> Result<Vec<MigrationFile>, ParseError> {
+        let mut paths = Vec::new();
+        Self::collect_sql_files(&self.root, &mut HashSet::new(), &mut paths)?;
+
+        let mut files = Vec::with_capacity(paths.len());
+        for path in paths {
+            let content =
+                std::fs::read_to_string(&path).map_err(|e| Self::file_read_error(&path, e))?;
+            let file_name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let mut file = MigrationFile::new(self.relative_path(&path), file_name, content);
+            file.strict = self.strict;
+            files.push(file);
+        }
+        Ok(files)
+    }
+
+    /// Parses every file into a project, stopping at the first error.
+    /// Returns the warnings of every file along with the project.
+    pub fn parse_project(&self) -> Result<(MigrationProject, Vec<ParseWarning>), ParseError> {
+        let mut project = MigrationProject::new(self.root.display().to_string());
+        let mut warnings = Vec::new();
+
+        for mut file in self.load_files()? {
+            warnings.extend(file.parse_file()?);
+            project.add_file(file)?;
+        }
+
+        self.check_languages(&project)?;
+        project.resolve_dependencies()?;
+        project.expand_macros()?;
+        Ok((project, warnings))
+    }
+
+    /// Like `parse_project`, but reports every error of every file instead of
+    /// stopping at the first one. Whatever parsed is still added to the
+    /// project and checked across files, so errors there are reported too.
+    /// The project is only missing when the files could not be read.
+    pub fn parse_project_recovering(&self) -> (Option<MigrationProject>, ParseReport) {
+        let mut project = MigrationProject::new(self.root.display().to_string());
+        let mut report = ParseReport::default();
+
+        let files = match self.load_files() {
+            Ok(files) => files,
+            Err(e) => {
+                report.errors.push(e);
+                return (None, report);
+            }
+        };
+        for mut file in files {
+            report.extend(file.parse_file_recovering());
+            if let Err(e) = project.add_file(file) {
+                report.errors.push(e);
+            }
+        }
+
+        if let Err(e) = self.check_languages(&project) {
+            report.errors.push(e);
+        }
+        let unresolved = project.resolve_dependencies_recovering();
+        if unresolved.is_empty() {
+            // Expanding resolves the calls again, only worth it when they all resolved
+            if let Err(e) = project.expand_macros() {
+                report.errors.push(e);
+            }
+        }
+        report.errors.extend(unresolved);
+        (Some(project), report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::root;
+
+    #[test]
+    fn checks_across_files_run_despite_broken_files() {
+        let root = root(
+            "recovering",
+            &[
+                (
+                    "a.sql",
+                    "-- +migration: broken\n-- +retries: many\nSELECT 1;\n-- +endmigration\n",
+                ),
+                (
+                    "b.sql",
+                    "-- +migration: z\n-- +depends: Migration(nothing)\nSELECT 1;\n-- +endmigration\n",
+                ),
+            ],
+        );
+        let (project, report) = MigrationParser::new(&root).parse_project_recovering();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let kinds: Vec<&ParseErrorKind> = report.errors.iter().map(|error| &error.kind).collect();
+        assert!(matches!(
+            kinds.as_slice(),
+            [
+                ParseErrorKind::InvalidRetries(_),
+                ParseErrorKind::UnresolvedDependency(..)
+            ]
+        ));
+        assert_eq!(project.unwrap().migrations().len(), 1);
+    }
+
+    #[test]
+    fn parse_project_returns_the_warnings_of_every_file() {
+        let root = root(
+            "warnings",
+            &[
+                ("a.sql", "-- +frob\n"),
+                ("nested/b.sql", "-- +migraton: b\n"),
+            ],
+        );
+        let parsed = MigrationParser::new(&root).parse_project();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let (_, warnings) = parsed.unwrap();
+        let files: Vec<&str> = warnings.iter().map(|w| w.file.as_str()).collect();
+        assert_eq!(files, ["a.sql", "nested/b.sql"]);
+    }
+
+    #[test]
+    fn unknown_languages_point_at_the_directive() {
+        let content = "-- +function: f\n-- +language: plperl\nreturn 1;\n-- +endfunction\n";
+        let root = root("languages", &[("a.sql", content)]);
+        let rejected = MigrationParser::new(&root).parse_project();
+        let mut parser = MigrationParser::new(&root);
+        parser.add_language("plperl");
+        let accepted = parser.parse_project();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let error = rejected.unwrap_err();
+        assert!(matches!(error.kind, ParseErrorKind::UnknownLanguage(_)));
+        let span = error.span.as_deref().unwrap();
+        assert_eq!(&content[span.range.clone()], "plperl");
+        assert_eq!(span.file, "a.sql");
+        assert!(accepted.is_ok());
+    }
+
+    #[test]
+    fn files_are_walked_in_path_order_through_nested_directories() {
+        let root = root(
+            "walk",
+            &[
+                ("b.sql", ""),
+                ("a/z.sql", ""),
+                ("a/nested/m.sql", ""),
+                ("a/notes.txt", ""),
+                ("c.sql", ""),
+            ],
+        );
+        let files = MigrationParser::new(&root).load_files();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let paths: Vec<String> = files
+            .unwrap()
+            .iter()
+            .map(|file| file.file_path.clone())
+            .collect();
+        assert_eq!(paths, ["a/nested/m.sql", "a/z.sql", "b.sql", "c.sql"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_loops_are_walked_once() {
+        let root = root("symlinks", &[("a/b.sql", "")]);
+        std::os::unix::fs::symlink(&root, root.join("a/loop")).unwrap();
+        let files = MigrationParser::new(&root).load_files();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let paths: Vec<String> = files
+            .unwrap()
+            .iter()
+            .map(|file| file.file_path.clone())
+            .collect();
+        assert_eq!(paths, ["a/b.sql"]);
+    }
+}
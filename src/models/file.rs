@@ -1,39 +1,119 @@
+use std::ops::Range;
+
 use ts_rs::TS;
 
 use crate::{
+    directives,
     models::{
-        function::Function, macro_func::MacroFunc, migration::Migration,
-        migration_dependency::Dependency, migration_group::MigrationGroup,
+        function::Function,
+        macro_call::MacroCall,
+        macro_func::MacroFunc,
+        migration::Migration,
+        migration_dependency::Dependency,
+        migration_group::MigrationGroup,
+        syntax::{BlockKind, BlockNode, DirectiveNode, FileNode, Node, SqlChunk},
     },
-    parse_errors::{ParseError, ParseErrorKind},
+    parse_errors::{ParseError, ParseErrorKind, ParseReport, ParseWarning},
 };
 
-const REGEX_MIGRATION_START: &str = r"-- \+migration: (\w+)";
-const REGEX_MIGRATION_END: &str = r"-- \+endmigration";
-const REGEX_MACRO_START: &str = r"-- \+macro: (\w+)";
-const REGEX_MACRO_END: &str = r"-- \+endmacro";
-const REGEX_FUNCTION_START: &str = r"-- \+function: (\w+)";
-const REGEX_FUNCTION_END: &str = r"-- \+endfunction";
-const REGEX_FSQL_VERSION: &str = r"-- \+fsql:version: (\d+\.\d+)";
-const REGEX_MIGRATION_GROUP: &str = r"-- \+group: (\w+)";
-const REGEX_MIGRATION_GROUP_END: &str = r"-- \+endgroup";
-const REGEX_NUCLEAR: &str = r"-- \+nuclear";
-const REGEX_PIPE: &str = r"-- \| (.+)";
-const REGEX_TAGS: &str = r"-- \+tags: (.+)";
-const REGEX_DESCRIPTION: &str = r"--\+ (.+)";
-const REGEX_VERSION: &str = r"-- \+version: (\d+\.\d+\.\d+)";
-const REGEX_DEPENDS: &str = r"-- \+depends: (.+)";
-const REGEX_ROLLBACK: &str = r"-- \+rollback";
-const REGEX_PARAMETERS: &str = r"-- \+parameters: (.+)";
-const REGEX_RETURNS: &str = r"-- \+returns: (.+)";
-const REGEX_CALL_MACRO: &str = r"-- \+call: (.+)";
-const REGEX_CALL_FUNC: &str = r"-- \+call-func: (\w+)";
-
-pub enum PipeFor {
-    Tags,
-    Parameters,
-    Returns,
-    Macro
+/// A migration, macro or function being lowered, the blocks holding SQL.
+enum Leaf {
+    Migration {
+        migration: Migration,
+        // Set once `-- +rollback` was read
+        rollback: bool,
+    },
+    Macro(MacroFunc),
+    Function(Function),
+}
+
+impl Leaf {
+    fn context(&self) -> &'static str {
+        match self {
+            Leaf::Migration { .. } => "migration",
+            Leaf::Macro(_) => "macro",
+            Leaf::Function(_) => "function",
+        }
+    }
+
+    /// Adds SQL, as written, to the body of the block.
+    fn add_sql(&mut self, sql: &str, range: Range<usize>) {
+        match self {
+            Leaf::Migration {
+                migration,
+                rollback: true,
+            } => migration.add_sql_rollback(sql, range),
+            Leaf::Migration { migration, .. } => migration.add_sql(sql, range),
+            Leaf::Macro(macro_func) => macro_func.add_body(sql, range),
+            Leaf::Function(function) => function.add_body(sql, range),
+        }
+    }
+
+    fn add_dependency(&mut self, dependency: Dependency) {
+        match self {
+            Leaf::Migration { migration, .. } => migration.add_dependency(dependency),
+            Leaf::Macro(macro_func) => macro_func.add_dependency(dependency),
+            Leaf::Function(function) => function.add_dependency(dependency),
+        }
+    }
+
+    fn add_description(&mut self, description: &str) {
+        match self {
+            Leaf::Migration { migration, .. } => migration.add_description(description),
+            Leaf::Macro(macro_func) => macro_func.add_description(description),
+            Leaf::Function(function) => function.add_description(description),
+        }
+    }
+}
+
+/// Name of the block `open` starts, the first word of its argument, with
+/// its span.
+fn block_name(open: &DirectiveNode) -> Option<(String, Range<usize>)> {
+    let argument = open.argument.as_deref()?;
+    let start = open.argument_span.as_ref()?.start;
+    let end = argument
+        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(argument.len());
+    (end > 0).then(|| (argument[..end].to_string(), start..start + end))
+}
+
+/// The argument of `directive` when it is there, else its name.
+fn argument_span(directive: &DirectiveNode) -> Range<usize> {
+    directive
+        .argument_span
+        .clone()
+        .filter(|span| !span.is_empty())
+        .unwrap_or_else(|| directive.name_span.clone())
+}
+
+/// `dependency`, declared by `directive`.
+fn declared_by(mut dependency: Dependency, directive: &DirectiveNode) -> Dependency {
+    dependency.set_line(directive.line);
+    dependency.set_span(argument_span(directive));
+    dependency
+}
+
+/// Splits a comma separated argument, with the span of every item when the
+/// argument is written on a single line.
+fn split_list(directive: &DirectiveNode) -> Vec<(&str, Range<usize>)> {
+    let argument = directive.argument.as_deref().unwrap_or_default();
+    let whole = argument_span(directive);
+    let single_line = whole.len() == argument.len();
+
+    let mut offset = 0;
+    argument
+        .split(',')
+        .map(|item| {
+            let start = offset + item.len() - item.trim_start().len();
+            offset += item.len() + 1;
+            let span = match single_line {
+                true => whole.start + start..whole.start + start + item.trim().len(),
+                false => whole.clone(),
+            };
+            (item.trim(), span)
+        })
+        .filter(|(item, _)| !item.is_empty())
+        .collect()
 }
 
 #[derive(TS, Debug, Clone, Default)]
@@ -47,6 +127,9 @@ pub struct MigrationFile {
     pub macros: Vec<MacroFunc>,
     pub functions: Vec<Function>,
     pub fsql_version: Option<String>,
+    /// Fail on unknown directives instead of warning about them.
+    #[ts(skip)]
+    pub strict: bool,
 }
 
 impl MigrationFile {
@@ -63,913 +146,996 @@ impl MigrationFile {
         }
     }
 
-    fn unexpected_macro_start_error(ctx: &str, idx: usize) -> ParseError {
-        ParseError {
-            kind: ParseErrorKind::UnexpectedMacroStart,
-            line: idx + 1,
-            column: 1,
-            message: format!("Unexpected macro start inside {} at line {}", ctx, idx + 1),
+    fn qualify_path(&self, path: impl Into<String>) -> String {
+        let path = path.into();
+        if self.file_path.is_empty() {
+            path
+        } else {
+            format!("{}::{}", self.file_path, path)
         }
     }
 
-    fn unexpected_macro_end_error(ctx: &str, idx: usize) -> ParseError {
-        ParseError {
-            kind: ParseErrorKind::UnexpectedMacroEnd,
-            line: idx + 1,
-            column: 1,
-            message: format!("Unexpected macro end inside {} at line {}", ctx, idx + 1),
+    /// Qualifies `node` with the groups it is nested in, if any.
+    fn scoped_path(&self, group_path: &str, node: impl Into<String>) -> String {
+        let node = node.into();
+        if group_path.is_empty() {
+            self.qualify_path(node)
+        } else {
+            self.qualify_path(format!("{group_path}::{node}"))
         }
     }
 
-    fn unexpected_migration_start_error(ctx: &str, idx: usize) -> ParseError {
-        ParseError {
-            kind: ParseErrorKind::UnexpectedMigrationStart,
-            line: idx + 1,
-            column: 1,
-            message: format!(
-                "Unexpected migration start inside {} at line {}",
-                ctx,
-                idx + 1
-            ),
-        }
+    /// Syntax tree of the content, the models are lowered from it.
+    pub fn syntax_tree(&self) -> FileNode {
+        FileNode::parse(&self.file_content)
     }
 
-    fn unexpected_migration_end_error(ctx: &str, idx: usize) -> ParseError {
-        ParseError {
-            kind: ParseErrorKind::UnexpectedMigrationEnd,
-            line: idx + 1,
-            column: 1,
-            message: format!(
-                "Unexpected migration end inside {} at line {}",
-                ctx,
-                idx + 1
-            ),
+    /// Parses the file, stopping at the first error. Returns the warnings
+    /// found on the way.
+    pub fn parse_file(&mut self) -> Result<Vec<ParseWarning>, ParseError> {
+        let report = self.parse();
+        match report.errors.into_iter().next() {
+            Some(error) => Err(error),
+            None => Ok(report.warnings),
         }
     }
 
-    fn unexpected_function_start_error(ctx: &str, idx: usize) -> ParseError {
-        ParseError {
-            kind: ParseErrorKind::UnexpectedFunctionStart,
-            line: idx + 1,
-            column: 1,
-            message: format!(
-                "Unexpected function start inside {} at line {}",
-                ctx,
-                idx + 1
-            ),
-        }
+    /// Parses the whole file even if it has errors. A block with an error is
+    /// dropped, the blocks following the error inside of it are parsed as if
+    /// it had been closed right before them.
+    pub fn parse_file_recovering(&mut self) -> ParseReport {
+        self.parse()
     }
 
-    fn unexpected_function_end_error(ctx: &str, idx: usize) -> ParseError {
-        ParseError {
-            kind: ParseErrorKind::UnexpectedFunctionEnd,
-            line: idx + 1,
-            column: 1,
-            message: format!("Unexpected function end inside {} at line {}", ctx, idx + 1),
-        }
+    /// An error about `span` of the content of this file.
+    pub(crate) fn error(
+        &self,
+        kind: ParseErrorKind,
+        span: Range<usize>,
+        message: impl Into<String>,
+    ) -> ParseError {
+        ParseError::at(kind, message, &self.file_path, &self.file_content, span)
     }
 
-    fn unexpected_migration_group_start_error(ctx: &str, idx: usize) -> ParseError {
-        ParseError {
-            kind: ParseErrorKind::UnexpectedMigrationGroupStart,
-            line: idx + 1,
-            column: 1,
-            message: format!(
-                "Unexpected migration group start inside {} at line {}",
-                ctx,
-                idx + 1
-            ),
+    fn warning(&self, line: usize, message: impl Into<String>) -> ParseWarning {
+        ParseWarning {
+            file: self.file_path.clone(),
+            message: message.into(),
+            line,
         }
     }
 
-    fn unexpected_migration_group_end_error(ctx: &str, idx: usize) -> ParseError {
-        ParseError {
-            kind: ParseErrorKind::UnexpectedMigrationGroupEnd,
-            line: idx + 1,
-            column: 1,
-            message: format!(
-                "Unexpected migration group end inside {} at line {}",
-                ctx,
-                idx + 1
-            ),
-        }
+    fn without_context(
+        &self,
+        directive: &DirectiveNode,
+        kind: ParseErrorKind,
+        message: &str,
+    ) -> ParseError {
+        self.error(kind, directive.name_span.clone(), message)
     }
 
-    fn process_macro_name_str(macro_call: &str, line: usize) -> Result<String, ParseError> {
-        if macro_call.trim().is_empty() {
-            Err(ParseError {
-                kind: ParseErrorKind::MissingMacroName,
-                line,
-                column: 1,
-                message: "Missing macro name in call".to_string(),
-            })
-        } else {
-            Ok(macro_call.split('(').next().unwrap_or("").trim().to_string())
-        }
+    /// Lowers the syntax tree of the content to migrations, groups, macros
+    /// and functions, collecting every error and warning on the way.
+    fn parse(&mut self) -> ParseReport {
+        let mut report = ParseReport::default();
+        let tree = self.syntax_tree();
+        self.lower_items(&tree.items, None, "", &mut report);
+        report
     }
 
-    fn process_macro_name(macro_call: Option<&regex::Match>, line: usize) -> Result<String, ParseError> {
-        match macro_call {
-            None => Err(ParseError {
-                kind: ParseErrorKind::MissingMacroName,
-                line,
-                column: 1,
-                message: "Missing macro name in call".to_string(),
-            }),
-            Some(name) if name.as_str().is_empty() => Err(ParseError {
-                kind: ParseErrorKind::MissingMacroName,
-                line,
-                column: 1,
-                message: "Missing macro name in call".to_string(),
-            }),
-            Some(name) => { 
-                Ok(name.as_str().split('(').next().unwrap_or("").trim().to_string())
-            },
+    /// Lowers what is at the top of the file, or directly inside `group`.
+    /// `group_path` names the groups the items are nested in.
+    fn lower_items(
+        &mut self,
+        items: &[Node],
+        mut group: Option<&mut MigrationGroup>,
+        group_path: &str,
+        report: &mut ParseReport,
+    ) {
+        for item in items {
+            match item {
+                Node::Sql(chunk) => {
+                    if let Err(error) = self.sql_outside_of_blocks(chunk) {
+                        report.errors.push(error);
+                    }
+                }
+                Node::Directive(directive) => {
+                    if let Err(error) =
+                        self.group_directive(group.as_deref_mut(), directive, report)
+                    {
+                        report.errors.push(error);
+                    }
+                }
+                Node::Block(block) if block.kind == BlockKind::Group => {
+                    if let Some(subgroup) = self.lower_group(block, group_path, report) {
+                        match group.as_deref_mut() {
+                            Some(group) => group.add_group(subgroup),
+                            None => self.migration_groups.push(subgroup),
+                        }
+                    }
+                }
+                Node::Block(block) => match self.lower_leaf(block, group_path, report) {
+                    Ok(Leaf::Migration { migration, .. }) => match group.as_deref_mut() {
+                        Some(group) => group.add_migration(migration),
+                        None => self.migrations.push(migration),
+                    },
+                    Ok(Leaf::Macro(macro_func)) => self.macros.push(macro_func),
+                    Ok(Leaf::Function(function)) => self.functions.push(function),
+                    Err((error, resume)) => {
+                        report.errors.push(error);
+                        // Blocks after the error are read as if the broken one was closed
+                        if let Some(resume) = resume {
+                            self.lower_items(
+                                &block.items[resume..],
+                                group.as_deref_mut(),
+                                group_path,
+                                report,
+                            );
+                        }
+                    }
+                },
+            }
         }
     }
 
-    pub fn parse_file(&mut self) -> Result<(), ParseError> {
-        let mut current_migration: Option<Migration> = None;
-        let mut migration_opened_at: usize = 0;
-        let mut current_group: Option<MigrationGroup> = None;
-        let mut group_opened_at: Vec<usize> = Vec::new();
-        let mut current_macro: Option<MacroFunc> = None;
-        let mut macro_opened_at: usize = 0;
-        let mut current_function: Option<Function> = None;
-        let mut function_opened_at: usize = 0;
-        let mut previous_groups: Vec<MigrationGroup> = Vec::new();
-
-        let mut buffer = String::new();
-        let mut full_path = String::new();
-        let mut rollback = false;
-        let mut pipe_for: Option<PipeFor> = None;
-
-        let mut lines = self.file_content.lines().enumerate().peekable();
-
-        let regex_migration_start =
-            regex::Regex::new(REGEX_MIGRATION_START).expect("Invalid regex for migration start");
-        let regex_migration_end =
-            regex::Regex::new(REGEX_MIGRATION_END).expect("Invalid regex for migration end");
-        let regex_macro_start =
-            regex::Regex::new(REGEX_MACRO_START).expect("Invalid regex for macro");
-        let regex_macro_end =
-            regex::Regex::new(REGEX_MACRO_END).expect("Invalid regex for macro end");
-        let regex_function_start =
-            regex::Regex::new(REGEX_FUNCTION_START).expect("Invalid regex for function");
-        let regex_function_end =
-            regex::Regex::new(REGEX_FUNCTION_END).expect("Invalid regex for function end");
-        let regex_fsql_version =
-            regex::Regex::new(REGEX_FSQL_VERSION).expect("Invalid regex for FSQL version");
-        let regex_migration_group_start =
-            regex::Regex::new(REGEX_MIGRATION_GROUP).expect("Invalid regex for migration group");
-        let regex_migration_group_end = regex::Regex::new(REGEX_MIGRATION_GROUP_END)
-            .expect("Invalid regex for migration group end");
-        let regex_nuclear = regex::Regex::new(REGEX_NUCLEAR).expect("Invalid regex for nuclear");
-        let regex_version = regex::Regex::new(REGEX_VERSION).expect("Invalid regex for version");
-        let regex_depends = regex::Regex::new(REGEX_DEPENDS).expect("Invalid regex for depends");
-        let regex_rollback = regex::Regex::new(REGEX_ROLLBACK).expect("Invalid regex for rollback");
-        let regex_parameters =
-            regex::Regex::new(REGEX_PARAMETERS).expect("Invalid regex for parameters");
-        let regex_returns = regex::Regex::new(REGEX_RETURNS).expect("Invalid regex for returns");
-        let regex_pipe = regex::Regex::new(REGEX_PIPE).expect("Invalid regex for pipe");
-        let regex_tags = regex::Regex::new(REGEX_TAGS).expect("Invalid regex for tags");
-        let regex_description =
-            regex::Regex::new(REGEX_DESCRIPTION).expect("Invalid regex for description");
-        let regex_call_macro =
-            regex::Regex::new(REGEX_CALL_MACRO).expect("Invalid regex for call macro");
-        let regex_call_func =
-            regex::Regex::new(REGEX_CALL_FUNC).expect("Invalid regex for call function");
-
-        while let Some((idx, line)) = lines.next() {
-            let line = line.trim();
-            if line.is_empty() {
-                continue;
+    /// Blank lines are fine between blocks, anything else is SQL out of place.
+    fn sql_outside_of_blocks(&self, chunk: &SqlChunk) -> Result<(), ParseError> {
+        let mut offset = chunk.span.start;
+        for line in chunk.text.split_inclusive('\n') {
+            let start = offset + line.len() - line.trim_start().len();
+            offset += line.len();
+            if !line.trim().is_empty() {
+                return Err(self.error(
+                    ParseErrorKind::SqlWithoutContext,
+                    start..start + line.trim().len(),
+                    "SQL used without a migration, macro, or function",
+                ));
             }
+        }
+        Ok(())
+    }
 
-            if let Some(caps) = regex_pipe.captures(line) {
-                if let Some((_, next_line)) = lines.peek()
-                    && !regex_pipe.is_match(next_line)
-                {
-                    buffer.push_str(caps.get(1).map_or("", |m| m.as_str()));
-                    match pipe_for {
-                        Some(PipeFor::Tags) => {
-                            if let Some(migration) = current_migration.as_mut() {
-                                if let Err(e) = migration.add_tag(buffer.trim()) {
-                                    return Err(ParseError {
-                                        kind: e,
-                                        line: idx + 1,
-                                        column: 1,
-                                        message: format!(
-                                            "Error parsing tag '{}' at line {}",
-                                            buffer.trim(),
-                                            idx + 1
-                                        ),
-                                    });
-                                }
-                            } else if let Some(group) = current_group.as_mut() {
-                                if let Err(e) = group.add_tag(buffer.trim()) {
-                                    return Err(ParseError {
-                                        kind: e,
-                                        line: idx + 1,
-                                        column: 1,
-                                        message: format!(
-                                            "Error parsing tag '{}' at line {}",
-                                            buffer.trim(),
-                                            idx + 1
-                                        ),
-                                    });
-                                }
-                            } else if let Some(func) = current_function.as_mut() {
-                                if let Err(e) = func.add_tag(buffer.trim()) {
-                                    return Err(ParseError {
-                                        kind: e,
-                                        line: idx + 1,
-                                        column: 1,
-                                        message: format!(
-                                            "Error parsing tag '{}' at line {}",
-                                            buffer.trim(),
-                                            idx + 1
-                                        ),
-                                    });
-                                }
-                            } else {
-                                return Err(ParseError {
-                                        kind: ParseErrorKind::TagsWithoutContext,
-                                        line: idx + 1,
-                                        column: 1,
-                                        message: "Tags used without a migration, migration group, or function".to_string(),
-                                    });
-                            }
-                            buffer.clear();
-                        }
-                        Some(PipeFor::Parameters) => {
-                            if let Some(func) = current_function.as_mut() {
-                                if let Err(e) = func.parse_arguments(buffer.trim()) {
-                                    return Err(ParseError {
-                                        kind: e,
-                                        line: idx + 1,
-                                        column: 1,
-                                        message: format!(
-                                            "Error parsing parameters '{}' at line {}",
-                                            buffer.trim(),
-                                            idx + 1
-                                        ),
-                                    });
-                                }
-                            } else if let Some(macro_func) = current_macro.as_mut() {
-                                if let Err(e) = macro_func.parse_arguments(buffer.trim()) {
-                                    return Err(ParseError {
-                                        kind: e,
-                                        line: idx + 1,
-                                        column: 1,
-                                        message: format!(
-                                            "Error parsing parameters '{}' at line {}",
-                                            buffer.trim(),
-                                            idx + 1
-                                        ),
-                                    });
-                                }
-                            } else {
-                                return Err(ParseError {
-                                    kind: ParseErrorKind::ParametersWithoutContext,
-                                    line: idx + 1,
-                                    column: 1,
-                                    message: "Parameters used without a function".to_string(),
-                                });
-                            }
-                            buffer.clear();
-                        }
-                        Some(PipeFor::Returns) => {
-                            if let Some(func) = current_function.as_mut() {
-                                if let Some(return_type) = caps.get(1) {
-                                    func.set_return_type(return_type.as_str().trim().to_string());
-                                } else {
-                                    return Err(ParseError {
-                                        kind: ParseErrorKind::MissingReturnType,
-                                        line: idx + 1,
-                                        column: 1,
-                                        message: "Missing return type in function".to_string(),
-                                    });
-                                }
-                            } else {
-                                return Err(ParseError {
-                                    kind: ParseErrorKind::ReturnsWithoutContext,
-                                    line: idx + 1,
-                                    column: 1,
-                                    message: "Returns used without a function".to_string(),
-                                });
-                            }
-                            buffer.clear();
-                        },
-                        Some(PipeFor::Macro) => {
-                            if let Some(macro_func) = current_macro.as_mut() {
-                                let macro_name = Self::process_macro_name_str(
-                                    buffer.trim(),
-                                    idx + 1,
-                                )?;
-                                let dependency = Dependency::new_macro(
-                                    macro_name.to_string()
-                                );
-                                macro_func.add_dependency(dependency); 
-                            } else if let Some(func) = current_function.as_mut() {
-                                let macro_name = Self::process_macro_name_str(
-                                    buffer.trim(),
-                                    idx + 1,
-                                )?;
-                                let dependency = Dependency::new_macro(
-                                    macro_name.to_string()
-                                );
-                                func.add_dependency(dependency);
-                            } else if let Some(migrations) = current_migration.as_mut() {
-                                let macro_name = Self::process_macro_name_str(
-                                    buffer.trim(),
-                                    idx + 1,
-                                )?;
-                                let dependency = Dependency::new_macro(
-                                    macro_name.to_string()
-                                );
-                                migrations.add_dependency(dependency); 
-                            } else {
-                                return Err(ParseError {
-                                    kind: ParseErrorKind::MacroCallWithoutContext,
-                                    line: idx + 1,
-                                    column: 1,
-                                    message: "Macro call used without migration, macro or function".to_string(),
-                                });
-                            }
-                            buffer.clear();
-                        }
-                        None => {
-                            return Err(ParseError {
-                                kind: ParseErrorKind::UnexpectedEndOfFile(idx + 1),
-                                line: idx + 1,
-                                column: 1,
-                                message: "Unexpected pipe without context".to_string(),
-                            });
-                        }
-                    }
-                } else {
-                    buffer.push_str(caps.get(1).map_or("", |m| m.as_str()));
-                }
-            } else if let Some(caps) = regex_fsql_version.captures(line) {
-                if let Some(version) = caps.get(1) {
-                    self.fsql_version = Some(version.as_str().trim().to_string());
-                }
-            } else if let Some(caps) = regex_migration_start.captures(line) {
-                if current_migration.take().is_some() {
-                    return Err(Self::unexpected_migration_start_error("migration", idx));
-                } else if current_function.take().is_some() {
-                    return Err(Self::unexpected_migration_start_error("function", idx));
-                } else if current_macro.take().is_some() {
-                    return Err(Self::unexpected_migration_start_error("macro", idx));
-                }
+    fn lower_group(
+        &mut self,
+        block: &BlockNode,
+        parent_path: &str,
+        report: &mut ParseReport,
+    ) -> Option<MigrationGroup> {
+        let named = block_name(&block.open);
+        if named.is_none() {
+            report.errors.push(self.error(
+                ParseErrorKind::MissingMigrationGroupName,
+                argument_span(&block.open),
+                "Missing migration group name",
+            ));
+        }
 
-                let migration_name = caps.get(1);
-                if let Some(name) = migration_name
-                    && !name.as_str().trim().is_empty()
-                {
-                    current_migration = Some(Migration::new(name.as_str().trim()));
-                    migration_opened_at = idx + 1;
-                } else {
-                    return Err(ParseError {
-                        kind: ParseErrorKind::MissingMigrationName,
-                        line: idx + 1,
-                        column: 1,
-                        message: "Missing migration name".to_string(),
-                    });
-                }
-            } else if regex_migration_end.captures(line).is_some() {
-                if current_macro.take().is_some() {
-                    return Err(Self::unexpected_migration_end_error("macro", idx));
-                } else if current_function.take().is_some() {
-                    return Err(Self::unexpected_migration_end_error("function", idx));
-                }
+        // A group without a name is still read, for the errors inside of it
+        let (name, span) = named
+            .clone()
+            .unwrap_or_else(|| (String::new(), block.open.name_span.clone()));
+        let (path, group_path) = if parent_path.is_empty() {
+            (name.clone(), format!("Group({name})"))
+        } else {
+            (
+                format!("{parent_path}::{name}"),
+                format!("{parent_path}::Group({name})"),
+            )
+        };
+        let mut group = MigrationGroup::new(name.as_str());
+        group.set_line(block.open.line);
+        group.set_span(span);
+        group.set_full_path(self.qualify_path(group_path));
+        self.lower_items(&block.items, Some(&mut group), &path, report);
 
-                if let Some(mut migration) = current_migration.take() {
-                    if full_path.is_empty() {
-                        migration.set_full_path(format!("Migration({})", migration.name()));
-                    } else {
-                        migration.set_full_path(format!(
-                            "{}::Migration({})",
-                            full_path, migration.name()
-                        ));
-                    }
-                    if let Some(group) = current_group.as_mut() {
-                        group.add_migration(migration);
-                    } else {
-                        self.migrations.push(migration);
-                    }
-                } else {
-                    return Err(ParseError {
-                        kind: ParseErrorKind::UnexpectedMigrationEnd,
-                        line: idx + 1,
-                        column: 1,
-                        message: format!("Unexpected migration end at line {}", idx + 1),
-                    });
-                }
-            } else if let Some(caps) = regex_macro_start.captures(line) {
-                if current_migration.take().is_some() {
-                    return Err(Self::unexpected_macro_start_error("migration", idx));
-                } else if current_function.take().is_some() {
-                    return Err(Self::unexpected_macro_start_error("function", idx));
-                } else if current_macro.take().is_some() {
-                    return Err(Self::unexpected_macro_start_error("macro", idx));
-                }
+        if block.close.is_none() {
+            report.errors.push(self.error(
+                ParseErrorKind::MigrationGroupNotClosed,
+                block.open.name_span.clone(),
+                format!("Migration group '{name}' is not closed"),
+            ));
+            return None;
+        }
+        named.map(|_| group)
+    }
 
-                let macro_name = caps.get(1);
-                if let Some(name) = macro_name {
-                    current_macro = Some(MacroFunc::new(name.as_str().trim()));
-                    macro_opened_at = idx + 1;
-                } else {
-                    return Err(ParseError {
-                        kind: ParseErrorKind::MissingMacroName,
-                        line: idx + 1,
-                        column: 1,
-                        message: "Missing macro name".to_string(),
-                    });
-                }
-            } else if regex_macro_end.captures(line).is_some() {
-                if current_migration.take().is_some() {
-                    return Err(Self::unexpected_macro_end_error("migration", idx));
-                } else if current_function.take().is_some() {
-                    return Err(Self::unexpected_macro_end_error("function", idx));
-                } else if let Some(mut macro_func) = current_macro.take() {
-                    macro_func.parse_body();
-                    self.macros.push(macro_func);
-                } else {
-                    return Err(Self::unexpected_macro_end_error("macro", idx));
-                }
-            } else if let Some(caps) = regex_function_start.captures(line) {
-                if current_migration.take().is_some() {
-                    return Err(Self::unexpected_function_start_error("migration", idx));
-                } else if current_macro.take().is_some() {
-                    return Err(Self::unexpected_function_start_error("macro", idx));
-                } else if current_function.take().is_some() {
-                    return Err(Self::unexpected_function_start_error("function", idx));
-                }
+    /// Lowers a migration, macro or function. On error, also returns where
+    /// in the block the next block starts, parsing resumes there.
+    fn lower_leaf(
+        &mut self,
+        block: &BlockNode,
+        group_path: &str,
+        report: &mut ParseReport,
+    ) -> Result<Leaf, (ParseError, Option<usize>)> {
+        let next_block = |from: usize| {
+            block.items[from..]
+                .iter()
+                .position(|item| matches!(item, Node::Block(_)))
+                .map(|position| from + position)
+        };
 
-                let function_name = caps.get(1);
-                if let Some(name) = function_name {
-                    current_function = Some(Function::new(name.as_str().trim()));
-                    function_opened_at = idx + 1;
-                } else {
-                    return Err(ParseError {
-                        kind: ParseErrorKind::MissingFunctionName,
-                        line: idx + 1,
-                        column: 1,
-                        message: "Missing function name".to_string(),
-                    });
-                }
-            } else if regex_function_end.captures(line).is_some() {
-                if current_migration.take().is_some() {
-                    return Err(Self::unexpected_function_end_error("migration", idx));
-                } else if current_macro.take().is_some() {
-                    return Err(Self::unexpected_function_end_error("macro", idx));
-                } else if let Some(mut function) = current_function.take() {
-                    function.put_boilerplate();
-                    self.functions.push(function);
-                } else {
-                    return Err(Self::unexpected_function_end_error("function", idx));
-                }
-            } else if let Some(caps) = regex_migration_group_start.captures(line) {
-                if current_migration.take().is_some() {
-                    return Err(Self::unexpected_migration_group_start_error(
-                        "migration",
-                        idx,
-                    ));
-                } else if current_macro.take().is_some() {
-                    return Err(Self::unexpected_migration_group_start_error("macro", idx));
-                } else if current_function.take().is_some() {
-                    return Err(Self::unexpected_migration_group_start_error(
-                        "function", idx,
-                    ));
+        let Some((name, span)) = block_name(&block.open) else {
+            let (kind, message) = match block.kind {
+                BlockKind::Migration => (
+                    ParseErrorKind::MissingMigrationName,
+                    "Missing migration name",
+                ),
+                BlockKind::Macro => (ParseErrorKind::MissingMacroName, "Missing macro name"),
+                _ => (ParseErrorKind::MissingFunctionName, "Missing function name"),
+            };
+            return Err((
+                self.error(kind, argument_span(&block.open), message),
+                next_block(0),
+            ));
+        };
+        let line = block.open.line;
+        let mut leaf = match block.kind {
+            BlockKind::Migration => {
+                let mut migration = Migration::new(name);
+                migration.set_line(line);
+                migration.set_span(span);
+                Leaf::Migration {
+                    migration,
+                    rollback: false,
                 }
+            }
+            BlockKind::Macro => {
+                let mut macro_func = MacroFunc::new(name);
+                macro_func.set_line(line);
+                macro_func.set_span(span);
+                Leaf::Macro(macro_func)
+            }
+            BlockKind::Function => {
+                let mut function = Function::new(name);
+                function.set_line(line);
+                function.set_span(span);
+                Leaf::Function(function)
+            }
+            BlockKind::Group => unreachable!("groups are lowered by lower_group"),
+        };
 
-                let group_name = caps.get(1);
-                if let Some(name) = group_name
-                    && !name.as_str().trim().is_empty()
-                {
-                    if let Some(group) = current_group.take() {
-                        previous_groups.push(group);
-                    }
-                    current_group = Some(MigrationGroup::new(name.as_str().trim()));
-                    if full_path.is_empty() {
-                        full_path.push_str(name.as_str().trim());
-                    } else {
-                        full_path = format!(
-                            "{}::{}",
-                            full_path,
-                            name.as_str().trim()
-                        );
-                    }
-                    group_opened_at.push(idx + 1);
-                } else {
-                    return Err(ParseError {
-                        kind: ParseErrorKind::MissingMigrationGroupName,
-                        line: idx + 1,
-                        column: 1,
-                        message: "Missing migration group name".to_string(),
-                    });
-                }
-            } else if regex_migration_group_end.captures(line).is_some() {
-                if current_migration.take().is_some() {
-                    return Err(Self::unexpected_migration_group_end_error("migration", idx));
-                } else if current_macro.take().is_some() {
-                    return Err(Self::unexpected_migration_group_end_error("macro", idx));
-                } else if current_function.take().is_some() {
-                    return Err(Self::unexpected_migration_group_end_error("function", idx));
+        for (index, item) in block.items.iter().enumerate() {
+            let result = match item {
+                Node::Sql(chunk) => {
+                    leaf.add_sql(&chunk.text, chunk.span.clone());
+                    Ok(())
                 }
+                Node::Directive(directive) => self.leaf_directive(&mut leaf, directive, report),
+                Node::Block(nested) => Err(self.unexpected_start(nested, leaf.context())),
+            };
+            if let Err(error) = result {
+                return Err((error, next_block(index)));
+            }
+        }
 
-                if let Some(group) = current_group.take() {
-                    if let Some(mut previous_group) = previous_groups.pop() {
-                        previous_group.add_group(group);
-                        current_group = Some(previous_group);
-                    } else {
-                        self.migration_groups.push(group);
-                    }
-                    if let Some((path, _)) = full_path.rsplit_once("::") {
-                        full_path = path.to_string();
-                    } else {
-                        full_path.clear();
-                    }
+        if block.close.is_none() {
+            let (kind, message) = match block.kind {
+                BlockKind::Migration => (ParseErrorKind::MigrationNotClosed, "Migration"),
+                BlockKind::Macro => (ParseErrorKind::MacroNotClosed, "Macro"),
+                _ => (ParseErrorKind::FunctionNotClosed, "Function"),
+            };
+            let name = block_name(&block.open)
+                .map(|(name, _)| name)
+                .unwrap_or_default();
+            let error = self.error(
+                kind,
+                block.open.name_span.clone(),
+                format!("{message} '{name}' is not closed"),
+            );
+            return Err((error, None));
+        }
+        self.close_leaf(leaf, &block.open, group_path, report)
+            .map_err(|error| (error, None))
+    }
 
-                    group_opened_at.pop();
-                } else {
-                    return Err(Self::unexpected_migration_group_end_error(
-                        "migration group",
-                        idx,
+    /// Finishes a block once its closing directive was read.
+    fn close_leaf(
+        &self,
+        mut leaf: Leaf,
+        open: &DirectiveNode,
+        group_path: &str,
+        report: &mut ParseReport,
+    ) -> Result<Leaf, ParseError> {
+        match &mut leaf {
+            Leaf::Migration { migration, .. } => {
+                let path = format!("Migration({})", migration.name());
+                migration.set_full_path(self.scoped_path(group_path, path));
+            }
+            Leaf::Macro(macro_func) => {
+                if let Err(kind) = macro_func.parse_body() {
+                    return Err(self.error(
+                        kind,
+                        open.name_span.clone(),
+                        format!(
+                            "Error parsing macro call in the body of macro '{}'",
+                            macro_func.name()
+                        ),
                     ));
                 }
-            } else if regex_nuclear.captures(line).is_some() {
-                if let Some(migration) = current_migration.as_mut() {
-                    migration.set_nuclear_true();
-                } else if let Some(group) = current_group.as_mut() {
-                    group.set_nuclear_true();
-                } else {
-                    return Err(ParseError {
-                        kind: ParseErrorKind::NuclearWithoutContext,
-                        line: idx + 1,
-                        column: 1,
-                        message: "Nuclear tag used without a migration or migration group"
-                            .to_string(),
-                    });
-                }
-            } else if let Some(caps) = regex_tags.captures(line) {
-                if let Some(tags) = caps.get(1) {
-                    if let Some((_, next_line)) = lines.peek() {
-                        if regex_pipe.is_match(next_line) {
-                            buffer.push_str(tags.as_str());
-                            pipe_for = Some(PipeFor::Tags);
-                        } else {
-                            let tags = tags
-                                .as_str()
-                                .split(',')
-                                .map(|s| s.trim())
-                                .collect::<Vec<&str>>();
-                            if let Some(migration) = current_migration.as_mut() {
-                                for tag in tags {
-                                    if let Err(e) = migration.add_tag(tag) {
-                                        return Err(ParseError {
-                                            kind: e,
-                                            line: idx + 1,
-                                            column: 1,
-                                            message: format!(
-                                                "Error parsing tag '{}' at line {}",
-                                                tag,
-                                                idx + 1
-                                            ),
-                                        });
-                                    }
-                                }
-                            } else if let Some(group) = current_group.as_mut() {
-                                for tag in tags {
-                                    if let Err(e) = group.add_tag(tag) {
-                                        return Err(ParseError {
-                                            kind: e,
-                                            line: idx + 1,
-                                            column: 1,
-                                            message: format!(
-                                                "Error parsing tag '{}' at line {}",
-                                                tag,
-                                                idx + 1
-                                            ),
-                                        });
-                                    }
-                                }
-                            } else if let Some(func) = current_function.as_mut() {
-                                for tag in tags {
-                                    if let Err(e) = func.add_tag(tag) {
-                                        return Err(ParseError {
-                                            kind: e,
-                                            line: idx + 1,
-                                            column: 1,
-                                            message: format!(
-                                                "Error parsing tag '{}' at line {}",
-                                                tag,
-                                                idx + 1
-                                            ),
-                                        });
-                                    }
-                                }
-                            } else {
-                                return Err(ParseError {
-                                    kind: ParseErrorKind::TagsWithoutContext,
-                                    line: idx + 1,
-                                    column: 1,
-                                    message:
-                                        "Tags used without a migration, migration group, or function"
-                                            .to_string(),
-                                });
-                            }
-                        }
-                    }
-                } else {
-                    return Err(ParseError {
-                        kind: ParseErrorKind::MissingTags,
-                        line: idx + 1,
-                        column: 1,
-                        message: "Missing tags in migration".to_string(),
-                    });
-                }
-            } else if let Some(caps) = regex_version.captures(line) {
-                if let Some(version) = caps.get(1) {
-                    if let Some(migration) = current_migration.as_mut() {
-                        migration.set_version(version.as_str().trim());
-                    } else if let Some(group) = current_group.as_mut() {
-                        group.set_version(version.as_str().trim());
-                    } else {
-                        return Err(ParseError {
-                            kind: ParseErrorKind::MissingArgument("version".to_string()),
-                            line: idx + 1,
-                            column: 1,
-                            message: "Version used without a migration or migration group"
-                                .to_string(),
-                        });
-                    }
-                } else {
-                    return Err(ParseError {
-                        kind: ParseErrorKind::MissingArgument("version".to_string()),
-                        line: idx + 1,
-                        column: 1,
-                        message: "Missing version in migration".to_string(),
-                    });
+                for name in macro_func.unused_arguments() {
+                    report.warnings.push(self.warning(
+                        open.line,
+                        format!(
+                            "Argument '{}' of macro '{}' is defined but not used in its body",
+                            name,
+                            macro_func.name()
+                        ),
+                    ));
                 }
-            } else if let Some(caps) = regex_depends.captures(line) {
-                if let Some(dependency) = caps.get(1) {
-                    let dependency = dependency.as_str().trim();
-                    if dependency.is_empty() {
-                        return Err(ParseError {
-                            kind: ParseErrorKind::MissingArgument("depends".to_string()),
-                            line: idx + 1,
-                            column: 1,
-                            message: "Missing dependency in migration".to_string(),
-                        });
+                let path = format!("Macro({})", macro_func.name());
+                macro_func.set_full_path(self.scoped_path(group_path, path));
+            }
+            Leaf::Function(function) => {
+                let warnings = match function.put_boilerplate() {
+                    Ok(warnings) => warnings,
+                    Err(kind) => {
+                        let reason = match &kind {
+                            ParseErrorKind::InvalidFunctionBody(reason) => reason.clone(),
+                            other => format!("{other:?}"),
+                        };
+                        return Err(self.error(
+                            kind,
+                            open.name_span.clone(),
+                            format!(
+                                "Invalid body for function '{}': {}",
+                                function.name(),
+                                reason
+                            ),
+                        ));
                     }
+                };
+                report.warnings.extend(
+                    warnings
+                        .into_iter()
+                        .map(|message| self.warning(open.line, message)),
+                );
+                let path = format!("Function({})", function.name());
+                function.set_full_path(self.scoped_path(group_path, path));
+            }
+        }
+        Ok(leaf)
+    }
 
-                    match Dependency::new(dependency) {
-                        Ok(dep) => {
-                            if let Some(migration) = current_migration.as_mut() {
-                                migration.add_dependency(dep);
-                            } else if let Some(macro_func) = current_macro.as_mut() {
-                                macro_func.add_dependency(dep);
-                            } else if let Some(func) = current_function.as_mut() {
-                                func.add_dependency(dep);
-                            } else if let Some(group) = current_group.as_mut() {
-                                group.add_dependency(dep);
-                            } else {
-                                return Err(ParseError {
-                                    kind: ParseErrorKind::DependsWithoutContext,
-                                    line: idx + 1,
-                                    column: 1,
-                                    message: "Depends used without a migration or migration group"
-                                        .to_string(),
-                                });
-                            }
-                        }
-                        Err(e) => {
-                            return Err(ParseError {
-                                kind: e,
-                                line: idx + 1,
-                                column: 1,
-                                message: format!(
-                                    "Error parsing dependency '{}' at line {}",
-                                    dependency,
-                                    idx + 1
-                                ),
-                            });
-                        }
-                    }
-                } else {
-                    return Err(ParseError {
-                        kind: ParseErrorKind::MissingArgument("depends".to_string()),
-                        line: idx + 1,
-                        column: 1,
-                        message: "Missing dependency in migration".to_string(),
-                    });
-                }
-            } else if regex_rollback.captures(line).is_some() {
-                if current_migration.is_some() {
-                    rollback = true;
-                } else {
-                    return Err(ParseError {
-                        kind: ParseErrorKind::RollbackWithoutContext,
-                        line: idx + 1,
-                        column: 1,
-                        message: "Rollback used without a migration".to_string(),
-                    });
-                }
-            } else if let Some(caps) = regex_parameters.captures(line) {
-                if let Some(parameters) = caps.get(1) {
-                    if let Some((_, next_line)) = lines.peek()
-                        && regex_pipe.is_match(next_line)
-                    {
-                        buffer.push_str(parameters.as_str());
-                        pipe_for = Some(PipeFor::Parameters);
-                    } else if let Some(func) = current_function.as_mut() {
-                        if let Err(e) = func.parse_arguments(parameters.as_str().trim()) {
-                            return Err(ParseError {
-                                kind: e,
-                                line: idx + 1,
-                                column: 1,
-                                message: format!(
-                                    "Error parsing parameters '{}' at line {}",
-                                    parameters.as_str().trim(),
-                                    idx + 1
-                                ),
-                            });
-                        }
-                    } else if let Some(macro_func) = current_macro.as_mut() {
-                        if let Err(e) = macro_func.parse_arguments(parameters.as_str().trim()) {
-                            return Err(ParseError {
-                                kind: e,
-                                line: idx + 1,
-                                column: 1,
-                                message: format!(
-                                    "Error parsing parameters '{}' at line {}",
-                                    parameters.as_str().trim(),
-                                    idx + 1
-                                ),
-                            });
-                        }
-                    } else {
-                        return Err(ParseError {
-                            kind: ParseErrorKind::ParametersWithoutContext,
-                            line: idx + 1,
-                            column: 1,
-                            message: "Parameters used without a function".to_string(),
-                        });
-                    }
-                } else {
-                    return Err(ParseError {
-                        kind: ParseErrorKind::MissingParameters,
-                        line: idx + 1,
-                        column: 1,
-                        message: "Missing parameters in function".to_string(),
-                    });
-                }
-            } else if let Some(caps) = regex_returns.captures(line) {
-                if let Some(return_type) = caps.get(1) {
-                    if let Some((_, next_line)) = lines.peek()
-                        && regex_pipe.is_match(next_line)
-                    {
-                        buffer.push_str(return_type.as_str());
-                        pipe_for = Some(PipeFor::Returns);
-                    } else if return_type.as_str().trim().is_empty() {
-                        return Err(ParseError {
-                            kind: ParseErrorKind::MissingReturnType,
-                            line: idx + 1,
-                            column: 1,
-                            message: "Missing return type in function".to_string(),
-                        });
-                    } else if let Some(func) = current_function.as_mut() {
-                        func.set_return_type(return_type.as_str().trim().to_string());
-                    } else {
-                        return Err(ParseError {
-                            kind: ParseErrorKind::ReturnsWithoutContext,
-                            line: idx + 1,
-                            column: 1,
-                            message: "Returns used without a function".to_string(),
-                        });
-                    }
-                } else {
-                    return Err(ParseError {
-                        kind: ParseErrorKind::MissingReturnType,
-                        line: idx + 1,
-                        column: 1,
-                        message: "Missing return type in function".to_string(),
-                    });
-                }
-            } else if let Some(caps) = regex_call_macro.captures(line) {
-                if let Some((_, next_line)) = lines.peek()
-                        && regex_pipe.is_match(next_line)
-                {
-                    if let Some(macro_name) = caps.get(1) {
-                        buffer.push_str(macro_name.as_str());
-                    } else {
-                        return Err(ParseError {
-                            kind: ParseErrorKind::MissingMacroName,
-                            line: idx + 1,
-                            column: 1,
-                            message: "Missing macro name in macro call".to_string(),
-                        });
-                    }
-                    pipe_for = Some(PipeFor::Macro);
-                } else {
-                    let macro_name = Self::process_macro_name(
-                        caps.get(1).as_ref(),
-                        idx + 1,
-                    )?;
-
-                    if let Some(macro_func) = current_macro.as_mut() {
-                        let dependency = Dependency::new_macro(macro_name.to_string());
-                        macro_func.add_dependency(dependency);
-                    } else if let Some(function) = current_function.as_mut() {
-                        let dependency = Dependency::new_macro(macro_name.to_string());
-                        function.add_dependency(dependency);
-                    } else if let Some(migration) = current_migration.as_mut() {
-                        let dependency = Dependency::new_macro(macro_name.to_string());
-                        migration.add_dependency(dependency);
-                    } else {
-                        return Err(ParseError {
-                            kind: ParseErrorKind::MacroCallWithoutContext,
-                            line: idx + 1,
-                            column: 1,
-                            message: "Macro call used without migration, macro or function".to_string(),
-                        });
-                    }
+    fn unexpected_start(&self, nested: &BlockNode, context: &str) -> ParseError {
+        let (kind, what) = match nested.kind {
+            BlockKind::Migration => (ParseErrorKind::UnexpectedMigrationStart, "migration"),
+            BlockKind::Group => (
+                ParseErrorKind::UnexpectedMigrationGroupStart,
+                "migration group",
+            ),
+            BlockKind::Macro => (ParseErrorKind::UnexpectedMacroStart, "macro"),
+            BlockKind::Function => (ParseErrorKind::UnexpectedFunctionStart, "function"),
+        };
+        self.error(
+            kind,
+            nested.open.name_span.clone(),
+            format!("Unexpected {what} start inside {context}"),
+        )
+    }
+
+    /// Error for a closing directive without the block it closes. `context`
+    /// is the block it was found in, if any.
+    fn unexpected_end(&self, directive: &DirectiveNode, context: Option<&str>) -> ParseError {
+        let (kind, what) = match directive.name.as_str() {
+            "endmigration" => (ParseErrorKind::UnexpectedMigrationEnd, "migration"),
+            "endgroup" => (
+                ParseErrorKind::UnexpectedMigrationGroupEnd,
+                "migration group",
+            ),
+            "endmacro" => (ParseErrorKind::UnexpectedMacroEnd, "macro"),
+            _ => (ParseErrorKind::UnexpectedFunctionEnd, "function"),
+        };
+        let message = match context {
+            Some(context) => format!("Unexpected {what} end inside {context}"),
+            None => format!("Unexpected {what} end"),
+        };
+        self.error(kind, directive.name_span.clone(), message)
+    }
+
+    /// Checks the directive is a known one. Unknown directives are treated
+    /// as comments with a warning, or are an error in strict mode. Deprecated
+    /// ones are read as the directive replacing them, with a warning.
+    fn check_known(
+        &self,
+        directive: &DirectiveNode,
+        report: &mut ParseReport,
+    ) -> Result<bool, ParseError> {
+        let name = directive.name.as_str();
+        if name == "|" || directives::DIRECTIVES.contains(&name) {
+            return Ok(true);
+        }
+        if let Some(replacement) = directives::replacement(name) {
+            report.warnings.push(self.warning(
+                directive.line,
+                format!("Directive '-- +{name}' is deprecated, use '-- +{replacement}'"),
+            ));
+            return Ok(true);
+        }
+        if self.strict {
+            return Err(self.error(
+                ParseErrorKind::UnknownDirective(name.to_string()),
+                directive.name_span.clone(),
+                format!("Unknown directive '-- +{name}'"),
+            ));
+        }
+        let suggestion = directives::suggest(name)
+            .map(|known| format!(", did you mean '-- +{known}'?"))
+            .unwrap_or_default();
+        report.warnings.push(self.warning(
+            directive.line,
+            format!("Unknown directive '-- +{name}' is treated as a comment{suggestion}"),
+        ));
+        Ok(false)
+    }
+
+    /// The argument of `directive`, failing with `kind` when it is missing.
+    fn required_argument<'d>(
+        &self,
+        directive: &'d DirectiveNode,
+        kind: ParseErrorKind,
+        message: &str,
+    ) -> Result<&'d str, ParseError> {
+        match directive.argument.as_deref() {
+            Some(argument) if !argument.is_empty() => Ok(argument),
+            _ => Err(self.error(kind, argument_span(directive), message)),
+        }
+    }
+
+    fn retries(&self, directive: &DirectiveNode) -> Result<u32, ParseError> {
+        let value = directive.argument.as_deref().unwrap_or_default();
+        value.parse::<u32>().map_err(|_| {
+            self.error(
+                ParseErrorKind::InvalidRetries(value.to_string()),
+                argument_span(directive),
+                format!("Invalid retries '{value}', expected a whole number"),
+            )
+        })
+    }
+
+    fn add_tags(
+        &self,
+        directive: &DirectiveNode,
+        mut add: impl FnMut(&str) -> Result<bool, ParseErrorKind>,
+    ) -> Result<(), ParseError> {
+        let tags = split_list(directive);
+        if tags.is_empty() {
+            return Err(self.error(
+                ParseErrorKind::MissingTags,
+                argument_span(directive),
+                "Missing tags",
+            ));
+        }
+        for (tag, span) in tags {
+            if let Err(kind) = add(tag) {
+                return Err(self.error(kind, span, format!("Error parsing tag '{tag}'")));
+            }
+        }
+        Ok(())
+    }
+
+    fn dependency(&self, directive: &DirectiveNode) -> Result<Dependency, ParseError> {
+        let dependency = self.required_argument(
+            directive,
+            ParseErrorKind::MissingArgument("depends".to_string()),
+            "Missing dependency",
+        )?;
+        match Dependency::new(dependency) {
+            Ok(parsed) => Ok(declared_by(parsed, directive)),
+            Err(kind) => Err(self.error(
+                kind,
+                argument_span(directive),
+                format!("Error parsing dependency '{dependency}'"),
+            )),
+        }
+    }
+
+    /// Name of the macro a `-- +call:` calls.
+    fn called_macro(&self, directive: &DirectiveNode) -> Result<String, ParseError> {
+        let call = self.required_argument(
+            directive,
+            ParseErrorKind::MissingMacroName,
+            "Missing macro name in call",
+        )?;
+        let name = call.split('(').next().unwrap_or_default().trim();
+        if name.is_empty() {
+            return Err(self.error(
+                ParseErrorKind::MissingMacroName,
+                argument_span(directive),
+                "Missing macro name in call",
+            ));
+        }
+        Ok(name.to_string())
+    }
+
+    fn called_function(&self, directive: &DirectiveNode) -> Result<Dependency, ParseError> {
+        let path = self.required_argument(
+            directive,
+            ParseErrorKind::MissingFunctionName,
+            "Missing function name in function call",
+        )?;
+        Ok(declared_by(Dependency::new_function(path), directive))
+    }
+
+    fn pipe_without_context(&self, directive: &DirectiveNode) -> ParseError {
+        self.error(
+            ParseErrorKind::UnexpectedEndOfFile(directive.line),
+            directive.name_span.clone(),
+            "Unexpected pipe without context",
+        )
+    }
+
+    /// Applies a directive found at the top of the file, or directly inside
+    /// `group`.
+    fn group_directive(
+        &mut self,
+        group: Option<&mut MigrationGroup>,
+        directive: &DirectiveNode,
+        report: &mut ParseReport,
+    ) -> Result<(), ParseError> {
+        if !self.check_known(directive, report)? {
+            return Ok(());
+        }
+
+        let name = directives::canonical(&directive.name);
+        let Some(group) = group else {
+            let (kind, message) = match name {
+                "|" => return Err(self.pipe_without_context(directive)),
+                "fsql:version" => {
+                    self.fsql_version = directive.argument.clone();
+                    return Ok(());
                 }
-            } else if let Some(caps) = regex_description.captures(line)
-                && let Some(description) = caps.get(1)
-            {
-                if let Some(group) = current_group.as_mut() {
-                    group.add_description(description.as_str().trim());
-                } else if let Some(func) = current_function.as_mut() {
-                    func.add_description(description.as_str().trim());
-                } else if let Some(migration) = current_migration.as_mut() {
-                    migration.add_description(description.as_str().trim());
-                } else {
-                    return Err(ParseError {
-                        kind: ParseErrorKind::DescriptionWithoutContext,
-                        line: idx + 1,
-                        column: 1,
-                        message:
-                            "Description used without a migration, migration group, or function"
-                                .to_string(),
-                    });
+                "endmigration" | "endgroup" | "endmacro" | "endfunction" => {
+                    return Err(self.unexpected_end(directive, None));
                 }
-            } else if let Some(migration) = current_migration.as_mut() {
-                if rollback {
-                    migration.add_sql_rollback(line);
-                } else {
-                    migration.add_sql(line);
+                "nuclear" => (
+                    ParseErrorKind::NuclearWithoutContext,
+                    "Nuclear tag used without a migration or migration group",
+                ),
+                "retries" => (
+                    ParseErrorKind::RetriesWithoutContext,
+                    "Retries used without a migration or migration group",
+                ),
+                "transaction" => (
+                    ParseErrorKind::TransactionWithoutContext,
+                    "Transaction used without a migration group",
+                ),
+                "tags" => (
+                    ParseErrorKind::TagsWithoutContext,
+                    "Tags used without a migration, migration group, or function",
+                ),
+                "version" => (
+                    ParseErrorKind::MissingArgument("version".to_string()),
+                    "Version used without a migration or migration group",
+                ),
+                "depends" => (
+                    ParseErrorKind::DependsWithoutContext,
+                    "Depends used without a migration or migration group",
+                ),
+                "description" => (
+                    ParseErrorKind::DescriptionWithoutContext,
+                    "Description used without a migration, migration group, or function",
+                ),
+                _ => return self.leaf_only(directive),
+            };
+            return Err(self.without_context(directive, kind, message));
+        };
+
+        match name {
+            "|" => return Err(self.pipe_without_context(directive)),
+            "fsql:version" => self.fsql_version = directive.argument.clone(),
+            "endmigration" | "endgroup" | "endmacro" | "endfunction" => {
+                return Err(self.unexpected_end(directive, Some("migration group")));
+            }
+            "nuclear" => group.set_nuclear_true(),
+            "transaction" => group.set_transaction_true(),
+            "retries" => group.set_retries(self.retries(directive)?),
+            "tags" => self.add_tags(directive, |tag| group.add_tag(tag))?,
+            "version" => group.set_version(self.required_argument(
+                directive,
+                ParseErrorKind::MissingArgument("version".to_string()),
+                "Missing version",
+            )?),
+            "depends" => group.add_dependency(self.dependency(directive)?),
+            "description" => {
+                if let Some(description) = directive.argument.as_deref().filter(|d| !d.is_empty()) {
+                    group.add_description(description);
                 }
-            } else if let Some(macro_func) = current_macro.as_mut() {
-                macro_func.add_body(line);
-            } else if let Some(function) = current_function.as_mut() {
-                function.add_body(line);
-            } else {
-                return Err(ParseError {
-                    kind: ParseErrorKind::SqlWithoutContext,
-                    line: idx + 1,
-                    column: 1,
-                    message: "SQL used without a migration, macro, or function".to_string(),
-                });
             }
+            _ => return self.leaf_only(directive),
         }
+        Ok(())
+    }
 
-        if current_migration.is_some() {
-            return Err(ParseError {
-                kind: ParseErrorKind::MigrationNotClosed,
-                line: migration_opened_at,
-                column: 1,
-                message: format!("Migration not closed at line {migration_opened_at}"),
-            });
-        } else if current_macro.is_some() {
-            return Err(ParseError {
-                kind: ParseErrorKind::MacroNotClosed,
-                line: macro_opened_at,
-                column: 1,
-                message: format!("Macro not closed at line {macro_opened_at}"),
-            });
-        } else if current_function.is_some() {
-            return Err(ParseError {
-                kind: ParseErrorKind::FunctionNotClosed,
-                line: function_opened_at,
-                column: 1,
-                message: format!("Function not closed at line {function_opened_at}"),
-            });
-        } else if !previous_groups.is_empty() {
-            return Err(ParseError {
-                kind: ParseErrorKind::MigrationGroupNotClosed,
-                line: group_opened_at.last().copied().unwrap_or(1),
-                column: 1,
-                message: format!(
-                    "Migration group not closed, {} groups still open",
-                    group_opened_at.last().copied().unwrap_or(1)
-                ),
-            });
+    /// Error for a directive that only has a meaning inside a migration,
+    /// macro or function, found outside of them.
+    fn leaf_only(&self, directive: &DirectiveNode) -> Result<(), ParseError> {
+        let (kind, message) = match directive.name.as_str() {
+            "rollback" => (
+                ParseErrorKind::RollbackWithoutContext,
+                "Rollback used without a migration",
+            ),
+            "parameters" => (
+                ParseErrorKind::ParametersWithoutContext,
+                "Parameters used without a function",
+            ),
+            "returns" => (
+                ParseErrorKind::ReturnsWithoutContext,
+                "Returns used without a function",
+            ),
+            "language" => (
+                ParseErrorKind::LanguageWithoutContext,
+                "Language used without a function",
+            ),
+            "call" => (
+                ParseErrorKind::MacroCallWithoutContext,
+                "Macro call used without migration, macro or function",
+            ),
+            "call-func" => (
+                ParseErrorKind::FunctionCallWithoutContext,
+                "Function call used without migration, macro or function",
+            ),
+            _ => return Ok(()),
+        };
+        Err(self.without_context(directive, kind, message))
+    }
+
+    /// Applies a directive found inside a migration, macro or function.
+    fn leaf_directive(
+        &mut self,
+        leaf: &mut Leaf,
+        directive: &DirectiveNode,
+        report: &mut ParseReport,
+    ) -> Result<(), ParseError> {
+        if !self.check_known(directive, report)? {
+            // A comment, SQL as far as FSQL is concerned
+            leaf.add_sql(&directive.text, directive.span.clone());
+            return Ok(());
         }
 
+        match (directives::canonical(&directive.name), &mut *leaf) {
+            ("|", _) => return Err(self.pipe_without_context(directive)),
+            ("fsql:version", _) => self.fsql_version = directive.argument.clone(),
+            ("endmigration" | "endgroup" | "endmacro" | "endfunction", leaf) => {
+                return Err(self.unexpected_end(directive, Some(leaf.context())));
+            }
+            ("nuclear", Leaf::Migration { migration, .. }) => migration.set_nuclear_true(),
+            ("retries", Leaf::Migration { migration, .. }) => {
+                migration.set_retries(self.retries(directive)?)
+            }
+            ("version", Leaf::Migration { migration, .. }) => {
+                migration.set_version(self.required_argument(
+                    directive,
+                    ParseErrorKind::MissingArgument("version".to_string()),
+                    "Missing version",
+                )?)
+            }
+            ("rollback", Leaf::Migration { rollback, .. }) => *rollback = true,
+            ("tags", Leaf::Migration { migration, .. }) => {
+                self.add_tags(directive, |tag| migration.add_tag(tag))?
+            }
+            ("tags", Leaf::Function(function)) => {
+                self.add_tags(directive, |tag| function.add_tag(tag))?
+            }
+            ("parameters", Leaf::Function(_) | Leaf::Macro(_)) => {
+                let parameters = self.required_argument(
+                    directive,
+                    ParseErrorKind::MissingParameters,
+                    "Missing parameters",
+                )?;
+                let parsed = match leaf {
+                    Leaf::Function(function) => function.parse_arguments(parameters),
+                    Leaf::Macro(macro_func) => macro_func.parse_arguments(parameters),
+                    Leaf::Migration { .. } => Ok(()),
+                };
+                parsed.map_err(|kind| {
+                    self.error(
+                        kind,
+                        argument_span(directive),
+                        format!("Error parsing parameters '{parameters}'"),
+                    )
+                })?;
+            }
+            ("returns", Leaf::Function(function)) => {
+                let return_type = self.required_argument(
+                    directive,
+                    ParseErrorKind::MissingReturnType,
+                    "Missing return type in function",
+                )?;
+                function.set_return_type(return_type.to_string());
+            }
+            ("language", Leaf::Function(function)) => {
+                let language = self.required_argument(
+                    directive,
+                    ParseErrorKind::MissingLanguage,
+                    "Missing language in function",
+                )?;
+                function.set_language(language.to_string());
+                function.set_language_span(argument_span(directive));
+            }
+            ("depends", leaf) => leaf.add_dependency(self.dependency(directive)?),
+            (
+                "call",
+                Leaf::Migration {
+                    migration,
+                    rollback,
+                },
+            ) => {
+                let name = self.called_macro(directive)?;
+                let source = directive.argument.as_deref().unwrap_or_default();
+                let mut call = MacroCall::parse(source).map_err(|kind| {
+                    self.error(
+                        kind,
+                        argument_span(directive),
+                        format!("Invalid macro call '{source}'"),
+                    )
+                })?;
+                call.set_line(directive.line);
+                call.set_span(argument_span(directive));
+                migration.add_dependency(declared_by(Dependency::new_macro(name), directive));
+                migration.add_macro_call(call, *rollback);
+            }
+            ("call", leaf) => {
+                let name = self.called_macro(directive)?;
+                leaf.add_dependency(declared_by(Dependency::new_macro(name), directive))
+            }
+            ("call-func", leaf) => leaf.add_dependency(self.called_function(directive)?),
+            ("description", leaf) => {
+                if let Some(description) = directive.argument.as_deref().filter(|d| !d.is_empty()) {
+                    leaf.add_description(description);
+                }
+            }
+            ("nuclear", _) => {
+                return Err(self.without_context(
+                    directive,
+                    ParseErrorKind::NuclearWithoutContext,
+                    "Nuclear tag used without a migration or migration group",
+                ));
+            }
+            ("retries", _) => {
+                return Err(self.without_context(
+                    directive,
+                    ParseErrorKind::RetriesWithoutContext,
+                    "Retries used without a migration or migration group",
+                ));
+            }
+            ("transaction", _) => {
+                return Err(self.without_context(
+                    directive,
+                    ParseErrorKind::TransactionWithoutContext,
+                    "Transaction used without a migration group",
+                ));
+            }
+            ("version", _) => {
+                return Err(self.without_context(
+                    directive,
+                    ParseErrorKind::MissingArgument("version".to_string()),
+                    "Version used without a migration or migration group",
+                ));
+            }
+            ("tags", _) => {
+                return Err(self.without_context(
+                    directive,
+                    ParseErrorKind::TagsWithoutContext,
+                    "Tags used without a migration, migration group, or function",
+                ));
+            }
+            _ => return self.leaf_only(directive),
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> (MigrationFile, ParseReport) {
+        let mut file = MigrationFile::new("a.sql", "a.sql", content);
+        let report = file.parse_file_recovering();
+        (file, report)
+    }
+
+    #[test]
+    fn python_bodies_are_not_lexed_as_sql() {
+        let (file, report) = parse(
+            "-- +function: shout\n\
+             -- +language: plpython3u\n\
+             -- +parameters: s text\n\
+             -- +returns: text\n\
+             # don't shout twice\n\
+             return s.upper()\n\
+             -- +endfunction\n\
+             -- +migration: uses\n\
+             -- +call-func: shout\n\
+             SELECT shout('hi');\n\
+             -- +endmigration\n",
+        );
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert_eq!(file.functions.len(), 1);
+        assert_eq!(file.migrations.len(), 1);
+        assert!(file.functions[0].body().contains("# don't shout twice"));
+    }
+
+    #[test]
+    fn directives_inside_dollar_quotes_are_sql() {
+        let (file, report) = parse(
+            "-- +migration: a\n\
+             DO $$ BEGIN\n\
+             -- +endmigration\n\
+             END $$;\n\
+             -- +endmigration\n",
+        );
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert!(
+            file.migrations[0]
+                .sql()
+                .contains("-- +endmigration\nEND $$;")
+        );
+    }
+
+    #[test]
+    fn lowers_crlf_files_keeping_the_sql_as_written() {
+        let content = "-- +migration: a\r\n  SELECT 1;\r\n-- +rollback\r\n  SELECT 2;\r\n-- +endmigration\r\n";
+        let (file, report) = parse(content);
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        let migration = &file.migrations[0];
+        assert_eq!(migration.sql(), "  SELECT 1;\r\n");
+        assert_eq!(migration.sql_rollback(), "  SELECT 2;\r\n");
+        assert_eq!(&content[migration.sql_ranges()[0].clone()], migration.sql());
+        assert_eq!(
+            &content[migration.sql_rollback_ranges()[0].clone()],
+            migration.sql_rollback()
+        );
+    }
+
+    #[test]
+    fn sql_sections_keep_their_text_and_where_it_was_read() {
+        let content = "-- +macro: fill\n\
+                       -- +parameters: t\n\
+                       \x20   INSERT INTO {{t}} VALUES (1);\n\
+                       -- +endmacro\n\
+                       -- +function: f\n\
+                       -- +language: plpython3u\n\
+                       -- +returns: int\n\
+                       if True:\n\
+                       \x20   return 1\n\
+                       -- +endfunction\n\
+                       -- +migration: a\n\
+                       \x20 INSERT INTO t VALUES ('one\n\
+                       \x20   two');\n\
+                       COPY t FROM stdin;\n\
+                       \tx  \n\
+                       \\.\n\
+                       -- +rollback\n\
+                       \x20 DELETE FROM t;\n\
+                       -- +endmigration\n";
+        let (file, report) = parse(content);
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+
+        let text = |ranges: &[std::ops::Range<usize>]| -> String {
+            ranges.iter().map(|range| &content[range.clone()]).collect()
+        };
+        let migration = &file.migrations[0];
+        assert_eq!(
+            migration.sql(),
+            "  INSERT INTO t VALUES ('one\n    two');\nCOPY t FROM stdin;\n\tx  \n\\.\n"
+        );
+        assert_eq!(text(migration.sql_ranges()), migration.sql());
+        assert_eq!(migration.sql_rollback(), "  DELETE FROM t;\n");
+        assert_eq!(
+            text(migration.sql_rollback_ranges()),
+            migration.sql_rollback()
+        );
+
+        let function = &file.functions[0];
+        assert_eq!(function.body(), "if True:\n    return 1\n");
+        assert_eq!(text(function.body_ranges()), function.body());
+        assert_eq!(
+            text(file.macros[0].body_ranges()),
+            "    INSERT INTO {{t}} VALUES (1);\n"
+        );
+    }
+
+    #[test]
+    fn errors_point_at_the_argument_they_are_about() {
+        let content = "-- +migration: a\n-- +retries: many\nSELECT 1;\n-- +endmigration\n";
+        let (_, report) = parse(content);
+        let error = &report.errors[0];
+        assert!(matches!(error.kind, ParseErrorKind::InvalidRetries(_)));
+        assert_eq!((error.line, error.column), (2, 14));
+        let span = error.span.as_ref().unwrap();
+        assert_eq!(&content[span.range.clone()], "many");
+        assert!(!error.message.contains("line"));
+    }
+
+    #[test]
+    fn nodes_record_where_their_name_was_read() {
+        let content = "-- +group: g\n\
+                       -- +migration: m\n\
+                       -- +call: fill(1)\n\
+                       -- +endmigration\n\
+                       -- +endgroup\n\
+                       -- +macro: fill\n\
+                       -- +parameters: n\n\
+                       SELECT {{n}}, {{other(1)}};\n\
+                       -- +endmacro\n\
+                       -- +function: f\n\
+                       -- +language: plpgsql\n\
+                       -- +returns: int\n\
+                       BEGIN RETURN 1; END;\n\
+                       -- +endfunction\n";
+        let (file, report) = parse(content);
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+
+        let group = &file.migration_groups[0];
+        let migration = &group.migrations()[0];
+        let macro_func = &file.macros[0];
+        let function = &file.functions[0];
+        assert_eq!(&content[group.span().clone()], "g");
+        assert_eq!(&content[migration.span().clone()], "m");
+        assert_eq!(
+            &content[migration.macro_calls()[0].span().clone()],
+            "fill(1)"
+        );
+        assert_eq!(&content[macro_func.span().clone()], "fill");
+        assert_eq!(
+            &content[macro_func.macro_calls()[0].span().clone()],
+            "{{other(1)}}"
+        );
+        assert_eq!(&content[function.span().clone()], "f");
+        assert_eq!(
+            &content[function.language_span().unwrap().clone()],
+            "plpgsql"
+        );
+    }
+
+    #[test]
+    fn recovers_after_a_broken_block() {
+        let (file, report) = parse(
+            "-- +migration: broken\n\
+             -- +retries: many\n\
+             SELECT 1;\n\
+             -- +endmigration\n\
+             -- +migration: unclosed\n\
+             SELECT 2;\n\
+             -- +migration: after\n\
+             SELECT 3;\n\
+             -- +endmigration\n",
+        );
+        let kinds: Vec<&ParseErrorKind> = report.errors.iter().map(|error| &error.kind).collect();
+        assert!(matches!(
+            kinds.as_slice(),
+            [
+                ParseErrorKind::InvalidRetries(_),
+                ParseErrorKind::UnexpectedMigrationStart
+            ]
+        ));
+        let names: Vec<&str> = file.migrations.iter().map(|m| m.name()).collect();
+        assert_eq!(names, ["after"]);
+    }
+
+    #[test]
+    fn parse_file_returns_warnings_with_their_file() {
+        let mut file = MigrationFile::new(
+            "dir/a.sql",
+            "a.sql",
+            "-- +migration: a\n-- +frob\nSELECT 1;\n-- +endmigration\n",
+        );
+        let warnings = file.parse_file().unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(
+            warnings[0]
+                .to_string()
+                .starts_with("dir/a.sql:2: Unknown directive '-- +frob'")
+        );
+    }
+
+    #[test]
+    fn reads_the_deprecated_version_directive_with_a_warning() {
+        let (file, report) = parse("-- +sqlx:version: 0.1\n");
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert_eq!(file.fsql_version.as_deref(), Some("0.1"));
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].message.contains("deprecated"));
+    }
+}
@@ -1,8 +1,20 @@
-use std::collections::HashSet;
+use sha2::{Digest, Sha256};
+use std::{collections::HashSet, ops::Range};
 
 use ts_rs::TS;
 
-use crate::{models::migration_dependency::Dependency, parse_errors::ParseErrorKind};
+use crate::{
+    models::{
+        migration::{normalize_sql, push_range},
+        migration_dependency::Dependency,
+    },
+    parse_errors::ParseErrorKind,
+};
+
+/// Languages functions may be written in without extra configuration.
+pub const KNOWN_LANGUAGES: [&str; 4] = ["plpgsql", "sql", "plpython3u", "plv8"];
+
+const DEFAULT_LANGUAGE: &str = "plpgsql";
 
 #[derive(TS, Debug, Clone, PartialEq, Hash, Eq)]
 #[ts(export)]
@@ -10,12 +22,22 @@ pub enum FunctionTags {
     NoBoilerplate,
 }
 
+impl FunctionTags {
+    pub const ALL: [FunctionTags; 1] = [FunctionTags::NoBoilerplate];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FunctionTags::NoBoilerplate => "no_boilerplate",
+        }
+    }
+}
+
 impl TryFrom<String> for FunctionTags {
     type Error = ParseErrorKind;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
         match value.to_lowercase().as_str() {
-            "noboilerplate" => Ok(FunctionTags::NoBoilerplate),
+            "noboilerplate" | "no_boilerplate" => Ok(FunctionTags::NoBoilerplate),
             _ => Err(ParseErrorKind::UnknownTag(value)),
         }
     }
@@ -38,15 +60,23 @@ impl FuncArgument {
 #[ts(export)]
 pub struct Function {
     name: String,
+    full_path: Option<String>,
     arguments: Vec<FuncArgument>,
     return_type: Option<String>,
     tags: HashSet<FunctionTags>,
     description: String,
     body: String,
+    // Where `body` was read from in the file
+    body_ranges: Vec<Range<usize>>,
     parsed_body: String,
     language: Option<String>,
+    // Where the language was read from in the file
+    language_span: Option<Range<usize>>,
     complete: bool,
     dependencies: Vec<Dependency>,
+    line: usize,
+    // Where the name was read from in the file
+    span: Range<usize>,
 }
 
 impl Function {
@@ -65,6 +95,21 @@ impl Function {
         &self.parsed_body
     }
 
+    /// Hex encoded SHA-256 of the normalized `CREATE FUNCTION` statement, the
+    /// function is created again whenever it changes.
+    pub fn hash(&self) -> String {
+        Sha256::digest(self.normalized_content().as_bytes())
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    /// The normalized `CREATE FUNCTION` statement, stored when the function
+    /// is created.
+    pub fn normalized_content(&self) -> String {
+        normalize_sql(&self.parsed_body)
+    }
+
     pub fn is_complete(&self) -> bool {
         self.complete
     }
@@ -85,8 +130,15 @@ impl Function {
         &self.body
     }
 
+    /// Byte ranges of the file the body was read from, in order.
+    pub fn body_ranges(&self) -> &[Range<usize>] {
+        &self.body_ranges
+    }
+
     pub fn language(&self) -> String {
-        self.language.clone().unwrap_or("plpgsql".to_string())
+        self.language
+            .clone()
+            .unwrap_or(DEFAULT_LANGUAGE.to_string())
     }
 
     pub fn tags(&self) -> &HashSet<FunctionTags> {
@@ -97,6 +149,30 @@ impl Function {
         &self.dependencies
     }
 
+    pub fn path(&self) -> &str {
+        self.full_path.as_deref().unwrap_or_else(|| &self.name)
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn span(&self) -> &Range<usize> {
+        &self.span
+    }
+
+    pub fn set_full_path(&mut self, full_path: impl Into<String>) {
+        self.full_path = Some(full_path.into());
+    }
+
+    pub fn set_line(&mut self, line: usize) {
+        self.line = line;
+    }
+
+    pub fn set_span(&mut self, span: Range<usize>) {
+        self.span = span;
+    }
+
     pub fn add_dependency(&mut self, dependency: Dependency) {
         self.dependencies.push(dependency);
     }
@@ -117,13 +193,20 @@ impl Function {
         let args = args.into();
         let arguments: Vec<&str> = args.split(',').map(|s| s.trim()).collect();
         for arg in arguments {
-            let parts: Vec<&str> = arg.split(':').map(|s| s.trim()).collect();
-            if parts.len() == 2 {
-                let name = parts[0].to_string();
-                let type_name = parts[1].to_string();
-                self.arguments.push(FuncArgument::new(name, type_name));
-            } else {
-                return Err(ParseErrorKind::MissingArgumentType);
+            // Both `name: TYPE` and the SQL style `name TYPE` are accepted
+            let parts = arg
+                .split_once(':')
+                .or_else(|| arg.split_once(char::is_whitespace));
+            match parts {
+                Some((name, type_name))
+                    if !name.trim().is_empty() && !type_name.trim().is_empty() =>
+                {
+                    self.arguments.push(FuncArgument::new(
+                        name.trim().to_string(),
+                        type_name.trim().to_string(),
+                    ));
+                }
+                _ => return Err(ParseErrorKind::MissingArgumentType),
             }
         }
         Ok(())
@@ -150,16 +233,15 @@ impl Function {
         }
     }
 
-    pub fn add_body(&mut self, body: impl Into<String>) {
+    /// Appends a line of the body as written, with its line break, read
+    /// from `range` of the file.
+    pub fn add_body(&mut self, body: &str, range: Range<usize>) {
         if self.complete {
             panic!("Cannot add body to a complete function. Use put_boilerplate() instead.");
         }
 
-        if self.body.is_empty() {
-            self.body = body.into();
-        } else {
-            self.body.push_str(&format!("\n{}", body.into()));
-        }
+        self.body.push_str(body);
+        push_range(&mut self.body_ranges, range);
     }
 
     pub fn set_language(&mut self, language: String) {
@@ -167,36 +249,228 @@ impl Function {
             panic!("Cannot set language on a complete function. Use put_boilerplate() instead.");
         }
 
-        self.language = Some(language);
+        self.language = Some(language.to_lowercase());
+    }
+
+    pub fn set_language_span(&mut self, span: Range<usize>) {
+        self.language_span = Some(span);
+    }
+
+    pub fn language_span(&self) -> Option<&Range<usize>> {
+        self.language_span.as_ref()
     }
 
-    pub fn put_boilerplate(&mut self) {
-        if !self.tags.contains(&FunctionTags::NoBoilerplate) || self.complete {
-            // remove empty lines from body
-            self.parsed_body = self
-                .body
-                .lines()
-                .filter(|line| !line.trim().is_empty())
-                .map(|line| line.trim())
-                .collect::<Vec<&str>>()
-                .join("\n");
+    /// Fails unless the language is one of `KNOWN_LANGUAGES` or `extra`.
+    pub fn check_language(&self, extra: &[String]) -> Result<(), ParseErrorKind> {
+        let language = self.language();
+        if KNOWN_LANGUAGES.contains(&language.as_str())
+            || extra
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(&language))
+        {
+            Ok(())
+        } else {
+            Err(ParseErrorKind::UnknownLanguage(language))
+        }
+    }
 
-            let language = &self.language.as_ref();
-            let return_type = &self.return_type.as_ref();
-            self.parsed_body = format!(
+    /// Wraps the body in the block its language needs. PL/pgSQL bodies get
+    /// `BEGIN ... END;` unless they already start one, while `sql`,
+    /// `plpython3u` and `plv8` bodies are used as written and must not have
+    /// one.
+    fn shape_body(&self, body: &str) -> Result<String, ParseErrorKind> {
+        let first_word = body
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_uppercase();
+        let has_block = first_word == "BEGIN" || first_word == "DECLARE";
+
+        match self.language().as_str() {
+            "plpgsql" if has_block => {
+                if first_word == "DECLARE" && !body.to_uppercase().contains("BEGIN") {
+                    Err(ParseErrorKind::InvalidFunctionBody(
+                        "a DECLARE section must be followed by BEGIN ... END".to_string(),
+                    ))
+                } else {
+                    Ok(body.to_string())
+                }
+            }
+            "plpgsql" => Ok(format!("BEGIN\n{body}\nEND;")),
+            language @ ("sql" | "plpython3u" | "plv8") if has_block => {
+                Err(ParseErrorKind::InvalidFunctionBody(format!(
+                    "{language} functions cannot have a {first_word} block, it is only valid in plpgsql"
+                )))
+            }
+            _ => Ok(body.to_string()),
+        }
+    }
+
+    /// The parameter list as it appears in `CREATE FUNCTION`.
+    pub fn parameter_list(&self) -> String {
+        self.arguments
+            .iter()
+            .map(|arg| format!("{} {}", arg.name, arg.type_name))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    /// Fills `{N}`, `{P}`, `{R}` and `{L}` in a hand-written `no_boilerplate`
+    /// body with the name, parameters, return type and language. Placeholders
+    /// missing from the body are reported in `warnings`.
+    fn fill_placeholders(&self, body: &str, warnings: &mut Vec<String>) -> String {
+        let mut placeholders = vec![
+            ("{N}", self.name.clone()),
+            ("{P}", self.parameter_list()),
+            ("{R}", self.return_type()),
+        ];
+        if self.language.is_some() || body.contains("{L}") {
+            placeholders.push(("{L}", self.language()));
+        }
+
+        let mut result = body.to_string();
+        for (placeholder, value) in placeholders {
+            if !result.contains(placeholder) {
+                warnings.push(format!(
+                    "Placeholder '{placeholder}' is not used in the body of function '{}', '{value}' will not be applied.",
+                    self.name
+                ));
+            }
+            result = result.replace(placeholder, &value);
+        }
+        result
+    }
+
+    /// `body` without its leading and trailing blank lines.
+    fn strip_blank_lines(body: &str) -> &str {
+        let start = body
+            .split_inclusive('\n')
+            .take_while(|line| line.trim().is_empty())
+            .map(str::len)
+            .sum::<usize>();
+        body[start..].trim_end()
+    }
+
+    /// Builds the final `CREATE FUNCTION` statement and completes the
+    /// function. Returns the warnings found on the way.
+    pub fn put_boilerplate(&mut self) -> Result<Vec<String>, ParseErrorKind> {
+        // Kept as written, indentation matters to languages like plpython3u.
+        // Only the blank lines around it go, the template has its own breaks.
+        let body = Self::strip_blank_lines(&self.body).to_string();
+
+        let mut warnings = Vec::new();
+        self.parsed_body = if self.tags.contains(&FunctionTags::NoBoilerplate) {
+            self.fill_placeholders(&body, &mut warnings)
+        } else {
+            format!(
                 "CREATE OR REPLACE FUNCTION {}({}) RETURNS {} AS $$\n{}\n$$ LANGUAGE {};",
                 self.name,
-                self.arguments
-                    .iter()
-                    .map(|arg| format!("{} {}", arg.name, arg.type_name))
-                    .collect::<Vec<String>>()
-                    .join(", "),
-                return_type.unwrap_or(&"VOID".to_string()),
-                self.parsed_body,
-                language.unwrap_or(&"plpgsql".to_string())
-            );
-        } else if !self.complete {
-            self.complete = true;
+                self.parameter_list(),
+                self.return_type(),
+                self.shape_body(&body)?,
+                self.language()
+            )
+        };
+        self.complete = true;
+        Ok(warnings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn function(tags: &[&str], arguments: &str, body: &str) -> Function {
+        let mut function = Function::new("add");
+        for tag in tags {
+            function.add_tag(*tag).unwrap();
         }
+        function.parse_arguments(arguments).unwrap();
+        function.set_return_type("INT".to_string());
+        function.add_body(body, 0..body.len());
+        function
+    }
+
+    #[test]
+    fn fills_placeholders_in_no_boilerplate_bodies() {
+        let mut function = function(
+            &["no_boilerplate"],
+            "a: INT, b INT",
+            "CREATE FUNCTION {N}({P}) RETURNS {R} AS $$ SELECT a + b $$ LANGUAGE {L};\n",
+        );
+        function.set_language("SQL".to_string());
+        let warnings = function.put_boilerplate().unwrap();
+
+        assert!(warnings.is_empty());
+        assert!(function.is_complete());
+        assert_eq!(
+            function.parsed_body(),
+            "CREATE FUNCTION add(a INT, b INT) RETURNS INT AS $$ SELECT a + b $$ LANGUAGE sql;"
+        );
+    }
+
+    #[test]
+    fn warns_about_placeholders_the_body_leaves_out() {
+        let mut function = function(
+            &["no_boilerplate"],
+            "a: INT",
+            "CREATE FUNCTION {N}(a INT) RETURNS INT AS $$ SELECT a $$ LANGUAGE sql;\n",
+        );
+        let warnings = function.put_boilerplate().unwrap();
+
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].starts_with("Placeholder '{P}' is not used"));
+        assert!(warnings[1].contains("'{R}'") && warnings[1].contains("'INT' will not be applied"));
+    }
+
+    #[test]
+    fn language_placeholder_is_only_expected_when_a_language_is_set() {
+        let body = "CREATE FUNCTION {N}({P}) RETURNS {R} AS $$ SELECT 1 $$ LANGUAGE sql;\n";
+        let mut unset = function(&["no_boilerplate"], "a: INT", body);
+        assert!(unset.put_boilerplate().unwrap().is_empty());
+
+        let mut set = function(&["no_boilerplate"], "a: INT", body);
+        set.set_language("sql".to_string());
+        let warnings = set.put_boilerplate().unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("Placeholder '{L}' is not used"));
+    }
+
+    #[test]
+    fn boilerplate_wraps_plpgsql_bodies_in_a_block() {
+        let mut function = function(&[], "a: INT", "\n  RETURN a;\n\n");
+        assert!(function.put_boilerplate().unwrap().is_empty());
+        assert_eq!(
+            function.parsed_body(),
+            "CREATE OR REPLACE FUNCTION add(a INT) RETURNS INT AS $$\nBEGIN\n  RETURN a;\nEND;\n$$ LANGUAGE plpgsql;"
+        );
+    }
+
+    #[test]
+    fn blocks_are_rejected_outside_plpgsql() {
+        let mut function = function(&[], "a: INT", "BEGIN\nSELECT a;\nEND;\n");
+        function.set_language("sql".to_string());
+        assert!(matches!(
+            function.put_boilerplate(),
+            Err(ParseErrorKind::InvalidFunctionBody(_))
+        ));
+    }
+
+    #[test]
+    fn languages_are_known_or_configured() {
+        let mut function = Function::new("f");
+        assert!(function.check_language(&[]).is_ok());
+        assert_eq!(function.language(), "plpgsql");
+
+        function.set_language("PLV8".to_string());
+        assert_eq!(function.language(), "plv8");
+        assert!(function.check_language(&[]).is_ok());
+
+        function.set_language("plperl".to_string());
+        assert!(matches!(
+            function.check_language(&[]),
+            Err(ParseErrorKind::UnknownLanguage(language)) if language == "plperl"
+        ));
+        assert!(function.check_language(&["PLPerl".to_string()]).is_ok());
     }
 }
@@ -0,0 +1,188 @@
+use std::{collections::HashMap, ops::Range};
+
+use ts_rs::TS;
+
+use crate::parse_errors::ParseErrorKind;
+
+/// A `-- +call: name(args...)` site inside a migration, or a
+/// `{{name(args...)}}` placeholder inside a macro body.
+#[derive(TS, Debug, Clone, Default)]
+#[ts(export)]
+pub struct MacroCall {
+    path: String,
+    source: String,
+    arguments: Vec<String>,
+    raw_arguments: Vec<String>,
+    line: usize,
+    // Where the call was read from in the file
+    span: Range<usize>,
+    rollback: bool,
+    sql_line: usize,
+}
+
+impl MacroCall {
+    /// Parses `name(arg, 'quoted, arg', ...)`. Quoted arguments are unquoted,
+    /// everything else is kept as written.
+    pub fn parse(call: impl Into<String>) -> Result<Self, ParseErrorKind> {
+        let call = call.into();
+        let call = call.trim();
+
+        let (path, raw_arguments) = match call.find('(') {
+            Some(start) => {
+                let arguments = call[start + 1..]
+                    .trim_end()
+                    .strip_suffix(')')
+                    .ok_or(ParseErrorKind::InvalidArgumentFormat)?;
+                (&call[..start], Self::split_arguments(arguments)?)
+            }
+            None => (call, Vec::new()),
+        };
+        let arguments = raw_arguments
+            .iter()
+            .map(|argument| Self::unquote(argument))
+            .collect::<Result<Vec<String>, ParseErrorKind>>()?;
+
+        let path = path.trim();
+        if path.is_empty() {
+            return Err(ParseErrorKind::MissingMacroName);
+        }
+
+        Ok(Self {
+            path: path.to_string(),
+            source: call.to_string(),
+            arguments,
+            raw_arguments,
+            ..Default::default()
+        })
+    }
+
+    fn split_arguments(arguments: &str) -> Result<Vec<String>, ParseErrorKind> {
+        let mut result = Vec::new();
+        let mut buffer = String::new();
+        let mut paren_level = 0;
+        let mut in_quotes = false;
+        let mut chars = arguments.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '\'' if in_quotes && chars.peek() == Some(&'\'') => {
+                    // '' is an escaped quote inside a literal
+                    buffer.push_str("''");
+                    chars.next();
+                }
+                '\'' => {
+                    in_quotes = !in_quotes;
+                    buffer.push(c);
+                }
+                '(' if !in_quotes => {
+                    paren_level += 1;
+                    buffer.push(c);
+                }
+                ')' if !in_quotes => {
+                    if paren_level == 0 {
+                        return Err(ParseErrorKind::InvalidArgumentFormat);
+                    }
+                    paren_level -= 1;
+                    buffer.push(c);
+                }
+                ',' if !in_quotes && paren_level == 0 => {
+                    result.push(buffer.trim().to_string());
+                    buffer.clear();
+                }
+                _ => buffer.push(c),
+            }
+        }
+
+        if in_quotes || paren_level != 0 {
+            return Err(ParseErrorKind::InvalidArgumentFormat);
+        }
+        if !buffer.trim().is_empty() || !result.is_empty() {
+            result.push(buffer.trim().to_string());
+        }
+        Ok(result)
+    }
+
+    fn unquote(argument: &str) -> Result<String, ParseErrorKind> {
+        if argument.is_empty() {
+            return Err(ParseErrorKind::InvalidArgumentFormat);
+        }
+        match argument
+            .strip_prefix('\'')
+            .and_then(|argument| argument.strip_suffix('\''))
+        {
+            Some(literal) => Ok(literal.replace("''", "'")),
+            None => Ok(argument.to_string()),
+        }
+    }
+
+    /// Values of the call when made from a macro body. An unquoted argument
+    /// naming a parameter of the calling macro passes that parameter on, and
+    /// `...name` spreads all of its values.
+    pub fn bind_outer(&self, outer: &HashMap<String, Vec<String>>) -> Vec<String> {
+        let mut values = Vec::with_capacity(self.arguments.len());
+        for (raw, argument) in self.raw_arguments.iter().zip(&self.arguments) {
+            if let Some(name) = raw.strip_prefix("...")
+                && let Some(outer_values) = outer.get(name.trim())
+            {
+                values.extend(outer_values.iter().cloned());
+            } else if let Some(outer_values) = outer.get(raw.as_str()) {
+                values.push(outer_values.join(", "));
+            } else {
+                values.push(argument.clone());
+            }
+        }
+        values
+    }
+
+    /// Names of the calling macro's parameters this call passes on.
+    pub fn referenced_names(&self) -> impl Iterator<Item = &str> {
+        self.raw_arguments
+            .iter()
+            .map(|raw| raw.strip_prefix("...").unwrap_or(raw).trim())
+            .filter(|raw| !raw.starts_with('\''))
+    }
+
+    pub fn set_line(&mut self, line: usize) {
+        self.line = line;
+    }
+
+    pub fn set_span(&mut self, span: Range<usize>) {
+        self.span = span;
+    }
+
+    /// Places the call after the first `sql_line` lines of the migration SQL,
+    /// or of its rollback section.
+    pub fn set_position(&mut self, rollback: bool, sql_line: usize) {
+        self.rollback = rollback;
+        self.sql_line = sql_line;
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The call as written, without the `-- +call:` prefix or `{{ }}`.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn arguments(&self) -> &[String] {
+        &self.arguments
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn span(&self) -> &Range<usize> {
+        &self.span
+    }
+
+    pub fn is_rollback(&self) -> bool {
+        self.rollback
+    }
+
+    pub fn sql_line(&self) -> usize {
+        self.sql_line
+    }
+}
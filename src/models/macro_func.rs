@@ -1,8 +1,26 @@
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+};
 
 use ts_rs::TS;
 
-use crate::{models::migration_dependency::Dependency, parse_errors::ParseErrorKind};
+use crate::{
+    models::{macro_call::MacroCall, migration::push_range, migration_dependency::Dependency},
+    parse_errors::ParseErrorKind,
+};
+
+/// The inner text of a `{{name(args...)}}` placeholder, which calls another
+/// macro instead of referencing a parameter.
+fn nested_call(placeholder: &str) -> Option<&str> {
+    let (name, _) = placeholder.split_once('(')?;
+    let name = name.trim();
+    (!name.is_empty()
+        && !name.starts_with("...")
+        && !name.contains(char::is_whitespace)
+        && placeholder.ends_with(')'))
+    .then_some(placeholder)
+}
 
 #[derive(TS, Debug, Clone)]
 #[ts(export)]
@@ -16,12 +34,19 @@ pub enum MacroArgument {
 #[ts(export)]
 pub struct MacroFunc {
     name: String,
+    full_path: Option<String>,
     arguments: Vec<MacroArgument>,
     description: String,
     body: String,
+    // Where `body` was read from in the file
+    body_ranges: Vec<Range<usize>>,
     parsed_body: String,
     used_arguments: HashSet<String>,
+    macro_calls: Vec<MacroCall>,
     dependencies: Vec<Dependency>,
+    line: usize,
+    // Where the name was read from in the file
+    span: Range<usize>,
 }
 
 impl MacroFunc {
@@ -32,11 +57,19 @@ impl MacroFunc {
         }
     }
 
-    pub fn add_body(&mut self, body: impl Into<String>) {
-        if self.body.is_empty() {
-            self.body = body.into();
+    /// Appends a line of the body as written, with its line break, read
+    /// from `range` of the file.
+    pub fn add_body(&mut self, body: &str, range: Range<usize>) {
+        self.body.push_str(body);
+        push_range(&mut self.body_ranges, range);
+    }
+
+    pub fn add_description(&mut self, description: impl Into<String>) {
+        let description = description.into();
+        if self.description.is_empty() {
+            self.description = description;
         } else {
-            self.body.push_str(&format!("\n{}", body.into()));
+            self.description.push_str(&format!("\n{description}"));
         }
     }
 
@@ -48,6 +81,18 @@ impl MacroFunc {
         self.dependencies.push(dependency);
     }
 
+    pub fn set_full_path(&mut self, full_path: impl Into<String>) {
+        self.full_path = Some(full_path.into());
+    }
+
+    pub fn set_line(&mut self, line: usize) {
+        self.line = line;
+    }
+
+    pub fn set_span(&mut self, span: Range<usize>) {
+        self.span = span;
+    }
+
     pub fn parse_arguments(&mut self, args: impl Into<String>) -> Result<(), ParseErrorKind> {
         let args = args.into();
         let mut arguments = Vec::new();
@@ -121,17 +166,17 @@ impl MacroFunc {
         Ok(())
     }
 
-    pub fn log_if_arguments_are_unused(&self) {
-        for arg in &self.arguments {
-            let name = match arg {
+    /// Parameters the body never uses.
+    pub fn unused_arguments(&self) -> Vec<&str> {
+        self.arguments
+            .iter()
+            .map(|arg| match arg {
                 MacroArgument::AsIs(name)
                 | MacroArgument::Spread(name)
-                | MacroArgument::SpreadJoinedBy(name, _) => name,
-            };
-            if !self.used_arguments.contains(name) {
-                eprintln!("Warning: Argument '{name}' is defined but not used in the macro body.");
-            }
-        }
+                | MacroArgument::SpreadJoinedBy(name, _) => name.as_str(),
+            })
+            .filter(|name| !self.used_arguments.contains(*name))
+            .collect()
     }
 
     pub fn name(&self) -> &str {
@@ -150,21 +195,98 @@ impl MacroFunc {
         &self.parsed_body
     }
 
+    /// Byte ranges of the file the body was read from, in order.
+    pub fn body_ranges(&self) -> &[Range<usize>] {
+        &self.body_ranges
+    }
+
     pub fn used_arguments(&self) -> &HashSet<String> {
         &self.used_arguments
     }
 
-    pub fn parse_body(&mut self) {
+    pub fn path(&self) -> &str {
+        self.full_path.as_deref().unwrap_or_else(|| &self.name)
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn span(&self) -> &Range<usize> {
+        &self.span
+    }
+
+    pub fn parse_body(&mut self) -> Result<(), ParseErrorKind> {
         self.parsed_body = self.body.clone();
         self.parse_used_arguments();
+        self.parse_macro_calls()?;
+        Ok(())
+    }
 
-        self.parsed_body = self
-            .parsed_body
-            .lines()
-            .filter(|line| !line.trim().is_empty())
-            .map(|line| line.trim())
-            .collect::<Vec<&str>>()
-            .join("\n");
+    /// Collects the `{{name(args...)}}` placeholders of the body as calls and
+    /// dependencies on the macros they name.
+    fn parse_macro_calls(&mut self) -> Result<(), ParseErrorKind> {
+        let mut calls = Vec::new();
+        let mut remaining = self.parsed_body.as_str();
+
+        while let Some(start) = remaining.find("{{") {
+            let placeholder_start = self.parsed_body.len() - remaining.len() + start;
+            remaining = &remaining[start + 2..];
+            let Some(end) = remaining.find("}}") else {
+                break;
+            };
+            if let Some(call) = nested_call(remaining[..end].trim()) {
+                let mut call = MacroCall::parse(call)?;
+                let placeholder_end = self.parsed_body.len() - remaining.len() + end + 2;
+                call.set_line(self.line);
+                call.set_span(self.source_range(placeholder_start..placeholder_end));
+                calls.push(call);
+            }
+            remaining = &remaining[end + 2..];
+        }
+
+        for call in calls {
+            let names: Vec<String> = call.referenced_names().map(str::to_string).collect();
+            for name in names {
+                if self.has_argument(&name) {
+                    self.used_arguments.insert(name);
+                }
+            }
+            let mut dependency = Dependency::new_macro(call.path());
+            dependency.set_line(call.line());
+            dependency.set_span(call.span().clone());
+            self.dependencies.push(dependency);
+            self.macro_calls.push(call);
+        }
+        Ok(())
+    }
+
+    /// Where `range` of the body was read from in the file, or the name of
+    /// the macro when the body does not map back to it.
+    fn source_range(&self, range: Range<usize>) -> Range<usize> {
+        let mut body_start = 0;
+        for source in &self.body_ranges {
+            let body_end = body_start + source.len();
+            if (body_start..body_end).contains(&range.start) && range.end <= body_end {
+                let start = source.start + range.start - body_start;
+                return start..start + range.len();
+            }
+            body_start = body_end;
+        }
+        self.span.clone()
+    }
+
+    fn has_argument(&self, name: &str) -> bool {
+        self.arguments.iter().any(|arg| match arg {
+            MacroArgument::AsIs(arg_name)
+            | MacroArgument::Spread(arg_name)
+            | MacroArgument::SpreadJoinedBy(arg_name, _) => arg_name == name,
+        })
+    }
+
+    /// Calls to other macros made from the body, in order.
+    pub fn macro_calls(&self) -> &[MacroCall] {
+        &self.macro_calls
     }
 
     pub fn parse_used_arguments(&mut self) {
@@ -176,8 +298,10 @@ impl MacroFunc {
 
             if let Some(end) = remaining.find("}}") {
                 let arg = remaining[..end].trim();
-                if !arg.is_empty() {
+                if !arg.is_empty() && nested_call(arg).is_none() {
                     if let Some(arg) = arg.strip_prefix("...") {
+                        // `{{...name, 'joiner'}}` only uses `name`
+                        let arg = arg.split_once(',').map_or(arg, |(name, _)| name);
                         let arg_name = arg.trim().to_string();
                         if let Some(joiner_start) = arg_name.find('(') {
                             let (name, joiner) = arg_name.split_at(joiner_start);
@@ -199,11 +323,132 @@ impl MacroFunc {
         }
     }
 
-    pub fn render_body(
+    /// Binds the values of a call to the parameters, in order. A spread
+    /// parameter takes every value the parameters after it do not need.
+    pub fn bind_arguments(
+        &self,
+        values: &[String],
+    ) -> Result<HashMap<String, Vec<String>>, ParseErrorKind> {
+        let mut bound = HashMap::new();
+        let mut position = 0;
+
+        for (index, arg) in self.arguments.iter().enumerate() {
+            match arg {
+                MacroArgument::AsIs(name) => {
+                    let value = values
+                        .get(position)
+                        .ok_or_else(|| ParseErrorKind::MissingArgument(name.clone()))?;
+                    bound.insert(name.clone(), vec![value.clone()]);
+                    position += 1;
+                }
+                MacroArgument::Spread(name) | MacroArgument::SpreadJoinedBy(name, _) => {
+                    let needed_after = self.arguments[index + 1..]
+                        .iter()
+                        .filter(|arg| matches!(arg, MacroArgument::AsIs(_)))
+                        .count();
+                    let end = values.len().saturating_sub(needed_after).max(position);
+                    bound.insert(name.clone(), values[position..end].to_vec());
+                    position = end;
+                }
+            }
+        }
+
+        match values.get(position) {
+            Some(extra) => Err(ParseErrorKind::UnexpectedArgument(extra.clone())),
+            None => Ok(bound),
+        }
+    }
+
+    /// Turns a joiner as written in FSQL, like `', '` or `',\n    '`, into
+    /// the text it stands for.
+    fn unescape_joiner(joiner: &str) -> String {
+        let joiner = joiner.trim();
+        let joiner = joiner
+            .strip_prefix('\'')
+            .and_then(|joiner| joiner.strip_suffix('\''))
+            .unwrap_or(joiner);
+        joiner
+            .replace("\\n", "\n")
+            .replace("\\t", "\t")
+            .replace("''", "'")
+    }
+
+    /// Replaces `{{...name, 'joiner'}}`, where the joiner is given at the use
+    /// site instead of in the parameters.
+    fn render_inline_joiners(
         &self,
-        arg_values: &std::collections::HashMap<String, Vec<String>>,
+        body: &str,
+        arg_values: &HashMap<String, Vec<String>>,
     ) -> String {
-        let mut result = self.parsed_body.clone();
+        let mut result = String::with_capacity(body.len());
+        let mut remaining = body;
+
+        while let Some(start) = remaining.find("{{...") {
+            result.push_str(&remaining[..start]);
+            let placeholder = &remaining[start..];
+            let Some(end) = placeholder.find("}}") else {
+                break;
+            };
+
+            let inner = &placeholder[5..end];
+            match inner
+                .split_once(',')
+                .and_then(|(name, joiner)| Some((arg_values.get(name.trim())?, joiner)))
+            {
+                Some((values, joiner)) => {
+                    result.push_str(&values.join(&Self::unescape_joiner(joiner)));
+                }
+                None => result.push_str(&placeholder[..end + 2]),
+            }
+            remaining = &placeholder[end + 2..];
+        }
+
+        result.push_str(remaining);
+        result
+    }
+
+    pub fn render_body(&self, arg_values: &HashMap<String, Vec<String>>) -> String {
+        self.render_text(&self.parsed_body, arg_values)
+    }
+
+    /// Renders the body, replacing each nested `{{name(args...)}}` call with
+    /// what `expand` returns for it.
+    pub fn render_body_with<E>(
+        &self,
+        arg_values: &HashMap<String, Vec<String>>,
+        mut expand: impl FnMut(&MacroCall) -> Result<String, E>,
+    ) -> Result<String, E> {
+        let mut result = String::with_capacity(self.parsed_body.len());
+        let mut calls = self.macro_calls.iter();
+        let mut remaining = self.parsed_body.as_str();
+        let mut text_start = 0;
+        let mut offset = 0;
+
+        while let Some(start) = remaining.find("{{") {
+            let Some(end) = remaining[start..].find("}}").map(|end| start + end + 2) else {
+                break;
+            };
+            if nested_call(remaining[start + 2..end - 2].trim()).is_some()
+                && let Some(call) = calls.next()
+            {
+                let text = &self.parsed_body[text_start..offset + start];
+                result.push_str(&self.render_text(text, arg_values));
+                // The body ends with the line break of its last line, the placeholder is inline
+                let body = expand(call)?;
+                let body = body.strip_suffix('\n').unwrap_or(&body);
+                result.push_str(body.strip_suffix('\r').unwrap_or(body));
+                text_start = offset + end;
+            }
+            offset += end;
+            remaining = &remaining[end..];
+        }
+
+        result.push_str(&self.render_text(&self.parsed_body[text_start..], arg_values));
+        Ok(result)
+    }
+
+    fn render_text(&self, text: &str, arg_values: &HashMap<String, Vec<String>>) -> String {
+        let mut result = text.to_string();
 
         for arg in &self.arguments {
             match arg {
@@ -225,13 +470,87 @@ impl MacroFunc {
                 MacroArgument::SpreadJoinedBy(name, joiner) => {
                     if let Some(values) = arg_values.get(name) {
                         // Join all values with the specified joiner
-                        let joined = values.join(joiner);
+                        let joined = values.join(&Self::unescape_joiner(joiner));
                         result = result.replace(&format!("{{{{...{name}}}}}"), &joined);
                     }
                 }
             }
         }
 
-        result
+        self.render_inline_joiners(&result, arg_values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn macro_func(parameters: &str, body: &str) -> MacroFunc {
+        let mut macro_func = MacroFunc::new("m");
+        macro_func.parse_arguments(parameters).unwrap();
+        macro_func.add_body(body, 0..body.len());
+        macro_func.parse_body().unwrap();
+        macro_func
+    }
+
+    fn values(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn spreads_take_what_the_parameters_after_them_leave() {
+        let macro_func = macro_func("first, ...rest, last", "");
+        let bound = macro_func
+            .bind_arguments(&values(&["a", "b", "c", "d"]))
+            .unwrap();
+        assert_eq!(bound["first"], ["a"]);
+        assert_eq!(bound["rest"], ["b", "c"]);
+        assert_eq!(bound["last"], ["d"]);
+
+        let bound = macro_func.bind_arguments(&values(&["a", "d"])).unwrap();
+        assert!(bound["rest"].is_empty());
+    }
+
+    #[test]
+    fn binding_checks_the_number_of_values() {
+        let macro_func = macro_func("a, b", "");
+        assert!(matches!(
+            macro_func.bind_arguments(&values(&["1"])),
+            Err(ParseErrorKind::MissingArgument(name)) if name == "b"
+        ));
+        assert!(matches!(
+            macro_func.bind_arguments(&values(&["1", "2", "3"])),
+            Err(ParseErrorKind::UnexpectedArgument(value)) if value == "3"
+        ));
+    }
+
+    #[test]
+    fn renders_values_and_joined_spreads() {
+        let macro_func = macro_func(
+            "table, ...columns",
+            "CREATE TABLE {{table}} (\n    {{...columns, ',\\n    '}}\n);\n",
+        );
+        let bound = macro_func
+            .bind_arguments(&values(&["t", "id INT", "name TEXT"]))
+            .unwrap();
+        assert_eq!(
+            macro_func.render_body(&bound),
+            "CREATE TABLE t (\n    id INT,\n    name TEXT\n);\n"
+        );
+    }
+
+    #[test]
+    fn spreads_join_with_a_comma_unless_told_otherwise() {
+        let plain = macro_func("...v", "{{...v}}");
+        let joined = macro_func("...v(' OR ')", "{{...v}}");
+        let bound = plain.bind_arguments(&values(&["a", "b"])).unwrap();
+        assert_eq!(plain.render_body(&bound), "a, b");
+        assert_eq!(joined.render_body(&bound), "a OR b");
+    }
+
+    #[test]
+    fn reports_unused_parameters() {
+        let macro_func = macro_func("used, unused", "SELECT {{used}};");
+        assert_eq!(macro_func.unused_arguments(), ["unused"]);
     }
 }
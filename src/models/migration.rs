@@ -1,11 +1,98 @@
-use std::collections::HashSet;
+use sha2::{Digest, Sha256};
+use std::{collections::HashSet, ops::Range};
 use ts_rs::TS;
 
 use crate::{
-    models::{migration_dependency::Dependency, migration_tags::MigrationTags},
+    lexer::{self, TokenKind},
+    models::{
+        macro_call::MacroCall, migration_dependency::Dependency, migration_tags::MigrationTags,
+    },
     parse_errors::ParseErrorKind,
 };
 
+/// Trims every line, collapses runs of whitespace and drops empty lines, so
+/// re-indenting SQL does not change its hash. Strings, dollar quotes and
+/// quoted identifiers are kept as written, whitespace inside them matters.
+pub fn normalize_sql(sql: &str) -> String {
+    let mut normalized = String::with_capacity(sql.len());
+    // Whitespace skipped since the last text kept, a line break wins over a space
+    let mut skipped = None;
+
+    for token in lexer::tokenize(sql) {
+        let mut rest = &sql[token.range];
+        if matches!(
+            token.kind,
+            TokenKind::String | TokenKind::DollarString | TokenKind::QuotedIdentifier
+        ) {
+            push_separated(&mut normalized, &mut skipped, rest);
+            continue;
+        }
+
+        while !rest.is_empty() {
+            let word_start = rest
+                .find(|c: char| !c.is_whitespace())
+                .unwrap_or(rest.len());
+            if rest[..word_start].contains('\n') {
+                skipped = Some('\n');
+            } else if word_start > 0 && skipped.is_none() {
+                skipped = Some(' ');
+            }
+            rest = &rest[word_start..];
+
+            let word_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            if word_end > 0 {
+                push_separated(&mut normalized, &mut skipped, &rest[..word_end]);
+            }
+            rest = &rest[word_end..];
+        }
+    }
+    normalized
+}
+
+/// Appends `text`, after the whitespace skipped before it unless it would
+/// start a line.
+fn push_separated(normalized: &mut String, skipped: &mut Option<char>, text: &str) {
+    if let Some(separator) = skipped.take()
+        && !normalized.is_empty()
+        && !normalized.ends_with('\n')
+    {
+        normalized.push(separator);
+    }
+    normalized.push_str(text);
+}
+
+/// Adds `range` to `ranges`, growing the last one when they touch.
+pub fn push_range(ranges: &mut Vec<Range<usize>>, range: Range<usize>) {
+    match ranges.last_mut() {
+        Some(last) if last.end == range.start => last.end = range.end,
+        _ => ranges.push(range),
+    }
+}
+
+/// Splits SQL into its statements on top level `;`, skipping the ones inside
+/// quotes, dollar quotes and comments. Needed where statements have to be
+/// sent one at a time, like `CREATE INDEX CONCURRENTLY`.
+pub fn split_statements(sql: &str) -> Vec<&str> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+
+    for token in lexer::tokenize(sql) {
+        if token.kind == TokenKind::Semicolon {
+            statements.push(&sql[start..token.range.end]);
+            start = token.range.end;
+        }
+    }
+    statements.push(&sql[start..]);
+
+    statements
+        .into_iter()
+        .filter(|statement| {
+            let statement = statement.trim().trim_end_matches(';').trim();
+            !statement.is_empty()
+        })
+        .collect()
+}
+
 #[derive(TS, Debug, Clone, Default)]
 #[ts(export)]
 pub struct Migration {
@@ -15,9 +102,17 @@ pub struct Migration {
     description: String,
     sql: String,
     sql_rollback: String,
+    // Where the SQL was read from in the file, before macros were expanded
+    sql_ranges: Vec<Range<usize>>,
+    sql_rollback_ranges: Vec<Range<usize>>,
     dependencies: Vec<Dependency>,
+    macro_calls: Vec<MacroCall>,
     tags: HashSet<MigrationTags>,
     nuclear: bool,
+    retries: Option<u32>,
+    line: usize,
+    // Where the name was read from in the file
+    span: Range<usize>,
 }
 
 impl Migration {
@@ -42,26 +137,56 @@ impl Migration {
         }
     }
 
-    pub fn add_sql(&mut self, sql: impl Into<String>) {
-        if self.sql.is_empty() {
-            self.sql = sql.into();
-        } else {
-            self.sql.push_str(&format!("\n{}", sql.into()));
-        }
+    /// Appends a line of SQL as written, with its line break, read from
+    /// `range` of the file.
+    pub fn add_sql(&mut self, sql: &str, range: Range<usize>) {
+        self.sql.push_str(sql);
+        push_range(&mut self.sql_ranges, range);
     }
 
-    pub fn add_sql_rollback(&mut self, sql: impl Into<String>) {
-        if self.sql_rollback.is_empty() {
-            self.sql_rollback = sql.into();
-        } else {
-            self.sql_rollback.push_str(&format!("\n{}", sql.into()));
-        }
+    pub fn add_sql_rollback(&mut self, sql: &str, range: Range<usize>) {
+        self.sql_rollback.push_str(sql);
+        push_range(&mut self.sql_rollback_ranges, range);
     }
 
     pub fn add_dependency(&mut self, dependency: Dependency) {
         self.dependencies.push(dependency);
     }
 
+    /// Records a macro call at the current end of the SQL, or of the rollback
+    /// section when `rollback` is set.
+    pub fn add_macro_call(&mut self, mut call: MacroCall, rollback: bool) {
+        let sql = if rollback {
+            &self.sql_rollback
+        } else {
+            &self.sql
+        };
+        call.set_position(rollback, sql.lines().count());
+        self.macro_calls.push(call);
+    }
+
+    /// Inserts `body` after the first `at` lines of the SQL or rollback SQL.
+    pub fn insert_sql(&mut self, rollback: bool, at: usize, body: &str) {
+        let sql = if rollback {
+            &mut self.sql_rollback
+        } else {
+            &mut self.sql
+        };
+        let lines: Vec<&str> = sql.split_inclusive('\n').collect();
+        let at = at.min(lines.len());
+
+        let mut spliced = lines[..at].concat();
+        if !spliced.is_empty() && !spliced.ends_with('\n') {
+            spliced.push('\n');
+        }
+        spliced.push_str(body);
+        if !body.is_empty() && !body.ends_with('\n') && at < lines.len() {
+            spliced.push('\n');
+        }
+        spliced.push_str(&lines[at..].concat());
+        *sql = spliced;
+    }
+
     pub fn set_version(&mut self, version: impl Into<String>) {
         self.version = Some(version.into());
     }
@@ -74,6 +199,18 @@ impl Migration {
         self.nuclear = true;
     }
 
+    pub fn set_retries(&mut self, retries: u32) {
+        self.retries = Some(retries);
+    }
+
+    pub fn set_line(&mut self, line: usize) {
+        self.line = line;
+    }
+
+    pub fn set_span(&mut self, span: Range<usize>) {
+        self.span = span;
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
@@ -90,10 +227,60 @@ impl Migration {
         &self.sql
     }
 
+    pub fn sql_rollback(&self) -> &str {
+        &self.sql_rollback
+    }
+
+    /// Byte ranges of the file the SQL was read from, in order.
+    pub fn sql_ranges(&self) -> &[Range<usize>] {
+        &self.sql_ranges
+    }
+
+    pub fn sql_rollback_ranges(&self) -> &[Range<usize>] {
+        &self.sql_rollback_ranges
+    }
+
+    pub fn has_rollback(&self) -> bool {
+        !self.sql_rollback.trim().is_empty()
+    }
+
+    /// Hex encoded SHA-256 of the normalized migration SQL, rollback SQL and
+    /// the rendered bodies of the macros it calls.
+    pub fn hash(&self, macro_bodies: &[&str]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(normalize_sql(&self.sql).as_bytes());
+        hasher.update([0]);
+        hasher.update(normalize_sql(&self.sql_rollback).as_bytes());
+        for body in macro_bodies {
+            hasher.update([0]);
+            hasher.update(normalize_sql(body).as_bytes());
+        }
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    /// The normalized SQL and rollback SQL, stored when the migration is
+    /// applied to show what changed once its hash no longer matches.
+    pub fn normalized_content(&self) -> String {
+        let mut content = normalize_sql(&self.sql);
+        if self.has_rollback() {
+            content.push_str("\n-- +rollback\n");
+            content.push_str(&normalize_sql(&self.sql_rollback));
+        }
+        content
+    }
+
     pub fn dependencies(&self) -> &[Dependency] {
         &self.dependencies
     }
 
+    pub fn macro_calls(&self) -> &[MacroCall] {
+        &self.macro_calls
+    }
+
     pub fn tags(&self) -> &HashSet<MigrationTags> {
         &self.tags
     }
@@ -101,4 +288,69 @@ impl Migration {
     pub fn path(&self) -> &str {
         self.full_path.as_deref().unwrap_or_else(|| &self.name)
     }
+
+    pub fn is_nuclear(&self) -> bool {
+        self.nuclear
+    }
+
+    /// Retries declared on the migration itself, groups may set a default.
+    pub fn retries(&self) -> Option<u32> {
+        self.retries
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn span(&self) -> &Range<usize> {
+        &self.span
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizing_trims_lines_and_collapses_whitespace() {
+        assert_eq!(
+            normalize_sql("  CREATE TABLE t (\r\n\n\tid   INT\n  );  \n"),
+            "CREATE TABLE t (\nid INT\n);"
+        );
+    }
+
+    #[test]
+    fn normalizing_keeps_literals_as_written() {
+        assert_eq!(
+            normalize_sql("SELECT  'a   b',\n   $$x\n   y$$,  \"c  d\";"),
+            "SELECT 'a   b',\n$$x\n   y$$, \"c  d\";"
+        );
+    }
+
+    #[test]
+    fn hashes_ignore_indentation_but_not_literals() {
+        let hash = |sql: &str| {
+            let mut migration = Migration::new("m");
+            migration.add_sql(sql, 0..sql.len());
+            migration.hash(&[])
+        };
+        assert_eq!(
+            hash("SELECT 'a  b';\n"),
+            hash("\n    SELECT\t'a  b';  \n\n")
+        );
+        assert_ne!(hash("SELECT 'a  b';\n"), hash("SELECT 'a b';\n"));
+    }
+
+    #[test]
+    fn normalized_content_holds_the_rollback_section() {
+        let mut migration = Migration::new("m");
+        migration.add_sql("  CREATE TABLE t ();\n", 0..21);
+        assert_eq!(migration.normalized_content(), "CREATE TABLE t ();");
+
+        migration.add_sql_rollback("  DROP TABLE t;\n", 30..46);
+        assert_eq!(
+            migration.normalized_content(),
+            "CREATE TABLE t ();\n-- +rollback\nDROP TABLE t;"
+        );
+    }
 }
@@ -1,26 +1,116 @@
+use std::{ops::Range, sync::LazyLock};
+
 use regex::Regex;
 use ts_rs::TS;
 
 use crate::parse_errors::ParseErrorKind;
 
-const REGEX_MIGRATION: &str = r"Migration\(([^)]+)\)";
-const REGEX_GROUP: &str = r"Group\(([^)]+)\)";
+static REGEX_MIGRATION: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"Migration\(([^)]+)\)").expect("Invalid regex for migration")
+});
+static REGEX_GROUP: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"Group\(([^)]+)\)").expect("Invalid regex for group"));
 
 #[derive(TS, Debug, Clone, PartialEq, Eq, Hash)]
 #[ts(export)]
-pub enum Dependency {
+pub enum DependencyKind {
     Migration(String),
     Group(String),
     Function(String),
     Macro(String),
-    InAnotherFile(Box<Dependency>),
+    InAnotherFile(Box<DependencyKind>),
+}
+
+/// What a node depends on, with where in its file the dependency was declared.
+#[derive(TS, Debug, Clone, PartialEq, Eq, Hash)]
+#[ts(export)]
+pub struct Dependency {
+    kind: DependencyKind,
+    line: usize,
+    // Where the dependency was read from in the file
+    span: Range<usize>,
 }
 
 impl Dependency {
+    pub fn new(dependency: impl Into<String>) -> Result<Self, ParseErrorKind> {
+        Ok(Self::from(DependencyKind::new(dependency)?))
+    }
+
+    pub fn new_function(complete_path: impl Into<String>) -> Self {
+        Self::from(DependencyKind::Function(complete_path.into()))
+    }
+
+    pub fn new_macro(complete_path: impl Into<String>) -> Self {
+        Self::from(DependencyKind::Macro(complete_path.into()))
+    }
+
+    pub fn migration(complete_path: impl Into<String>) -> Self {
+        Self::from(DependencyKind::Migration(complete_path.into()))
+    }
+
+    pub fn group(complete_path: impl Into<String>) -> Self {
+        Self::from(DependencyKind::Group(complete_path.into()))
+    }
+
+    pub fn set_line(&mut self, line: usize) {
+        self.line = line;
+    }
+
+    pub fn set_span(&mut self, span: Range<usize>) {
+        self.span = span;
+    }
+
+    pub fn kind(&self) -> &DependencyKind {
+        &self.kind
+    }
+
+    /// Line of the directive declaring the dependency.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn span(&self) -> &Range<usize> {
+        &self.span
+    }
+
+    pub fn complete_path(&self) -> &str {
+        self.kind.complete_path()
+    }
+
+    pub fn name(&self) -> &str {
+        self.kind.name()
+    }
+
+    pub fn is_migration(&self) -> bool {
+        self.kind.is_migration()
+    }
+
+    pub fn is_group(&self) -> bool {
+        self.kind.is_group()
+    }
+
+    pub fn is_from_other_file(&self) -> bool {
+        self.kind.is_from_other_file()
+    }
+
+    pub fn is_from_current_file(&self) -> bool {
+        self.kind.is_from_current_file()
+    }
+}
+
+impl From<DependencyKind> for Dependency {
+    fn from(kind: DependencyKind) -> Self {
+        Self {
+            kind,
+            line: 0,
+            span: 0..0,
+        }
+    }
+}
+
+impl DependencyKind {
     pub fn new(dependency: impl Into<String>) -> Result<Self, ParseErrorKind> {
         let dependency = dependency.into();
-        let migration_regex = Regex::new(REGEX_MIGRATION).expect("Invalid regex for migration");
-        let group_regex = Regex::new(REGEX_GROUP).expect("Invalid regex for group");
 
         if dependency.starts_with("::") {
             return Ok(Self::InAnotherFile(Box::new(Self::new(
@@ -28,11 +118,11 @@ impl Dependency {
             )?)));
         }
 
-        if migration_regex.captures(&dependency).is_some() {
+        if REGEX_MIGRATION.captures(&dependency).is_some() {
             Ok(Self::Migration(
                 dependency,
             ))
-        } else if group_regex.captures(&dependency).is_some() {
+        } else if REGEX_GROUP.captures(&dependency).is_some() {
             Ok(Self::Group(
                 dependency,
             ))
@@ -41,22 +131,6 @@ impl Dependency {
         }
     }
 
-    pub fn new_function(complete_path: impl Into<String>) -> Self {
-        Self::Function(complete_path.into())
-    }
-
-    pub fn new_macro(complete_path: impl Into<String>) -> Self {
-        Self::Macro(complete_path.into())
-    }
-
-    pub fn migration(complete_path: impl Into<String>) -> Self {
-        Self::Migration(complete_path.into())
-    }
-
-    pub fn group(complete_path: impl Into<String>) -> Self {
-        Self::Group(complete_path.into())
-    }
-
     pub fn complete_path(&self) -> &str {
         match self {
             Self::Migration(path) => path,
@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, ops::Range};
 
 use ts_rs::TS;
 
@@ -13,23 +13,31 @@ use crate::{
 #[ts(export)]
 pub struct MigrationGroup {
     name: String,
+    full_path: Option<String>,
     version: Option<String>,
     description: String,
     migrations: Vec<Migration>,
     groups: Vec<MigrationGroup>,
     dependencies: Vec<Dependency>,
+    current_migration_index: usize,
     current_group_index: usize,
     tags: HashSet<MigrationTags>,
     nuclear: bool,
+    transaction: bool,
+    retries: Option<u32>,
+    line: usize,
+    // Where the name was read from in the file
+    span: Range<usize>,
 }
 
 impl Iterator for MigrationGroup {
     type Item = Migration;
 
     fn next(&mut self) -> Option<Self::Item> {
-        // Drain local migrations first
-        if let Some(m) = self.migrations.pop() {
-            return Some(m);
+        // Drain local migrations first, in definition order
+        if let Some(m) = self.migrations.get(self.current_migration_index) {
+            self.current_migration_index += 1;
+            return Some(m.clone());
         }
 
         // Then move through subgroups
@@ -47,7 +55,7 @@ impl Iterator for MigrationGroup {
     where
         Self: Sized,
     {
-        let local_count = self.migrations.len();
+        let local_count = self.migrations.len() - self.current_migration_index;
         let subgroup_count: usize = self.groups.iter().map(|g| g.clone().count()).sum();
 
         local_count + subgroup_count
@@ -121,7 +129,54 @@ impl MigrationGroup {
         self.nuclear = true;
     }
 
+    pub fn set_full_path(&mut self, full_path: impl Into<String>) {
+        self.full_path = Some(full_path.into());
+    }
+
+    pub fn set_transaction_true(&mut self) {
+        self.transaction = true;
+    }
+
+    pub fn set_retries(&mut self, retries: u32) {
+        self.retries = Some(retries);
+    }
+
+    pub fn set_line(&mut self, line: usize) {
+        self.line = line;
+    }
+
+    pub fn set_span(&mut self, span: Range<usize>) {
+        self.span = span;
+    }
+
     pub fn tags(&self) -> &HashSet<MigrationTags> {
         &self.tags
     }
+
+    pub fn path(&self) -> &str {
+        self.full_path.as_deref().unwrap_or_else(|| &self.name)
+    }
+
+    pub fn is_nuclear(&self) -> bool {
+        self.nuclear
+    }
+
+    /// Whether the group runs in a single transaction, through either
+    /// `-- +transaction` or the `transactional` tag.
+    pub fn is_transactional(&self) -> bool {
+        self.transaction || self.tags.contains(&MigrationTags::Transactional)
+    }
+
+    /// Retries of the migrations in the group that do not set their own.
+    pub fn retries(&self) -> Option<u32> {
+        self.retries
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn span(&self) -> &Range<usize> {
+        &self.span
+    }
 }
@@ -8,6 +8,17 @@ pub enum MigrationTags {
     Transactional,
 }
 
+impl MigrationTags {
+    pub const ALL: [MigrationTags; 2] = [MigrationTags::Concurrent, MigrationTags::Transactional];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MigrationTags::Concurrent => "concurrent",
+            MigrationTags::Transactional => "transactional",
+        }
+    }
+}
+
 impl TryFrom<String> for MigrationTags {
     type Error = ParseErrorKind;
 
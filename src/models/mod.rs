@@ -1,7 +1,10 @@
 pub mod file;
 pub mod function;
+pub mod macro_call;
 pub mod macro_func;
 pub mod migration;
 pub mod migration_dependency;
 pub mod migration_group;
 pub mod migration_tags;
+pub mod project;
+pub mod syntax;
@@ -0,0 +1,951 @@
+use std::{collections::HashMap, ops::Range};
+
+use ts_rs::TS;
+
+use crate::{
+    models::{
+        file::MigrationFile,
+        function::Function,
+        macro_call::MacroCall,
+        macro_func::MacroFunc,
+        migration::Migration,
+        migration_dependency::{Dependency, DependencyKind},
+        migration_group::MigrationGroup,
+        migration_tags::MigrationTags,
+    },
+    parse_errors::{ParseError, ParseErrorKind},
+};
+
+/// How deep macros may call each other before expansion gives up.
+const MAX_MACRO_DEPTH: usize = 32;
+
+#[derive(TS, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[ts(export)]
+pub enum NodeKind {
+    Migration,
+    Group,
+    Macro,
+    Function,
+}
+
+impl NodeKind {
+    /// Wrapper used for this kind in full paths, as in `Migration(name)`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NodeKind::Migration => "Migration",
+            NodeKind::Group => "Group",
+            NodeKind::Macro => "Macro",
+            NodeKind::Function => "Function",
+        }
+    }
+}
+
+#[derive(TS, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[ts(export)]
+pub struct NodeId {
+    pub kind: NodeKind,
+    pub index: usize,
+}
+
+impl NodeId {
+    pub fn new(kind: NodeKind, index: usize) -> Self {
+        Self { kind, index }
+    }
+}
+
+/// Every parsed file of a migrations directory, with all migrations, groups,
+/// macros and functions flattened and indexed by their full path.
+#[derive(TS, Debug, Clone, Default)]
+#[ts(export)]
+pub struct MigrationProject {
+    root: String,
+    files: Vec<MigrationFile>,
+    migrations: Vec<Migration>,
+    groups: Vec<MigrationGroup>,
+    macros: Vec<MacroFunc>,
+    functions: Vec<Function>,
+    index: HashMap<String, NodeId>,
+    resolved_dependencies: HashMap<NodeId, Vec<NodeId>>,
+}
+
+/// Splits a full path into the file it lives in and the path inside that file.
+pub fn split_file_path(full_path: &str) -> (&str, &str) {
+    full_path.split_once("::").unwrap_or(("", full_path))
+}
+
+/// Path members of a group share, `file::a::Group(b)` becomes `file::a::b`.
+pub fn group_scope(group_path: &str) -> String {
+    match group_path.rsplit_once("::") {
+        Some((parent, name)) => {
+            let name = name
+                .strip_prefix("Group(")
+                .and_then(|name| name.strip_suffix(')'))
+                .unwrap_or(name);
+            format!("{parent}::{name}")
+        }
+        None => group_path.to_string(),
+    }
+}
+
+/// Scopes a dependency declared by `owner` is looked up in, innermost first.
+fn scopes_of(owner: &str) -> Vec<String> {
+    let mut segments: Vec<&str> = owner.split("::").collect();
+    segments.pop();
+
+    let mut scopes = Vec::new();
+    while !segments.is_empty() {
+        scopes.push(segments.join("::"));
+        segments.pop();
+    }
+    scopes
+}
+
+/// Strips a `Kind(name)` wrapper, so `Function(foo)` and `foo` are the same name.
+fn unwrap_kind<'a>(name: &'a str, kind: &str) -> &'a str {
+    name.strip_prefix(kind)
+        .and_then(|rest| rest.strip_prefix('('))
+        .and_then(|rest| rest.strip_suffix(')'))
+        .unwrap_or(name)
+}
+
+impl MigrationProject {
+    pub fn new(root: impl Into<String>) -> Self {
+        Self {
+            root: root.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Indexes the node defined at `span` of `file` under `path`. A path
+    /// that is already taken keeps pointing at its first definition.
+    fn insert_index(
+        &mut self,
+        file: &MigrationFile,
+        path: &str,
+        id: NodeId,
+        span: &Range<usize>,
+    ) -> Result<(), ParseError> {
+        if self.index.contains_key(path) {
+            return Err(file.error(
+                ParseErrorKind::DuplicatePath(path.to_string()),
+                span.clone(),
+                format!("'{path}' is defined more than once"),
+            ));
+        }
+        self.index.insert(path.to_string(), id);
+        Ok(())
+    }
+
+    fn add_group(
+        &mut self,
+        file: &MigrationFile,
+        group: &MigrationGroup,
+    ) -> Result<(), ParseError> {
+        let id = NodeId::new(NodeKind::Group, self.groups.len());
+        self.insert_index(file, group.path(), id, group.span())?;
+        self.groups.push(group.clone());
+
+        for migration in group.migrations() {
+            self.add_migration(file, migration)?;
+        }
+        for subgroup in group.groups() {
+            self.add_group(file, subgroup)?;
+        }
+        Ok(())
+    }
+
+    fn add_migration(
+        &mut self,
+        file: &MigrationFile,
+        migration: &Migration,
+    ) -> Result<(), ParseError> {
+        let id = NodeId::new(NodeKind::Migration, self.migrations.len());
+        self.insert_index(file, migration.path(), id, migration.span())?;
+        self.migrations.push(migration.clone());
+        Ok(())
+    }
+
+    /// Adds an already parsed file and indexes everything defined in it. The
+    /// file is kept even when indexing fails, so later errors can point at it.
+    pub fn add_file(&mut self, file: MigrationFile) -> Result<(), ParseError> {
+        let indexed = self.index_file(&file);
+        self.files.push(file);
+        indexed
+    }
+
+    fn index_file(&mut self, file: &MigrationFile) -> Result<(), ParseError> {
+        for migration in &file.migrations {
+            self.add_migration(file, migration)?;
+        }
+        for group in &file.migration_groups {
+            self.add_group(file, group)?;
+        }
+        for macro_func in &file.macros {
+            let id = NodeId::new(NodeKind::Macro, self.macros.len());
+            self.insert_index(file, macro_func.path(), id, macro_func.span())?;
+            self.macros.push(macro_func.clone());
+        }
+        for function in &file.functions {
+            let id = NodeId::new(NodeKind::Function, self.functions.len());
+            self.insert_index(file, function.path(), id, function.span())?;
+            self.functions.push(function.clone());
+        }
+        Ok(())
+    }
+
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    pub fn files(&self) -> &[MigrationFile] {
+        &self.files
+    }
+
+    pub fn migrations(&self) -> &[Migration] {
+        &self.migrations
+    }
+
+    pub fn groups(&self) -> &[MigrationGroup] {
+        &self.groups
+    }
+
+    pub fn macros(&self) -> &[MacroFunc] {
+        &self.macros
+    }
+
+    pub fn functions(&self) -> &[Function] {
+        &self.functions
+    }
+
+    pub fn index(&self) -> &HashMap<String, NodeId> {
+        &self.index
+    }
+
+    pub fn get(&self, full_path: &str) -> Option<NodeId> {
+        self.index.get(full_path).copied()
+    }
+
+    pub fn migration(&self, full_path: &str) -> Option<&Migration> {
+        match self.get(full_path) {
+            Some(NodeId {
+                kind: NodeKind::Migration,
+                index,
+            }) => self.migrations.get(index),
+            _ => None,
+        }
+    }
+
+    pub fn group(&self, full_path: &str) -> Option<&MigrationGroup> {
+        match self.get(full_path) {
+            Some(NodeId {
+                kind: NodeKind::Group,
+                index,
+            }) => self.groups.get(index),
+            _ => None,
+        }
+    }
+
+    pub fn macro_func(&self, full_path: &str) -> Option<&MacroFunc> {
+        match self.get(full_path) {
+            Some(NodeId {
+                kind: NodeKind::Macro,
+                index,
+            }) => self.macros.get(index),
+            _ => None,
+        }
+    }
+
+    pub fn function(&self, full_path: &str) -> Option<&Function> {
+        match self.get(full_path) {
+            Some(NodeId {
+                kind: NodeKind::Function,
+                index,
+            }) => self.functions.get(index),
+            _ => None,
+        }
+    }
+
+    fn dependencies_by_node(&self) -> Vec<(NodeId, &[Dependency])> {
+        let mut nodes: Vec<(NodeId, &[Dependency])> = Vec::new();
+        for (index, migration) in self.migrations.iter().enumerate() {
+            nodes.push((
+                NodeId::new(NodeKind::Migration, index),
+                migration.dependencies(),
+            ));
+        }
+        for (index, group) in self.groups.iter().enumerate() {
+            nodes.push((NodeId::new(NodeKind::Group, index), group.dependencies()));
+        }
+        for (index, macro_func) in self.macros.iter().enumerate() {
+            nodes.push((
+                NodeId::new(NodeKind::Macro, index),
+                macro_func.dependencies(),
+            ));
+        }
+        for (index, function) in self.functions.iter().enumerate() {
+            nodes.push((
+                NodeId::new(NodeKind::Function, index),
+                function.dependencies(),
+            ));
+        }
+        nodes
+    }
+
+    fn lookup(&self, full_path: &str, kind: NodeKind) -> Option<NodeId> {
+        self.get(full_path).filter(|id| id.kind == kind)
+    }
+
+    /// Looks up a `Migration(..)`/`Group(..)` path from the owner's scope outwards.
+    fn resolve_scoped(&self, owner: &str, path: &str, kind: NodeKind) -> Option<NodeId> {
+        scopes_of(owner)
+            .iter()
+            .find_map(|scope| self.lookup(&format!("{scope}::{path}"), kind))
+    }
+
+    /// Resolves a macro or function reference: `name`, `group::name`,
+    /// `file.sql::name` or `::path/to/file.sql::name`. Names without a file
+    /// are looked up from the owner's scope outwards, then across the project.
+    fn resolve_callable(&self, owner: &str, path: &str, kind: NodeKind) -> Result<NodeId, String> {
+        let wrapper = kind.as_str();
+        let path = path.trim_start_matches("::");
+        let (prefix, name) = path.rsplit_once("::").unwrap_or(("", path));
+        let name = unwrap_kind(name.trim(), wrapper);
+        let target = if prefix.is_empty() {
+            format!("{wrapper}({name})")
+        } else {
+            format!("{prefix}::{wrapper}({name})")
+        };
+
+        let (first, rest) = prefix.split_once("::").unwrap_or((prefix, ""));
+        let file = first.trim_matches('/');
+        if file.ends_with(".sql") || file.contains('/') {
+            let full_path = if rest.is_empty() {
+                format!("{file}::{wrapper}({name})")
+            } else {
+                format!("{file}::{rest}::{wrapper}({name})")
+            };
+            return self.lookup(&full_path, kind).ok_or_else(|| {
+                format!("no {} named '{name}' in '{file}'", wrapper.to_lowercase())
+            });
+        }
+
+        if let Some(id) = scopes_of(owner)
+            .iter()
+            .find_map(|scope| self.lookup(&format!("{scope}::{target}"), kind))
+        {
+            return Ok(id);
+        }
+
+        // Otherwise the reference may live in any file, as long as it is unique
+        let suffix = format!("::{target}");
+        let mut candidates: Vec<&String> = self
+            .index
+            .iter()
+            .filter(|(full_path, id)| id.kind == kind && full_path.ends_with(&suffix))
+            .map(|(full_path, _)| full_path)
+            .collect();
+        candidates.sort();
+
+        match candidates.as_slice() {
+            [] => Err(format!(
+                "no {} named '{name}' in the project",
+                wrapper.to_lowercase()
+            )),
+            [only] => Ok(self.index[*only]),
+            many => Err(format!(
+                "{} '{name}' is ambiguous, candidates: {}",
+                wrapper.to_lowercase(),
+                many.iter()
+                    .map(|path| path.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        }
+    }
+
+    fn resolve_in_other_file(
+        &self,
+        owner: &str,
+        dependency: &DependencyKind,
+    ) -> Result<NodeId, String> {
+        let path = dependency.complete_path().trim_start_matches('/');
+        let owner_file = split_file_path(owner).0;
+
+        let mut candidates = vec![path.to_string()];
+        if let Some((owner_dir, _)) = owner_file.rsplit_once('/') {
+            candidates.push(format!("{owner_dir}/{path}"));
+        }
+
+        for candidate in &candidates {
+            let found = match dependency {
+                DependencyKind::Migration(_) => self.lookup(candidate, NodeKind::Migration),
+                DependencyKind::Group(_) => self.lookup(candidate, NodeKind::Group),
+                DependencyKind::Function(_) => self
+                    .resolve_callable(owner, candidate, NodeKind::Function)
+                    .ok(),
+                DependencyKind::Macro(_) => self
+                    .resolve_callable(owner, candidate, NodeKind::Macro)
+                    .ok(),
+                DependencyKind::InAnotherFile(inner) => {
+                    self.resolve_in_other_file(owner, inner).ok()
+                }
+            };
+            if let Some(id) = found {
+                return Ok(id);
+            }
+        }
+        Err(format!("'{path}' does not exist in the project"))
+    }
+
+    /// Resolves a single dependency declared by the node at `owner`.
+    pub fn resolve(&self, owner: &str, dependency: &DependencyKind) -> Result<NodeId, String> {
+        match dependency {
+            DependencyKind::Migration(path) => self
+                .resolve_scoped(owner, path, NodeKind::Migration)
+                .ok_or_else(|| format!("no migration matches '{path}'")),
+            DependencyKind::Group(path) => self
+                .resolve_scoped(owner, path, NodeKind::Group)
+                .ok_or_else(|| format!("no group matches '{path}'")),
+            DependencyKind::Function(path) => {
+                self.resolve_callable(owner, path, NodeKind::Function)
+            }
+            DependencyKind::Macro(path) => self.resolve_callable(owner, path, NodeKind::Macro),
+            DependencyKind::InAnotherFile(inner) => self.resolve_in_other_file(owner, inner),
+        }
+    }
+
+    /// Resolves every dependency in the project into node ids, failing on the
+    /// first one that points at nothing.
+    pub fn resolve_dependencies(&mut self) -> Result<(), ParseError> {
+        match self.resolve_dependencies_recovering().into_iter().next() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Like `resolve_dependencies`, but resolves everything it can and
+    /// returns an error for each dependency that points at nothing.
+    pub fn resolve_dependencies_recovering(&mut self) -> Vec<ParseError> {
+        let mut resolved = HashMap::new();
+        let mut errors = Vec::new();
+
+        for (id, dependencies) in self.dependencies_by_node() {
+            let owner = self.path_of(id);
+            let mut ids = Vec::with_capacity(dependencies.len());
+            for dependency in dependencies {
+                match self.resolve(owner, dependency.kind()) {
+                    Ok(dependency_id) => ids.push(dependency_id),
+                    Err(reason) => {
+                        let kind = ParseErrorKind::UnresolvedDependency(
+                            split_file_path(owner).0.to_string(),
+                            dependency.complete_path().to_string(),
+                        );
+                        let message = format!(
+                            "unresolved dependency '{}' of '{owner}': {reason}",
+                            dependency.complete_path()
+                        );
+                        errors.push(self.error_in(owner, kind, dependency.span().clone(), message));
+                    }
+                }
+            }
+            resolved.insert(id, ids);
+        }
+
+        self.resolved_dependencies = resolved;
+        errors
+    }
+
+    /// An error about `span` of the file the node at `full_path` lives in.
+    pub(crate) fn error_in(
+        &self,
+        full_path: &str,
+        kind: ParseErrorKind,
+        span: Range<usize>,
+        message: String,
+    ) -> ParseError {
+        let file_path = split_file_path(full_path).0;
+        match self.files.iter().find(|file| file.file_path == file_path) {
+            Some(file) => file.error(kind, span, message),
+            None => ParseError {
+                kind,
+                message,
+                line: 0,
+                column: 0,
+                span: None,
+            },
+        }
+    }
+
+    /// An error pointing at the name of the node behind `id`.
+    pub(crate) fn error_at_node(
+        &self,
+        id: NodeId,
+        kind: ParseErrorKind,
+        message: String,
+    ) -> ParseError {
+        self.error_in(self.path_of(id), kind, self.span_of(id).clone(), message)
+    }
+
+    fn expansion_error(
+        &self,
+        owner: &str,
+        call: &MacroCall,
+        kind: ParseErrorKind,
+        reason: impl std::fmt::Display,
+    ) -> ParseError {
+        let message = format!("cannot expand '{}' in '{owner}': {reason}", call.path());
+        self.error_in(owner, kind, call.span().clone(), message)
+    }
+
+    /// Resolves the macro called from `owner` and binds `values` to its
+    /// parameters.
+    fn bind_call(
+        &self,
+        owner: &str,
+        call: &MacroCall,
+        values: &[String],
+    ) -> Result<(NodeId, HashMap<String, Vec<String>>), ParseError> {
+        let id = self
+            .resolve(owner, &DependencyKind::Macro(call.path().to_string()))
+            .map_err(|reason| {
+                let kind = ParseErrorKind::UnresolvedDependency(
+                    split_file_path(owner).0.to_string(),
+                    call.path().to_string(),
+                );
+                self.expansion_error(owner, call, kind, reason)
+            })?;
+        let bound = self.macros[id.index]
+            .bind_arguments(values)
+            .map_err(|kind| {
+                let reason = match &kind {
+                    ParseErrorKind::MissingArgument(name) => {
+                        format!("missing a value for parameter '{name}'")
+                    }
+                    ParseErrorKind::UnexpectedArgument(value) => {
+                        format!("unexpected argument '{value}'")
+                    }
+                    _ => "invalid arguments".to_string(),
+                };
+                self.expansion_error(owner, call, kind, reason)
+            })?;
+        Ok((id, bound))
+    }
+
+    /// Renders a macro, expanding the macros it calls in turn. `stack` holds
+    /// the paths being expanded, outermost first.
+    fn render_macro(
+        &self,
+        id: NodeId,
+        values: &HashMap<String, Vec<String>>,
+        stack: &mut Vec<String>,
+    ) -> Result<String, ParseError> {
+        let macro_func = &self.macros[id.index];
+        let path = macro_func.path();
+
+        if stack.len() > MAX_MACRO_DEPTH || stack.iter().any(|entry| entry == path) {
+            stack.push(path.to_string());
+            let message = format!("macro expansion does not terminate: {}", stack.join(" -> "));
+            return Err(self.error_at_node(
+                id,
+                ParseErrorKind::MacroRecursion(stack.clone()),
+                message,
+            ));
+        }
+
+        stack.push(path.to_string());
+        let body = macro_func.render_body_with(values, |call| {
+            let (nested, nested_values) = self.bind_call(path, call, &call.bind_outer(values))?;
+            self.render_macro(nested, &nested_values, stack)
+        })?;
+        stack.pop();
+        Ok(body)
+    }
+
+    /// Splices the rendered body of every `-- +call:` into the SQL of the
+    /// calling migration, at the position of the call.
+    pub fn expand_macros(&mut self) -> Result<(), ParseError> {
+        for index in 0..self.migrations.len() {
+            let owner = self.migrations[index].path().to_string();
+            let mut expansions = Vec::new();
+
+            for call in self.migrations[index].macro_calls() {
+                let (id, values) = self.bind_call(&owner, call, call.arguments())?;
+                let mut stack = vec![owner.clone()];
+                expansions.push((
+                    call.is_rollback(),
+                    call.sql_line(),
+                    self.render_macro(id, &values, &mut stack)?,
+                ));
+            }
+
+            // Splice from the bottom up so earlier positions stay valid
+            let migration = &mut self.migrations[index];
+            for (rollback, at, body) in expansions.into_iter().rev() {
+                migration.insert_sql(rollback, at, &body);
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolved dependencies of `id`, empty until `resolve_dependencies` ran.
+    pub fn dependencies_of(&self, id: NodeId) -> &[NodeId] {
+        self.resolved_dependencies
+            .get(&id)
+            .map(|ids| ids.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Nodes of `kind` matching `query`, either by full path or by the
+    /// trailing `Kind(name)`/`name` part of it.
+    pub fn find(&self, query: &str, kind: NodeKind) -> Vec<NodeId> {
+        if let Some(id) = self.lookup(query, kind) {
+            return vec![id];
+        }
+
+        let wrapper = kind.as_str();
+        let suffix = if query.contains("::") || query.ends_with(')') {
+            format!("::{query}")
+        } else {
+            format!("::{wrapper}({query})")
+        };
+        let mut found: Vec<NodeId> = self
+            .index
+            .iter()
+            .filter(|(path, id)| id.kind == kind && path.ends_with(&suffix))
+            .map(|(_, id)| *id)
+            .collect();
+        found.sort();
+        found
+    }
+
+    /// Groups the node behind `id` is nested in, innermost first.
+    pub fn enclosing_groups(&self, id: NodeId) -> Vec<NodeId> {
+        scopes_of(self.path_of(id))
+            .iter()
+            .filter_map(|scope| {
+                let (parent, name) = scope.rsplit_once("::")?;
+                self.lookup(&format!("{parent}::Group({name})"), NodeKind::Group)
+            })
+            .collect()
+    }
+
+    /// The outermost transactional group `id` is nested in. All migrations
+    /// below it run in the same transaction.
+    pub fn transaction_group_of(&self, id: NodeId) -> Option<NodeId> {
+        self.enclosing_groups(id)
+            .into_iter()
+            .rev()
+            .find(|group| self.groups[group.index].is_transactional())
+    }
+
+    /// Whether a migration is nuclear, by itself or through a group it is
+    /// nested in.
+    pub fn is_nuclear(&self, id: NodeId) -> bool {
+        self.migrations[id.index].is_nuclear()
+            || self
+                .enclosing_groups(id)
+                .iter()
+                .any(|group| self.groups[group.index].is_nuclear())
+    }
+
+    /// Whether a migration runs outside of a transaction, through the
+    /// `concurrent` tag on itself or on a group it is nested in.
+    pub fn is_concurrent(&self, id: NodeId) -> bool {
+        self.migrations[id.index]
+            .tags()
+            .contains(&MigrationTags::Concurrent)
+            || self.enclosing_groups(id).iter().any(|group| {
+                self.groups[group.index]
+                    .tags()
+                    .contains(&MigrationTags::Concurrent)
+            })
+    }
+
+    /// Retries of a migration, falling back to the innermost group setting
+    /// them and to none.
+    pub fn retries_of(&self, id: NodeId) -> u32 {
+        self.migrations[id.index]
+            .retries()
+            .or_else(|| {
+                self.enclosing_groups(id)
+                    .iter()
+                    .find_map(|group| self.groups[group.index].retries())
+            })
+            .unwrap_or_default()
+    }
+
+    /// Every migration nested in `group`, including those of its subgroups.
+    pub fn migrations_in_group(&self, group: NodeId) -> Vec<NodeId> {
+        let prefix = format!("{}::", group_scope(self.path_of(group)));
+        (0..self.migrations.len())
+            .map(|index| NodeId::new(NodeKind::Migration, index))
+            .filter(|id| self.path_of(*id).starts_with(&prefix))
+            .collect()
+    }
+
+    /// Content hash of a migration, covering the bodies of the macros it calls.
+    pub fn migration_hash(&self, id: NodeId) -> String {
+        let macro_bodies: Vec<&str> = self
+            .dependencies_of(id)
+            .iter()
+            .filter(|dependency| dependency.kind == NodeKind::Macro)
+            .map(|dependency| self.macros[dependency.index].parsed_body())
+            .collect();
+        self.migrations[id.index].hash(&macro_bodies)
+    }
+
+    /// Full path of the node behind `id`.
+    pub fn path_of(&self, id: NodeId) -> &str {
+        match id.kind {
+            NodeKind::Migration => self.migrations[id.index].path(),
+            NodeKind::Group => self.groups[id.index].path(),
+            NodeKind::Macro => self.macros[id.index].path(),
+            NodeKind::Function => self.functions[id.index].path(),
+        }
+    }
+
+    /// Line at which the node behind `id` was opened in its file.
+    pub fn line_of(&self, id: NodeId) -> usize {
+        match id.kind {
+            NodeKind::Migration => self.migrations[id.index].line(),
+            NodeKind::Group => self.groups[id.index].line(),
+            NodeKind::Macro => self.macros[id.index].line(),
+            NodeKind::Function => self.functions[id.index].line(),
+        }
+    }
+    /// Where the name of the node behind `id` was read from in its file.
+    pub fn span_of(&self, id: NodeId) -> &Range<usize> {
+        match id.kind {
+            NodeKind::Migration => self.migrations[id.index].span(),
+            NodeKind::Group => self.groups[id.index].span(),
+            NodeKind::Macro => self.macros[id.index].span(),
+            NodeKind::Function => self.functions[id.index].span(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::try_project;
+
+    fn spanned<'a>(error: &ParseError, content: &'a str) -> &'a str {
+        &content[error.span.as_ref().expect("error has a span").range.clone()]
+    }
+
+    #[test]
+    fn duplicate_paths_point_at_the_second_definition() {
+        let content = "-- +migration: a\nSELECT 1;\n-- +endmigration\n\
+                       -- +migration: a\nSELECT 2;\n-- +endmigration\n";
+        let error = try_project(&[("a.sql", content)]).unwrap_err();
+        assert!(matches!(error.kind, ParseErrorKind::DuplicatePath(_)));
+        assert_eq!((error.line, error.column), (4, 16));
+        assert_eq!(spanned(&error, content), "a");
+    }
+
+    #[test]
+    fn expansion_errors_point_at_the_call() {
+        let content = "-- +macro: fill\n-- +parameters: n\nSELECT {{n}};\n-- +endmacro\n\
+                       -- +migration: m\n-- +call: fill(1, 2)\n-- +endmigration\n";
+        let error = try_project(&[("a.sql", content)]).unwrap_err();
+        assert!(matches!(error.kind, ParseErrorKind::UnexpectedArgument(_)));
+        assert_eq!(error.line, 6);
+        assert_eq!(spanned(&error, content), "fill(1, 2)");
+        assert!(error.message.starts_with("cannot expand"));
+    }
+
+    #[test]
+    fn recursive_macros_point_at_the_macro() {
+        let content = "-- +macro: loop\nSELECT {{loop()}};\n-- +endmacro\n\
+                       -- +migration: m\n-- +call: loop()\n-- +endmigration\n";
+        let error = try_project(&[("a.sql", content)]).unwrap_err();
+        assert!(matches!(error.kind, ParseErrorKind::MacroRecursion(_)));
+        assert_eq!(spanned(&error, content), "loop");
+        assert_eq!(error.line, 1);
+    }
+
+    #[test]
+    fn duplicate_paths_keep_the_first_definition() {
+        let mut project = MigrationProject::new("migrations");
+        let content = "-- +migration: a\nSELECT 1;\n-- +endmigration\n";
+        for path in ["a.sql", "a.sql"] {
+            let mut file = MigrationFile::new(path, path, content);
+            file.parse_file().unwrap();
+            let _ = project.add_file(file);
+        }
+        assert_eq!(
+            project.get("a.sql::Migration(a)"),
+            Some(NodeId::new(NodeKind::Migration, 0))
+        );
+        assert_eq!(project.migrations().len(), 1);
+    }
+
+    #[test]
+    fn resolves_scoped_dependencies_innermost_first() {
+        let project = try_project(&[(
+            "a.sql",
+            "-- +migration: m\nSELECT 1;\n-- +endmigration\n\
+             -- +group: g\n\
+             -- +migration: m\nSELECT 2;\n-- +endmigration\n\
+             -- +migration: uses\n-- +depends: Migration(m)\nSELECT 3;\n-- +endmigration\n\
+             -- +endgroup\n\
+             -- +migration: outer\n-- +depends: g::Migration(m)\nSELECT 4;\n-- +endmigration\n",
+        )])
+        .unwrap();
+        let inner = project.get("a.sql::g::Migration(m)").unwrap();
+        for user in ["a.sql::g::Migration(uses)", "a.sql::Migration(outer)"] {
+            let id = project.get(user).unwrap();
+            assert_eq!(project.dependencies_of(id), [inner]);
+        }
+    }
+
+    #[test]
+    fn resolves_dependencies_in_other_files() {
+        let project = try_project(&[
+            (
+                "lib/tables.sql",
+                "-- +migration: t\nSELECT 1;\n-- +endmigration\n\
+                 -- +function: f\n-- +language: sql\n-- +returns: int\nSELECT 1;\n-- +endfunction\n",
+            ),
+            (
+                "lib/uses.sql",
+                "-- +migration: u\n\
+                 -- +depends: ::tables.sql::Migration(t)\n\
+                 -- +call-func: f\n\
+                 SELECT f();\n\
+                 -- +endmigration\n",
+            ),
+        ])
+        .unwrap();
+        let uses = project.get("lib/uses.sql::Migration(u)").unwrap();
+        assert_eq!(
+            project.dependencies_of(uses),
+            [
+                project.get("lib/tables.sql::Migration(t)").unwrap(),
+                project.get("lib/tables.sql::Function(f)").unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn ambiguous_callables_are_not_resolved() {
+        let function =
+            "-- +function: f\n-- +language: sql\n-- +returns: int\nSELECT 1;\n-- +endfunction\n";
+        let project = try_project(&[("a.sql", function), ("b.sql", function)]).unwrap();
+        let reason = project
+            .resolve(
+                "c.sql::Migration(m)",
+                &DependencyKind::Function("f".to_string()),
+            )
+            .unwrap_err();
+        assert!(reason.contains("ambiguous"), "{reason}");
+        assert!(
+            project
+                .resolve(
+                    "a.sql::Migration(m)",
+                    &DependencyKind::Function("f".to_string())
+                )
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn unresolved_dependencies_point_at_their_directive() {
+        let content =
+            "-- +migration: m\nSELECT 1;\n-- +depends: Migration(nothing)\n-- +endmigration\n";
+        let error = try_project(&[("a.sql", content)]).unwrap_err();
+        assert!(matches!(
+            error.kind,
+            ParseErrorKind::UnresolvedDependency(..)
+        ));
+        assert_eq!((error.line, error.column), (3, 14));
+        assert_eq!(spanned(&error, content), "Migration(nothing)");
+    }
+
+    #[test]
+    fn calls_are_expanded_where_they_are_made() {
+        let project = try_project(&[(
+            "a.sql",
+            "-- +macro: audit\n-- +parameters: table\nCREATE TRIGGER audit AFTER UPDATE ON {{table}};\n-- +endmacro\n\
+             -- +migration: m\n\
+             CREATE TABLE t ();\n\
+             -- +call: audit(t)\n\
+             SELECT 1;\n\
+             -- +rollback\n\
+             -- +call: audit('it''s')\n\
+             DROP TABLE t;\n\
+             -- +endmigration\n",
+        )])
+        .unwrap();
+        let migration = project.migration("a.sql::Migration(m)").unwrap();
+        assert_eq!(
+            migration.sql(),
+            "CREATE TABLE t ();\nCREATE TRIGGER audit AFTER UPDATE ON t;\nSELECT 1;\n"
+        );
+        assert_eq!(
+            migration.sql_rollback(),
+            "CREATE TRIGGER audit AFTER UPDATE ON it's;\nDROP TABLE t;\n"
+        );
+    }
+
+    #[test]
+    fn macro_bodies_count_in_the_hash_of_their_callers() {
+        let files = |body: &str| {
+            format!(
+                "-- +macro: fill\n{body}\n-- +endmacro\n\
+                 -- +migration: m\n-- +call: fill\n-- +endmigration\n"
+            )
+        };
+        let hash = |body: &str| {
+            let project = try_project(&[("a.sql", &files(body))]).unwrap();
+            project.migration_hash(project.get("a.sql::Migration(m)").unwrap())
+        };
+        assert_eq!(hash("SELECT 1;"), hash("  SELECT   1;"));
+        assert_ne!(hash("SELECT 1;"), hash("SELECT 2;"));
+    }
+
+    #[test]
+    fn nested_calls_pass_parameters_on() {
+        let project = try_project(&[(
+            "a.sql",
+            "-- +macro: column\n-- +parameters: name, type\n{{name}} {{type}}\n-- +endmacro\n\
+             -- +macro: table\n-- +parameters: name, ...columns\n\
+             CREATE TABLE {{name}} ({{column(id, INT)}}, {{...columns}});\n-- +endmacro\n\
+             -- +macro: twice\n-- +parameters: ...columns\n\
+             {{table(a, ...columns)}}\n{{table(b, 'x TEXT')}}\n-- +endmacro\n\
+             -- +migration: m\n-- +call: twice('y TEXT', 'z TEXT')\n-- +endmigration\n",
+        )])
+        .unwrap();
+        assert_eq!(
+            project.migration("a.sql::Migration(m)").unwrap().sql(),
+            "CREATE TABLE a (id INT, y TEXT, z TEXT);\nCREATE TABLE b (id INT, x TEXT);\n"
+        );
+    }
+
+    #[test]
+    fn nested_calls_depend_on_the_macros_they_call() {
+        let project = try_project(&[(
+            "a.sql",
+            "-- +macro: inner\nSELECT 1;\n-- +endmacro\n\
+             -- +macro: outer\n{{inner()}}\n-- +endmacro\n",
+        )])
+        .unwrap();
+        let outer = project.get("a.sql::Macro(outer)").unwrap();
+        assert_eq!(
+            project.dependencies_of(outer),
+            [project.get("a.sql::Macro(inner)").unwrap()]
+        );
+    }
+
+    #[test]
+    fn unknown_nested_macros_point_at_their_placeholder() {
+        let content = "-- +macro: outer\nSELECT {{missing(1)}};\n-- +endmacro\n\
+                       -- +migration: m\n-- +call: outer\n-- +endmigration\n";
+        let error = try_project(&[("a.sql", content)]).unwrap_err();
+        assert!(matches!(
+            error.kind,
+            ParseErrorKind::UnresolvedDependency(..)
+        ));
+        assert_eq!(spanned(&error, content), "{{missing(1)}}");
+    }
+}
@@ -0,0 +1,492 @@
+use std::ops::Range;
+
+use ts_rs::TS;
+
+use crate::{
+    directives::{self, Directive},
+    lexer::{Lexer, TokenKind},
+};
+
+/// Function languages whose bodies are SQL. Bodies in other languages are
+/// not lexed, a `'` in Python says nothing about where a string ends.
+const SQL_LANGUAGES: [&str; 2] = ["sql", "plpgsql"];
+
+/// Syntax tree of an FSQL file. Every byte of the file belongs to exactly one
+/// leaf, a directive or a chunk of SQL, so printing the tree gives the file
+/// back as it was written. Building it never fails: blocks that are not
+/// closed simply have no `close`, `MigrationFile::parse_file` reports what
+/// is wrong when lowering the tree to models.
+#[derive(TS, Debug, Clone, PartialEq, Eq, Default)]
+#[ts(export)]
+pub struct FileNode {
+    pub items: Vec<Node>,
+    pub span: Range<usize>,
+}
+
+#[derive(TS, Debug, Clone, PartialEq, Eq)]
+#[ts(export)]
+pub enum Node {
+    Block(Box<BlockNode>),
+    Directive(DirectiveNode),
+    Sql(SqlChunk),
+}
+
+#[derive(TS, Debug, Clone, Copy, PartialEq, Eq)]
+#[ts(export)]
+pub enum BlockKind {
+    Migration,
+    Group,
+    Macro,
+    Function,
+}
+
+/// A `-- +migration`, `-- +group`, `-- +macro` or `-- +function` block, from
+/// its opening directive to its closing one.
+#[derive(TS, Debug, Clone, PartialEq, Eq)]
+#[ts(export)]
+pub struct BlockNode {
+    pub kind: BlockKind,
+    pub name: String,
+    pub open: DirectiveNode,
+    pub items: Vec<Node>,
+    pub close: Option<DirectiveNode>,
+    pub span: Range<usize>,
+}
+
+/// A directive line, with the `-- |` lines continuing it.
+#[derive(TS, Debug, Clone, PartialEq, Eq)]
+#[ts(export)]
+pub struct DirectiveNode {
+    /// As in `directives::DIRECTIVES`, `description` for `--+` and `|` for a
+    /// continuation without a directive before it. Unknown names are kept.
+    pub name: String,
+    pub name_span: Range<usize>,
+    /// What follows the name, continuation lines appended.
+    pub argument: Option<String>,
+    /// From the start of the argument to the end of the last continuation.
+    pub argument_span: Option<Range<usize>>,
+    pub line: usize,
+    pub text: String,
+    pub span: Range<usize>,
+}
+
+/// Consecutive lines that are not directives: SQL, plain comments and blank
+/// lines.
+#[derive(TS, Debug, Clone, PartialEq, Eq)]
+#[ts(export)]
+pub struct SqlChunk {
+    pub line: usize,
+    pub text: String,
+    pub span: Range<usize>,
+}
+
+impl BlockKind {
+    fn opened_by(directive: &str) -> Option<Self> {
+        match directive {
+            "migration" => Some(BlockKind::Migration),
+            "group" => Some(BlockKind::Group),
+            "macro" => Some(BlockKind::Macro),
+            "function" => Some(BlockKind::Function),
+            _ => None,
+        }
+    }
+
+    /// Directive closing the block.
+    pub fn end(&self) -> &'static str {
+        match self {
+            BlockKind::Migration => "endmigration",
+            BlockKind::Group => "endgroup",
+            BlockKind::Macro => "endmacro",
+            BlockKind::Function => "endfunction",
+        }
+    }
+}
+
+impl Node {
+    pub fn span(&self) -> &Range<usize> {
+        match self {
+            Node::Block(block) => &block.span,
+            Node::Directive(directive) => &directive.span,
+            Node::Sql(chunk) => &chunk.span,
+        }
+    }
+
+    fn print_into(&self, out: &mut String) {
+        match self {
+            Node::Block(block) => block.print_into(out),
+            Node::Directive(directive) => out.push_str(&directive.text),
+            Node::Sql(chunk) => out.push_str(&chunk.text),
+        }
+    }
+}
+
+impl BlockNode {
+    fn new(open: DirectiveNode, kind: BlockKind) -> Self {
+        Self {
+            kind,
+            name: open.argument.clone().unwrap_or_default(),
+            span: open.span.clone(),
+            open,
+            items: Vec::new(),
+            close: None,
+        }
+    }
+
+    fn print_into(&self, out: &mut String) {
+        out.push_str(&self.open.text);
+        for item in &self.items {
+            item.print_into(out);
+        }
+        if let Some(close) = &self.close {
+            out.push_str(&close.text);
+        }
+    }
+}
+
+impl DirectiveNode {
+    fn new(
+        directive: Directive,
+        offset: usize,
+        line: usize,
+        text: &str,
+        span: Range<usize>,
+    ) -> Self {
+        let absolute = |range: Range<usize>| offset + range.start..offset + range.end;
+        Self {
+            name: directive.name,
+            name_span: absolute(directive.name_range),
+            argument: directive.argument,
+            argument_span: directive.argument_range.map(absolute),
+            line,
+            text: text.to_string(),
+            span,
+        }
+    }
+
+    /// Appends a `-- |` continuation to the directive.
+    fn continue_with(&mut self, continuation: DirectiveNode) {
+        let argument = continuation.argument.unwrap_or_default();
+        self.argument = Some(self.argument.take().unwrap_or_default() + &argument);
+        if let Some(end) = continuation.argument_span.map(|span| span.end) {
+            let start = self.argument_span.as_ref().map_or(end, |span| span.start);
+            self.argument_span = Some(start..end);
+        }
+        self.text.push_str(&continuation.text);
+        self.span.end = continuation.span.end;
+    }
+
+    /// Whether the directive opens or closes a block.
+    fn is_block_boundary(&self) -> bool {
+        BlockKind::opened_by(&self.name).is_some()
+            || ["endmigration", "endgroup", "endmacro", "endfunction"].contains(&self.name.as_str())
+    }
+}
+
+impl FileNode {
+    /// Builds the tree of `content`. Directives are only recognised in line
+    /// comments that start a line outside of strings, dollar quotes and
+    /// block comments. The bodies of functions in languages other than SQL
+    /// are not lexed at all.
+    pub fn parse(content: &str) -> Self {
+        let mut lexer = Lexer::new();
+        let mut in_function = false;
+        let mut verbatim = false;
+        let mut leaves: Vec<Node> = Vec::new();
+        let mut offset = 0;
+
+        for (idx, text) in content.split_inclusive('\n').enumerate() {
+            let span = offset..offset + text.len();
+            let line_start = offset;
+            offset += text.len();
+
+            let comment = if verbatim {
+                let start = text.len() - text.trim_start().len();
+                let end = text.trim_end_matches('\n').len().max(start);
+                text[start..].starts_with("--").then_some(start..end)
+            } else {
+                let in_literal = lexer.in_literal();
+                let tokens = lexer.scan(text);
+                let mut tokens = tokens.iter().skip_while(|token| {
+                    token.kind == TokenKind::Code && text[token.range.clone()].trim().is_empty()
+                });
+                tokens
+                    .next()
+                    .filter(|token| !in_literal && token.kind == TokenKind::LineComment)
+                    .map(|token| token.range.clone())
+            };
+            let directive = comment.and_then(|comment| {
+                let directive = directives::parse(&text[comment.clone()])?;
+                Some(DirectiveNode::new(
+                    directive,
+                    line_start + comment.start,
+                    idx + 1,
+                    text,
+                    span.clone(),
+                ))
+            });
+
+            if let Some(directive) = &directive {
+                if directive.is_block_boundary() {
+                    in_function = directive.name == "function";
+                    verbatim = false;
+                } else if in_function && directive.name == "language" {
+                    let language = directive.argument.as_deref().unwrap_or_default();
+                    verbatim = !SQL_LANGUAGES.contains(&language.to_lowercase().as_str());
+                }
+            }
+
+            match (directive, leaves.last_mut()) {
+                (Some(directive), Some(Node::Directive(previous))) if directive.name == "|" => {
+                    previous.continue_with(directive);
+                }
+                (Some(directive), _) => leaves.push(Node::Directive(directive)),
+                (None, Some(Node::Sql(chunk))) => {
+                    chunk.text.push_str(text);
+                    chunk.span.end = span.end;
+                }
+                (None, _) => leaves.push(Node::Sql(SqlChunk {
+                    line: idx + 1,
+                    text: text.to_string(),
+                    span,
+                })),
+            }
+        }
+
+        let mut items = Vec::new();
+        let mut open: Vec<BlockNode> = Vec::new();
+        for leaf in leaves {
+            let directive = match leaf {
+                Node::Directive(directive) => directive,
+                other => {
+                    Self::attach(&mut open, &mut items, other);
+                    continue;
+                }
+            };
+
+            if let Some(kind) = BlockKind::opened_by(&directive.name) {
+                open.push(BlockNode::new(directive, kind));
+            } else if let Some(position) = open
+                .iter()
+                .rposition(|block| block.kind.end() == directive.name)
+            {
+                // Blocks opened inside the one being closed were never closed
+                while open.len() > position + 1 {
+                    Self::close(&mut open, &mut items, None);
+                }
+                Self::close(&mut open, &mut items, Some(directive));
+            } else {
+                Self::attach(&mut open, &mut items, Node::Directive(directive));
+            }
+        }
+        while !open.is_empty() {
+            Self::close(&mut open, &mut items, None);
+        }
+
+        FileNode {
+            items,
+            span: 0..content.len(),
+        }
+    }
+
+    fn attach(open: &mut [BlockNode], items: &mut Vec<Node>, node: Node) {
+        match open.last_mut() {
+            Some(block) => {
+                block.span.end = node.span().end;
+                block.items.push(node);
+            }
+            None => items.push(node),
+        }
+    }
+
+    /// Closes the innermost open block with `close` and attaches it to the
+    /// block or file around it.
+    fn close(open: &mut Vec<BlockNode>, items: &mut Vec<Node>, close: Option<DirectiveNode>) {
+        let Some(mut block) = open.pop() else {
+            return;
+        };
+        if let Some(close) = &close {
+            block.span.end = close.span.end;
+        }
+        block.close = close;
+        Self::attach(open, items, Node::Block(Box::new(block)));
+    }
+
+    /// Regenerates the FSQL of the tree, byte for byte the parsed content
+    /// when nothing was changed.
+    pub fn print(&self) -> String {
+        let mut out = String::with_capacity(self.span.len());
+        for item in &self.items {
+            item.print_into(&mut out);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    /// Checks the tree prints back to `content` and every leaf covers the
+    /// bytes it was read from.
+    fn assert_round_trip(content: &str) -> FileNode {
+        fn check_leaves(items: &[Node], content: &str) {
+            for item in items {
+                match item {
+                    Node::Block(block) => {
+                        assert_eq!(&content[block.open.span.clone()], block.open.text);
+                        check_leaves(&block.items, content);
+                        if let Some(close) = &block.close {
+                            assert_eq!(&content[close.span.clone()], close.text);
+                        }
+                    }
+                    Node::Directive(directive) => {
+                        assert_eq!(&content[directive.span.clone()], directive.text)
+                    }
+                    Node::Sql(chunk) => assert_eq!(&content[chunk.span.clone()], chunk.text),
+                }
+            }
+        }
+
+        let tree = FileNode::parse(content);
+        assert_eq!(tree.print(), content);
+        check_leaves(&tree.items, content);
+        tree
+    }
+
+    fn block(node: &Node) -> &BlockNode {
+        match node {
+            Node::Block(block) => block,
+            other => panic!("expected a block, got {other:?}"),
+        }
+    }
+
+    fn sql_files(dir: &Path, files: &mut Vec<std::path::PathBuf>) {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                sql_files(&path, files);
+            } else if path.extension().is_some_and(|extension| extension == "sql") {
+                files.push(path);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_the_examples() {
+        let mut files = Vec::new();
+        sql_files(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("example-sql"),
+            &mut files,
+        );
+        assert!(!files.is_empty());
+        for file in files {
+            assert_round_trip(&std::fs::read_to_string(file).unwrap());
+        }
+    }
+
+    #[test]
+    fn round_trips_crlf_line_endings() {
+        let tree = assert_round_trip(
+            "-- +migration: a\r\n-- +nuclear\r\nSELECT 1;\r\n-- +endmigration\r\n",
+        );
+        let migration = block(&tree.items[0]);
+        assert_eq!(migration.name, "a");
+        assert!(migration.close.is_some());
+        match &migration.items[0] {
+            Node::Directive(directive) => {
+                assert_eq!(directive.name, "nuclear");
+                assert_eq!(directive.argument, None);
+            }
+            other => panic!("expected a directive, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn round_trips_without_a_trailing_newline() {
+        let tree = assert_round_trip("-- +migration: a\nSELECT 1;\n-- +endmigration");
+        assert!(block(&tree.items[0]).close.is_some());
+        assert_round_trip("-- +migration: a\nSELECT 1;");
+        assert_round_trip("");
+    }
+
+    #[test]
+    fn round_trips_unclosed_blocks() {
+        let tree = assert_round_trip("-- +group: g\n-- +migration: a\nSELECT 1;\n");
+        let group = block(&tree.items[0]);
+        assert!(group.close.is_none());
+        assert!(block(&group.items[0]).close.is_none());
+    }
+
+    #[test]
+    fn round_trips_nested_blocks() {
+        let content = "-- +group: outer\n\
+                       -- +transaction\n\
+                       -- +group: inner\n\
+                       -- +migration: a\n\
+                       SELECT 1;\n\
+                       -- +endmigration\n\
+                       -- +endgroup\n\
+                       -- +endgroup\n";
+        let tree = assert_round_trip(content);
+        let outer = block(&tree.items[0]);
+        let inner = block(&outer.items[1]);
+        let migration = block(&inner.items[0]);
+        assert_eq!(
+            (outer.name.as_str(), inner.name.as_str()),
+            ("outer", "inner")
+        );
+        assert_eq!(
+            &content[migration.span.clone()],
+            "-- +migration: a\nSELECT 1;\n-- +endmigration\n"
+        );
+    }
+
+    #[test]
+    fn closing_an_outer_block_leaves_inner_ones_unclosed() {
+        let tree = assert_round_trip("-- +group: g\n-- +migration: a\n-- +endgroup\n");
+        let group = block(&tree.items[0]);
+        assert!(group.close.is_some());
+        assert!(block(&group.items[0]).close.is_none());
+    }
+
+    #[test]
+    fn continuations_extend_the_directive() {
+        let content =
+            "-- +migration: a\n-- +tags: concurrent,\n-- | transactional\n-- +endmigration\n";
+        let tree = assert_round_trip(content);
+        match &block(&tree.items[0]).items[0] {
+            Node::Directive(directive) => {
+                assert_eq!(
+                    directive.argument.as_deref(),
+                    Some("concurrent,transactional")
+                );
+                assert_eq!(
+                    &content[directive.argument_span.clone().unwrap()],
+                    "concurrent,\n-- | transactional"
+                );
+            }
+            other => panic!("expected a directive, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn directives_in_literals_are_sql() {
+        let content = "-- +migration: a\nSELECT '\n-- +endmigration\n';\n/*\n-- +nuclear\n*/\n-- +endmigration\n";
+        let tree = assert_round_trip(content);
+        let migration = block(&tree.items[0]);
+        assert_eq!(migration.items.len(), 1);
+        assert!(matches!(migration.items[0], Node::Sql(_)));
+        assert!(migration.close.is_some());
+    }
+
+    #[test]
+    fn python_bodies_do_not_hide_directives() {
+        let content = "-- +function: f\n-- +language: plpython3u\n# don't\nreturn 1\n-- +endfunction\n-- +migration: a\n-- +endmigration\n";
+        let tree = assert_round_trip(content);
+        assert_eq!(tree.items.len(), 2);
+        assert!(block(&tree.items[0]).close.is_some());
+        assert!(block(&tree.items[1]).close.is_some());
+    }
+}
@@ -1,9 +1,209 @@
+use std::{fmt, ops::Range};
+
+use crate::{
+    directives,
+    models::{function::FunctionTags, migration_tags::MigrationTags},
+};
+
 #[derive(Debug, Clone)]
 pub struct ParseError {
     pub kind: ParseErrorKind,
     pub message: String,
     pub line: usize,
     pub column: usize,
+    pub span: Option<Box<Span>>,
+}
+
+/// The part of a file an error points at.
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub file: String,
+    /// Byte range into the content of the file.
+    pub range: Range<usize>,
+    /// The line `range` starts on, without its line break.
+    pub source_line: String,
+    /// Byte offset of `source_line` in the file.
+    pub line_start: usize,
+}
+
+impl Span {
+    /// The span of `range` in `content`, the content of `file`.
+    pub fn new(file: impl Into<String>, content: &str, range: Range<usize>) -> Self {
+        let line_start = content[..range.start].rfind('\n').map_or(0, |at| at + 1);
+        let line_end = content[line_start..]
+            .find('\n')
+            .map_or(content.len(), |at| line_start + at);
+        Self {
+            file: file.into(),
+            range,
+            source_line: content[line_start..line_end]
+                .trim_end_matches('\r')
+                .to_string(),
+            line_start,
+        }
+    }
+}
+
+impl ParseError {
+    /// An error about `range` of `content`, the content of `file`, with its
+    /// line and column worked out from the range.
+    pub fn at(
+        kind: ParseErrorKind,
+        message: impl Into<String>,
+        file: &str,
+        content: &str,
+        range: Range<usize>,
+    ) -> Self {
+        let span = Span::new(file, content, range);
+        Self {
+            kind,
+            message: message.into(),
+            line: content[..span.line_start].matches('\n').count() + 1,
+            column: content[span.line_start..span.range.start].chars().count() + 1,
+            span: Some(Box::new(span)),
+        }
+    }
+
+    /// A hint on how to fix the error, if there is one for its kind.
+    pub fn help(&self) -> Option<String> {
+        let help = match &self.kind {
+            ParseErrorKind::UnknownTag(_) => format!(
+                "migrations and groups accept {}, functions accept {}",
+                MigrationTags::ALL.map(|tag| tag.as_str()).join(", "),
+                FunctionTags::ALL.map(|tag| tag.as_str()).join(", ")
+            ),
+            ParseErrorKind::UnknownDirective(name) => match directives::suggest(name) {
+                Some(known) => format!("did you mean `-- +{known}`?"),
+                None => format!("known directives are {}", directives::DIRECTIVES.join(", ")),
+            },
+            ParseErrorKind::InvalidRetries(_) => {
+                "retries take a whole number, like `-- +retries: 3`".to_string()
+            }
+            ParseErrorKind::InvalidDependencyFormat(_) => {
+                "depend on `Migration(name)` or `Group(name)`, prefixed with the groups or `::file.sql::` when it lives elsewhere".to_string()
+            }
+            ParseErrorKind::MissingMigrationName
+            | ParseErrorKind::MissingMigrationGroupName
+            | ParseErrorKind::MissingMacroName
+            | ParseErrorKind::MissingFunctionName => {
+                "names are made of letters, digits and _, like `-- +migration: create_users`"
+                    .to_string()
+            }
+            ParseErrorKind::UnexpectedMigrationStart
+            | ParseErrorKind::UnexpectedMacroStart
+            | ParseErrorKind::UnexpectedFunctionStart
+            | ParseErrorKind::UnexpectedMigrationGroupStart => {
+                "migrations, macros and functions cannot be nested, close the open one first"
+                    .to_string()
+            }
+            ParseErrorKind::MigrationNotClosed => "close it with `-- +endmigration`".to_string(),
+            ParseErrorKind::MacroNotClosed => "close it with `-- +endmacro`".to_string(),
+            ParseErrorKind::FunctionNotClosed => "close it with `-- +endfunction`".to_string(),
+            ParseErrorKind::MigrationGroupNotClosed => "close it with `-- +endgroup`".to_string(),
+            ParseErrorKind::TransactionWithoutContext => {
+                "`-- +transaction` belongs to a group, before its first migration".to_string()
+            }
+            ParseErrorKind::RollbackWithoutContext => {
+                "`-- +rollback` starts the rollback section of a migration".to_string()
+            }
+            ParseErrorKind::SqlWithoutContext => {
+                "SQL goes inside a `-- +migration`, `-- +macro` or `-- +function` block"
+                    .to_string()
+            }
+            ParseErrorKind::NuclearWithoutContext
+            | ParseErrorKind::RetriesWithoutContext
+            | ParseErrorKind::TagsWithoutContext
+            | ParseErrorKind::ParametersWithoutContext
+            | ParseErrorKind::ReturnsWithoutContext
+            | ParseErrorKind::LanguageWithoutContext
+            | ParseErrorKind::DependsWithoutContext
+            | ParseErrorKind::DescriptionWithoutContext
+            | ParseErrorKind::MacroCallWithoutContext
+            | ParseErrorKind::FunctionCallWithoutContext => {
+                "move the directive inside the block it applies to".to_string()
+            }
+            _ => return None,
+        };
+        Some(help)
+    }
+}
+
+/// Renders the message, followed by the source line with the span
+/// underlined and the help note, in the style of rustc.
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+
+        if let Some(span) = &self.span {
+            let gutter = " ".repeat(self.line.to_string().len());
+            let start = span.range.start.saturating_sub(span.line_start);
+            let end = span
+                .range
+                .end
+                .saturating_sub(span.line_start)
+                .min(span.source_line.len())
+                .max(start);
+            let indent: String = span.source_line[..start]
+                .chars()
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let carets = "^".repeat(span.source_line[start..end].chars().count().max(1));
+
+            write!(
+                f,
+                "\n{gutter}--> {}:{}:{}",
+                span.file, self.line, self.column
+            )?;
+            write!(f, "\n{gutter} |")?;
+            write!(f, "\n{} | {}", self.line, span.source_line)?;
+            write!(f, "\n{gutter} | {indent}{carets}")?;
+        }
+
+        if let Some(help) = self.help() {
+            let gutter = match &self.span {
+                Some(_) => " ".repeat(self.line.to_string().len()),
+                None => String::new(),
+            };
+            write!(f, "\n{gutter} = help: {help}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Something suspicious that does not stop a file from parsing.
+#[derive(Debug, Clone)]
+pub struct ParseWarning {
+    pub file: String,
+    pub message: String,
+    pub line: usize,
+}
+
+impl fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+/// Every error and warning found while parsing, instead of just the first
+/// error.
+#[derive(Debug, Clone, Default)]
+pub struct ParseReport {
+    pub errors: Vec<ParseError>,
+    pub warnings: Vec<ParseWarning>,
+}
+
+impl ParseReport {
+    pub fn has_errors(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
+    /// Appends the errors and warnings of `other`.
+    pub fn extend(&mut self, other: ParseReport) {
+        self.errors.extend(other.errors);
+        self.warnings.extend(other.warnings);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -11,7 +211,14 @@ pub enum ParseErrorKind {
     MissingArgumentType,
     InvalidArgumentFormat,
     MissingArgument(String),
+    UnexpectedArgument(String),
+    MacroRecursion(Vec<String>),
+    MissingLanguage,
+    LanguageWithoutContext,
+    UnknownLanguage(String),
+    InvalidFunctionBody(String),
     UnknownTag(String),
+    UnknownDirective(String),
     UnexpectedEndOfFile(usize),
     UnexpectedMigrationStart,
     UnexpectedMigrationEnd,
@@ -26,6 +233,9 @@ pub enum ParseErrorKind {
     UnexpectedMigrationGroupEnd,
     MissingFunctionName,
     NuclearWithoutContext,
+    RetriesWithoutContext,
+    TransactionWithoutContext,
+    InvalidRetries(String),
     TagsWithoutContext,
     RollbackWithoutContext,
     ParametersWithoutContext,
@@ -33,6 +243,7 @@ pub enum ParseErrorKind {
     DependsWithoutContext,
     DescriptionWithoutContext,
     MacroCallWithoutContext,
+    FunctionCallWithoutContext,
     SqlWithoutContext,
     MissingReturnType,
     MissingTags,
@@ -44,4 +255,47 @@ pub enum ParseErrorKind {
     MacroNotClosed,
     FunctionNotClosed,
     EndMigrationWithoutStart(usize),
+    FileRead(String),
+    DuplicatePath(String),
+    UnresolvedDependency(String, String),
+    DependencyCycle(Vec<String>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_work_out_their_line_and_column_from_the_range() {
+        let content = "-- +migration: a\r\n-- +retries: many\r\n";
+        let at = content.find("many").unwrap();
+        let error = ParseError::at(
+            ParseErrorKind::InvalidRetries("many".to_string()),
+            "Invalid retries 'many'",
+            "a.sql",
+            content,
+            at..at + 4,
+        );
+        assert_eq!((error.line, error.column), (2, 14));
+
+        let span = error.span.as_deref().unwrap();
+        assert_eq!(span.source_line, "-- +retries: many");
+        assert_eq!(span.line_start, content.find("-- +retries").unwrap());
+    }
+
+    #[test]
+    fn display_underlines_the_span_once() {
+        let content = "-- +retries: many\n";
+        let error = ParseError::at(
+            ParseErrorKind::InvalidRetries("many".to_string()),
+            "Invalid retries 'many'",
+            "a.sql",
+            content,
+            13..17,
+        );
+        let rendered = error.to_string();
+        assert!(rendered.starts_with("Invalid retries 'many'\n --> a.sql:1:14\n"));
+        assert!(rendered.contains("1 | -- +retries: many\n  |              ^^^^"));
+        assert_eq!(rendered.matches("a.sql").count(), 1);
+    }
 }
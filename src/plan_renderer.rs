@@ -0,0 +1,245 @@
+use std::fmt::Write;
+
+use crate::{
+    advisory_lock::MigratorLock,
+    executor::{Executor, RetryPolicy, Step, savepoint_name},
+    models::project::{MigrationProject, NodeId, NodeKind, split_file_path},
+};
+
+/// Renders the steps of `up` as an annotated SQL script `psql` can run, with
+/// macros expanded, function boilerplate in place and the transactions `up`
+/// would open. Comments point every migration back to its source. The
+/// script takes the lock of the migrator but leaves the tracking table
+/// alone, and does not retry anything.
+pub struct PlanRenderer<'a> {
+    project: &'a MigrationProject,
+    table: &'a str,
+    retry_policy: RetryPolicy,
+    max_parallel: usize,
+}
+
+impl<'a> PlanRenderer<'a> {
+    pub fn new(project: &'a MigrationProject, executor: &'a Executor) -> Self {
+        Self {
+            project,
+            table: executor.table().name(),
+            retry_policy: executor.retry_policy(),
+            max_parallel: executor.max_parallel(),
+        }
+    }
+
+    pub fn render(&self, steps: &[Step]) -> String {
+        let nodes = || steps.iter().flat_map(|step| step.nodes());
+        let migrations = nodes().filter(|id| id.kind == NodeKind::Migration).count();
+        let functions = nodes().filter(|id| id.kind == NodeKind::Function).count();
+
+        let mut script = String::new();
+        let _ = writeln!(script, "-- fsql plan of '{}'", self.project.root());
+        let _ = writeln!(
+            script,
+            "-- {migrations} pending migration(s), {functions} function(s) to create"
+        );
+        let _ = writeln!(
+            script,
+            "-- `fsql up` also records every migration in '{}', this script does not",
+            self.table
+        );
+        script.push_str(
+            "-- `fsql up` retries transient failures, this script stops at the first error\n",
+        );
+        script.push_str("\\set ON_ERROR_STOP on\n");
+        let _ = writeln!(
+            script,
+            "-- Keeps other migrators of '{}' waiting, like `fsql up`",
+            self.table
+        );
+        let key = MigratorLock::key(self.table);
+        let _ = writeln!(script, "SELECT pg_advisory_lock({key});");
+        if steps.is_empty() {
+            script.push_str("\n-- Nothing to do\n");
+        }
+
+        for step in steps {
+            script.push('\n');
+            match step {
+                Step::Migration(id) => self.migration(&mut script, *id),
+                Step::Nuclear(id) => {
+                    script.push_str(
+                        "-- NUCLEAR: runs ahead of the plan and ignores its dependencies, needs --allow-nuclear\n",
+                    );
+                    self.migration(&mut script, *id);
+                }
+                Step::Function(id) => self.function(&mut script, *id),
+                Step::Group(group, steps) => self.group(&mut script, *group, steps),
+                Step::Concurrent(wave) => {
+                    if wave.len() > 1 {
+                        let _ = writeln!(
+                            script,
+                            "-- The next {} concurrent migrations run in parallel, at most {} at a time",
+                            wave.len(),
+                            self.max_parallel
+                        );
+                    }
+                    for (index, id) in wave.iter().enumerate() {
+                        if index > 0 {
+                            script.push('\n');
+                        }
+                        self.migration(&mut script, *id);
+                    }
+                }
+            }
+        }
+
+        let _ = writeln!(script, "\nSELECT pg_advisory_unlock({key});");
+        script
+    }
+
+    /// `file:line` the node behind `id` starts at, relative to the root.
+    fn source(&self, id: NodeId) -> String {
+        let (file, _) = split_file_path(self.project.path_of(id));
+        format!("{file}:{}", self.project.line_of(id))
+    }
+
+    fn retries(&self, retries: u32) -> String {
+        format!(
+            "-- `fsql up` retries it up to {retries} time(s) on transient errors, after {}ms, growing x{} up to {}ms\n",
+            self.retry_policy.initial_delay.as_millis(),
+            self.retry_policy.multiplier,
+            self.retry_policy.max_delay.as_millis()
+        )
+    }
+
+    fn header(&self, script: &mut String, id: NodeId) {
+        let _ = writeln!(script, "-- {}", self.project.path_of(id));
+        let _ = writeln!(script, "-- Source: {}", self.source(id));
+    }
+
+    fn migration(&self, script: &mut String, id: NodeId) {
+        self.header(script, id);
+        let retries = self.project.retries_of(id);
+        if retries > 0 {
+            script.push_str(&self.retries(retries));
+        }
+
+        let sql = self.project.migrations()[id.index].sql();
+        if self.project.is_concurrent(id) {
+            script.push_str(
+                "-- Concurrent: runs outside of a transaction, one statement at a time\n",
+            );
+            push_sql(script, sql);
+        } else {
+            script.push_str("BEGIN;\n");
+            push_sql(script, sql);
+            script.push_str("COMMIT;\n");
+        }
+    }
+
+    fn function(&self, script: &mut String, id: NodeId) {
+        self.header(script, id);
+        push_sql(script, self.project.functions()[id.index].parsed_body());
+    }
+
+    fn group(&self, script: &mut String, group: NodeId, steps: &[NodeId]) {
+        let _ = writeln!(
+            script,
+            "-- Transactional group {}, one transaction with a savepoint per migration",
+            self.project.path_of(group)
+        );
+        let _ = writeln!(script, "-- Source: {}", self.source(group));
+        let retries = steps
+            .iter()
+            .filter(|id| id.kind == NodeKind::Migration)
+            .map(|id| self.project.retries_of(*id))
+            .max()
+            .unwrap_or_default();
+        if retries > 0 {
+            script.push_str(&self.retries(retries));
+        }
+
+        script.push_str("BEGIN;\n");
+        for id in steps {
+            script.push('\n');
+            if id.kind == NodeKind::Function {
+                self.function(script, *id);
+                continue;
+            }
+            self.header(script, *id);
+            let savepoint = savepoint_name(self.project, *id);
+            let _ = writeln!(script, "SAVEPOINT {savepoint};");
+            push_sql(script, self.project.migrations()[id.index].sql());
+            let _ = writeln!(script, "RELEASE SAVEPOINT {savepoint};");
+        }
+        script.push_str("\nCOMMIT;\n");
+    }
+}
+
+/// Appends `sql`, making sure its last statement is terminated so the next
+/// one is not glued to it.
+fn push_sql(script: &mut String, sql: &str) {
+    let sql = sql.trim();
+    if sql.is_empty() {
+        script.push_str("-- (no SQL)\n");
+        return;
+    }
+    script.push_str(sql);
+    script.push('\n');
+    if !sql.ends_with(';') {
+        script.push_str(";\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::planned;
+
+    fn render(content: &str) -> String {
+        let (project, plan) = planned(content);
+        let pending: Vec<NodeId> = plan
+            .order()
+            .iter()
+            .filter(|id| id.kind == NodeKind::Migration)
+            .copied()
+            .collect();
+        let steps = Executor::schedule(&project, &plan, &pending).unwrap();
+        let renderer = PlanRenderer {
+            project: &project,
+            table: "migrations",
+            retry_policy: RetryPolicy::default(),
+            max_parallel: 4,
+        };
+        renderer.render(&steps)
+    }
+
+    #[test]
+    fn group_migrations_get_their_own_savepoint() {
+        let script = render(
+            "-- +group: g\n-- +transaction\n\
+             -- +migration: first\nSELECT 1;\n-- +endmigration\n\
+             -- +migration: second\nSELECT 2;\n-- +endmigration\n\
+             -- +endgroup\n",
+        );
+        assert!(script.contains(
+            "SAVEPOINT \"fsql_0_first\";\nSELECT 1;\nRELEASE SAVEPOINT \"fsql_0_first\";"
+        ));
+        assert!(script.contains(
+            "SAVEPOINT \"fsql_1_second\";\nSELECT 2;\nRELEASE SAVEPOINT \"fsql_1_second\";"
+        ));
+    }
+
+    #[test]
+    fn script_holds_the_migrator_lock() {
+        let script = render("-- +migration: m\n-- +retries: 2\nSELECT 1;\n-- +endmigration\n");
+        let key = MigratorLock::key("migrations");
+        let lock = script
+            .find(&format!("SELECT pg_advisory_lock({key});"))
+            .unwrap();
+        let unlock = script
+            .find(&format!("SELECT pg_advisory_unlock({key});"))
+            .unwrap();
+        let migration = script.find("SELECT 1;").unwrap();
+        assert!(lock < migration && migration < unlock);
+        assert!(script.contains("this script stops at the first error"));
+        assert!(script.contains("-- `fsql up` retries it up to 2 time(s)"));
+    }
+}
@@ -0,0 +1,83 @@
+//! Projects, migration roots and databases the tests build their cases from.
+
+use std::{path::PathBuf, time::Duration};
+
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+
+use crate::{
+    execution_plan::ExecutionPlan,
+    executor::{Executor, RetryPolicy},
+    models::{file::MigrationFile, project::MigrationProject},
+    parse_errors::ParseError,
+    tracking::TrackingTable,
+};
+
+/// URL of a database the tests may write to. Tests that need one do nothing
+/// when it is not set.
+const DATABASE_URL_VARIABLE: &str = "FSQL_TEST_DATABASE_URL";
+
+/// Parses `files`, given as path and content, into a project with its
+/// dependencies resolved and its macros expanded.
+pub fn try_project(files: &[(&str, &str)]) -> Result<MigrationProject, ParseError> {
+    let mut project = MigrationProject::new("migrations");
+    for (path, content) in files {
+        let mut file = MigrationFile::new(*path, *path, *content);
+        file.parse_file()?;
+        project.add_file(file)?;
+    }
+    project.resolve_dependencies()?;
+    project.expand_macros()?;
+    Ok(project)
+}
+
+pub fn project(files: &[(&str, &str)]) -> MigrationProject {
+    try_project(files).unwrap()
+}
+
+/// The project of a single `a.sql` and its plan.
+pub fn planned(content: &str) -> (MigrationProject, ExecutionPlan) {
+    let project = project(&[("a.sql", content)]);
+    let plan = ExecutionPlan::build(&project).unwrap();
+    (project, plan)
+}
+
+/// A migrations root with `files` in it, under the temporary directory.
+pub fn root(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("fsql-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).unwrap();
+    for (path, content) in files {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+    root
+}
+
+/// An executor on the schema `fsql_test_{name}`, recreated empty and put
+/// first on the search path, tracking into the `migrations` table of that
+/// schema. Retries do not wait. `None` without a test database.
+pub async fn executor(name: &str) -> Option<Executor> {
+    let url = std::env::var(DATABASE_URL_VARIABLE).ok()?;
+    let schema = format!("fsql_test_{name}");
+    let options: PgConnectOptions = url.parse().unwrap();
+    let pool = PgPoolOptions::new()
+        .max_connections(5)
+        .connect_with(options.options([("search_path", schema.as_str())]))
+        .await
+        .unwrap();
+    sqlx::raw_sql(&format!(
+        "DROP SCHEMA IF EXISTS {schema} CASCADE; CREATE SCHEMA {schema};"
+    ))
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    let mut executor = Executor::new(pool);
+    executor.set_table(TrackingTable::new(format!("{schema}.migrations")).unwrap());
+    executor.set_retry_policy(RetryPolicy {
+        initial_delay: Duration::ZERO,
+        ..RetryPolicy::default()
+    });
+    Some(executor)
+}
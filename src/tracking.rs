@@ -0,0 +1,461 @@
+use std::collections::HashMap;
+
+use sqlx::{PgConnection, PgPool, types::chrono};
+
+use crate::{
+    execution_errors::{ExecutionError, ExecutionErrorKind},
+    models::project::NodeKind,
+};
+
+pub const DEFAULT_TABLE_NAME: &str = "migrations";
+
+const REGEX_TABLE_NAME: &str = r"^[A-Za-z_][A-Za-z0-9_]*(\.[A-Za-z_][A-Za-z0-9_]*)?$";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MigrationStatus {
+    Pending,
+    Executed,
+    RolledBack,
+}
+
+impl MigrationStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MigrationStatus::Pending => "pending",
+            MigrationStatus::Executed => "executed",
+            MigrationStatus::RolledBack => "rolled_back",
+        }
+    }
+}
+
+impl TryFrom<String> for MigrationStatus {
+    type Error = ExecutionErrorKind;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "pending" => Ok(MigrationStatus::Pending),
+            "executed" => Ok(MigrationStatus::Executed),
+            "rolled_back" => Ok(MigrationStatus::RolledBack),
+            _ => Err(ExecutionErrorKind::UnknownStatus(value)),
+        }
+    }
+}
+
+/// One row of the tracking table.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct TrackedMigration {
+    pub id: i32,
+    pub full_path: String,
+    pub name: String,
+    pub hash: String,
+    pub rollback: bool,
+    pub locked: bool,
+    pub status: String,
+    pub description: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+    pub rolled_back_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub tags: sqlx::types::JsonValue,
+    pub dependencies: sqlx::types::JsonValue,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub nuclear: bool,
+    /// Normalized SQL as applied, missing on rows written before it was stored.
+    pub content: Option<String>,
+    /// `migration`, or `function` for the rows of created functions.
+    pub kind: String,
+    /// One `{attempt, sqlstate, error, at}` object per failed attempt of the
+    /// last run, `attempts` being their count.
+    pub attempt_log: sqlx::types::JsonValue,
+}
+
+impl TrackedMigration {
+    pub fn status(&self) -> Result<MigrationStatus, ExecutionError> {
+        MigrationStatus::try_from(self.status.clone()).map_err(|kind| {
+            ExecutionError::new(
+                kind,
+                format!(
+                    "Unknown status '{}' for '{}' in the migrations table",
+                    self.status, self.full_path
+                ),
+            )
+        })
+    }
+
+    pub fn is_function(&self) -> bool {
+        self.kind == kind_name(NodeKind::Function)
+    }
+}
+
+/// Value of the `kind` column for rows of `kind`.
+fn kind_name(kind: NodeKind) -> String {
+    kind.as_str().to_lowercase()
+}
+
+/// Values written for a migration or function when it changes state.
+#[derive(Debug, Clone)]
+pub struct TrackingEntry<'a> {
+    pub kind: NodeKind,
+    pub full_path: &'a str,
+    pub name: &'a str,
+    pub hash: String,
+    pub rollback: bool,
+    pub status: MigrationStatus,
+    pub description: &'a str,
+    pub tags: Vec<String>,
+    pub dependencies: Vec<String>,
+    pub attempts: i32,
+    pub nuclear: bool,
+    /// Pending dependencies a nuclear migration ran ahead of.
+    pub bypassed: Vec<String>,
+    pub content: String,
+}
+
+/// The `migrations` table described in the FSQL readme.
+#[derive(Debug, Clone)]
+pub struct TrackingTable {
+    name: String,
+}
+
+impl Default for TrackingTable {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_TABLE_NAME.to_string(),
+        }
+    }
+}
+
+impl TrackingTable {
+    pub fn new(name: impl Into<String>) -> Result<Self, ExecutionError> {
+        let name = name.into();
+        let regex_table_name =
+            regex::Regex::new(REGEX_TABLE_NAME).expect("Invalid regex for table name");
+        if !regex_table_name.is_match(&name) {
+            return Err(ExecutionError::new(
+                ExecutionErrorKind::InvalidTableName(name.clone()),
+                format!("'{name}' is not a valid table name"),
+            ));
+        }
+        Ok(Self { name })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The table keeping a row for every nuclear migration that ran, next to
+    /// the tracking table.
+    pub fn audit_name(&self) -> String {
+        format!("{}_audit", self.name)
+    }
+
+    pub async fn bootstrap(&self, pool: &PgPool) -> Result<(), ExecutionError> {
+        let sql = format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS {0} (
+                id SERIAL PRIMARY KEY,
+                full_path TEXT NOT NULL UNIQUE,
+                name TEXT NOT NULL,
+                hash TEXT NOT NULL,
+                rollback BOOLEAN NOT NULL DEFAULT FALSE,
+                locked BOOLEAN NOT NULL DEFAULT FALSE,
+                status TEXT NOT NULL DEFAULT 'pending'
+                    CHECK (status IN ('pending', 'executed', 'rolled_back')),
+                description TEXT,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                rolled_back_at TIMESTAMPTZ,
+                tags JSONB NOT NULL DEFAULT '[]'::jsonb,
+                dependencies JSONB NOT NULL DEFAULT '[]'::jsonb,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                last_error TEXT,
+                nuclear BOOLEAN NOT NULL DEFAULT FALSE,
+                content TEXT,
+                kind TEXT NOT NULL DEFAULT 'migration',
+                attempt_log JSONB NOT NULL DEFAULT '[]'::jsonb
+            );
+            ALTER TABLE {0}
+                ADD COLUMN IF NOT EXISTS attempts INTEGER NOT NULL DEFAULT 0,
+                ADD COLUMN IF NOT EXISTS last_error TEXT,
+                ADD COLUMN IF NOT EXISTS nuclear BOOLEAN NOT NULL DEFAULT FALSE,
+                ADD COLUMN IF NOT EXISTS content TEXT,
+                ADD COLUMN IF NOT EXISTS kind TEXT NOT NULL DEFAULT 'migration',
+                ADD COLUMN IF NOT EXISTS attempt_log JSONB NOT NULL DEFAULT '[]'::jsonb;
+            CREATE TABLE IF NOT EXISTS {1} (
+                id SERIAL PRIMARY KEY,
+                event TEXT NOT NULL,
+                full_path TEXT NOT NULL,
+                hash TEXT NOT NULL,
+                bypassed JSONB NOT NULL DEFAULT '[]'::jsonb,
+                db_user TEXT NOT NULL DEFAULT current_user,
+                at TIMESTAMPTZ NOT NULL DEFAULT now()
+            );
+            "#,
+            self.name,
+            self.audit_name()
+        );
+
+        sqlx::raw_sql(&sql).execute(pool).await.map_err(|e| {
+            ExecutionError::new(
+                ExecutionErrorKind::Bootstrap,
+                format!("Failed to create the '{}' table: {e}", self.name),
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Every tracked migration, keyed by full path.
+    pub async fn fetch_all(
+        &self,
+        pool: &PgPool,
+    ) -> Result<HashMap<String, TrackedMigration>, ExecutionError> {
+        let sql = format!("SELECT * FROM {} ORDER BY id", self.name);
+        let rows: Vec<TrackedMigration> = sqlx::query_as(&sql)
+            .fetch_all(pool)
+            .await
+            .map_err(ExecutionError::tracking_table)?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.full_path.clone(), row))
+            .collect())
+    }
+
+    /// Inserts or updates the row of `entry.full_path`.
+    pub async fn record(
+        &self,
+        connection: &mut PgConnection,
+        entry: &TrackingEntry<'_>,
+    ) -> Result<(), ExecutionError> {
+        let sql = format!(
+            r#"
+            INSERT INTO {0} (full_path, name, hash, rollback, status, description, tags, dependencies, attempts, nuclear, content, kind)
+            VALUES ($1, $2, $3, $4, $5, NULLIF($6, ''), to_jsonb($7::text[]), to_jsonb($8::text[]), $9, $10, $11, $12)
+            ON CONFLICT (full_path) DO UPDATE SET
+                kind = EXCLUDED.kind,
+                name = EXCLUDED.name,
+                hash = EXCLUDED.hash,
+                content = EXCLUDED.content,
+                rollback = EXCLUDED.rollback,
+                status = EXCLUDED.status,
+                description = EXCLUDED.description,
+                tags = EXCLUDED.tags,
+                dependencies = EXCLUDED.dependencies,
+                attempts = EXCLUDED.attempts,
+                nuclear = EXCLUDED.nuclear,
+                last_error = CASE WHEN EXCLUDED.attempts > 1 THEN {0}.last_error END,
+                attempt_log = CASE WHEN EXCLUDED.attempts > 1 THEN {0}.attempt_log ELSE '[]'::jsonb END,
+                created_at = now(),
+                updated_at = now(),
+                rolled_back_at = NULL
+            "#,
+            self.name
+        );
+
+        sqlx::query(&sql)
+            .bind(entry.full_path)
+            .bind(entry.name)
+            .bind(&entry.hash)
+            .bind(entry.rollback)
+            .bind(entry.status.as_str())
+            .bind(entry.description)
+            .bind(&entry.tags)
+            .bind(&entry.dependencies)
+            .bind(entry.attempts)
+            .bind(entry.nuclear)
+            .bind(&entry.content)
+            .bind(kind_name(entry.kind))
+            .execute(&mut *connection)
+            .await
+            .map_err(ExecutionError::tracking_table)?;
+
+        if entry.nuclear && entry.status == MigrationStatus::Executed {
+            self.audit(connection, "nuclear", entry).await?;
+        }
+        Ok(())
+    }
+
+    /// Adds an `event` about `entry` to the audit table.
+    async fn audit(
+        &self,
+        connection: &mut PgConnection,
+        event: &str,
+        entry: &TrackingEntry<'_>,
+    ) -> Result<(), ExecutionError> {
+        let sql = format!(
+            "INSERT INTO {} (event, full_path, hash, bypassed) VALUES ($1, $2, $3, to_jsonb($4::text[]))",
+            self.audit_name()
+        );
+
+        sqlx::query(&sql)
+            .bind(event)
+            .bind(entry.full_path)
+            .bind(&entry.hash)
+            .bind(&entry.bypassed)
+            .execute(connection)
+            .await
+            .map_err(ExecutionError::tracking_table)?;
+        Ok(())
+    }
+
+    /// Stores a failed attempt at applying `entry`, outside of the rolled back
+    /// migration transaction, and appends it to the attempt log. The first
+    /// attempt of a run starts a new log. A new row starts as `pending`, an
+    /// existing one keeps its status.
+    pub async fn record_attempt(
+        &self,
+        pool: &PgPool,
+        entry: &TrackingEntry<'_>,
+        error: &sqlx::Error,
+    ) -> Result<(), ExecutionError> {
+        let sql = format!(
+            r#"
+            INSERT INTO {0} (full_path, name, hash, rollback, status, description, attempts, last_error, attempt_log)
+            VALUES ($1, $2, $3, $4, $5, NULLIF($6, ''), $7, $8, jsonb_build_array(
+                jsonb_build_object('attempt', $7, 'sqlstate', $9::text, 'error', $8, 'at', now())
+            ))
+            ON CONFLICT (full_path) DO UPDATE SET
+                attempts = EXCLUDED.attempts,
+                last_error = EXCLUDED.last_error,
+                attempt_log = CASE WHEN EXCLUDED.attempts > 1 THEN {0}.attempt_log ELSE '[]'::jsonb END
+                    || EXCLUDED.attempt_log,
+                updated_at = now()
+            "#,
+            self.name
+        );
+        let sqlstate = match error {
+            sqlx::Error::Database(error) => error.code().map(|code| code.to_string()),
+            _ => None,
+        };
+
+        sqlx::query(&sql)
+            .bind(entry.full_path)
+            .bind(entry.name)
+            .bind(&entry.hash)
+            .bind(entry.rollback)
+            .bind(MigrationStatus::Pending.as_str())
+            .bind(entry.description)
+            .bind(entry.attempts)
+            .bind(error.to_string())
+            .bind(sqlstate)
+            .execute(pool)
+            .await
+            .map_err(ExecutionError::tracking_table)?;
+        Ok(())
+    }
+
+    /// Marks a migration as rolled back. Locked rows are never touched, the
+    /// caller's transaction has to be dropped when this fails.
+    pub async fn mark_rolled_back(
+        &self,
+        connection: &mut PgConnection,
+        full_path: &str,
+    ) -> Result<(), ExecutionError> {
+        let sql = format!(
+            "UPDATE {} SET status = $1, rolled_back_at = now(), updated_at = now() WHERE full_path = $2 AND NOT locked",
+            self.name
+        );
+
+        let result = sqlx::query(&sql)
+            .bind(MigrationStatus::RolledBack.as_str())
+            .bind(full_path)
+            .execute(connection)
+            .await
+            .map_err(ExecutionError::tracking_table)?;
+        if result.rows_affected() == 0 {
+            return Err(ExecutionError::new(
+                ExecutionErrorKind::Locked(full_path.to_string()),
+                format!("'{full_path}' is locked and cannot be rolled back"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Sets the `locked` flag of the given rows, returning the paths that
+    /// have a row.
+    pub async fn set_locked(
+        &self,
+        pool: &PgPool,
+        full_paths: &[String],
+        locked: bool,
+    ) -> Result<Vec<String>, ExecutionError> {
+        let sql = format!(
+            "UPDATE {} SET locked = $1, updated_at = now() WHERE full_path = ANY($2) RETURNING full_path",
+            self.name
+        );
+
+        sqlx::query_scalar(&sql)
+            .bind(locked)
+            .bind(full_paths)
+            .fetch_all(pool)
+            .await
+            .map_err(ExecutionError::tracking_table)
+    }
+
+    /// Accepts new content for a row, along with its hash.
+    pub async fn update_hash(
+        &self,
+        pool: &PgPool,
+        full_path: &str,
+        hash: &str,
+        content: &str,
+    ) -> Result<(), ExecutionError> {
+        let sql = format!(
+            "UPDATE {} SET hash = $1, content = $2, updated_at = now() WHERE full_path = $3",
+            self.name
+        );
+
+        sqlx::query(&sql)
+            .bind(hash)
+            .bind(content)
+            .bind(full_path)
+            .execute(pool)
+            .await
+            .map_err(ExecutionError::tracking_table)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_names_may_be_schema_qualified_identifiers() {
+        assert!(TrackingTable::new("migrations").is_ok());
+        assert_eq!(
+            TrackingTable::new("fsql.migrations_2").unwrap().name(),
+            "fsql.migrations_2"
+        );
+        for name in [
+            "",
+            "2fast",
+            "a.b.c",
+            "migrations; DROP TABLE users",
+            "\"quoted\"",
+        ] {
+            assert!(matches!(
+                TrackingTable::new(name).unwrap_err().kind,
+                ExecutionErrorKind::InvalidTableName(_)
+            ));
+        }
+    }
+
+    #[test]
+    fn statuses_round_trip_through_their_column_value() {
+        for status in [
+            MigrationStatus::Pending,
+            MigrationStatus::Executed,
+            MigrationStatus::RolledBack,
+        ] {
+            assert_eq!(
+                MigrationStatus::try_from(status.as_str().to_string()).unwrap(),
+                status
+            );
+        }
+        assert!(matches!(
+            MigrationStatus::try_from("done".to_string()),
+            Err(ExecutionErrorKind::UnknownStatus(_))
+        ));
+    }
+}